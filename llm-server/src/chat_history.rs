@@ -6,7 +6,9 @@ use axum::{
     Json,
 };
 use std::sync::Arc;
+use serde_json::Value;
 use crate::state::AppState;
+use crate::types::Role;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct FetchRequest {
@@ -24,9 +26,22 @@ pub struct NextChatIdRequest {
     pub username: String,
 }
 
+// one stored message of a chat, tool_data holds the calls of an answer that called tools or the
+// id of the call a tool result answers
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StoredMessage {
+    pub message_id: i32,
+    pub role: String,
+    pub message: String,
+    pub tool_data: Option<Value>,
+    // an answer cut off because the client cancelled generation
+    pub truncated: bool,
+    pub timestamp: String,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum FetchResponse {
-    Success {messages: Vec<(i32, String, String)>},
+    Success {messages: Vec<StoredMessage>},
     Error {message: String},
 }
 
@@ -34,6 +49,8 @@ pub enum FetchResponse {
 pub struct HistoryResponse {
     pub chat_id: i32,
     pub latest_msg: String,
+    pub role: String,
+    pub tool_data: Option<Value>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -41,6 +58,10 @@ pub struct NextChatIdResponse {
     pub chat_id: i32,
 }
 
+// role of a stored message; rows from before roles were stored come from chats without tool calls,
+// where user messages (odd message ids) and answers take turns
+const MESSAGE_ROLE: &str = "COALESCE(role, CASE message_id % 2 WHEN 1 THEN 'user' ELSE 'assistant' END)";
+
 pub fn get_user_id(conn: &Connection, name: &str) -> Result<i32> {
     let user_id: i32 = conn.query_row(
         "SELECT id FROM users WHERE name = ?1",
@@ -70,13 +91,24 @@ pub fn add_user(conn: &Connection, name: String) -> Result<()> {
     Ok(())
 }
 
-// function to create model
-pub fn add_model(conn: &Connection, model_name: &str) -> Result<()> {
+// function to create model if it does not exist yet, returns its id for the chats table
+pub fn add_model(conn: &Connection, model_name: &str) -> Result<i32> {
+    // older databases can hold the same model more than once, the first row is the one used
+    let model_id: Option<i32> = conn.query_row(
+        "SELECT MIN(id) FROM models WHERE model_name = ?1",
+        params![model_name],
+        |row| row.get(0),
+    )?;
+
+    if let Some(model_id) = model_id {
+        return Ok(model_id);
+    }
+
     conn.execute(
         "INSERT INTO models (model_name) VALUES (?1)",
         params![model_name],
     )?;
-    Ok(())
+    Ok(conn.last_insert_rowid() as i32)
 }
 
 pub fn next_chat_id(conn: &Connection, user_id: i32) -> Result<i32> {
@@ -88,7 +120,19 @@ pub fn next_chat_id(conn: &Connection, user_id: i32) -> Result<i32> {
     Ok(next_id)
 }
 
-pub fn add_message(conn: &Connection, username: String, model_id: i32, chat_id: i32, message: &str) -> Result<()> {
+// a user message or an answer, truncated marks an answer that was cut off because the client
+// cancelled generation
+pub fn add_message(conn: &Connection, username: String, model_id: i32, chat_id: i32, role: Role, message: &str, truncated: bool) -> Result<()> {
+    insert_message(conn, username, model_id, chat_id, message, truncated, (role, None))
+}
+
+// an answer that called tools (tool_data holds the calls) or a tool result (tool_data holds the id
+// of the call it answers)
+pub fn add_tool_message(conn: &Connection, username: String, model_id: i32, chat_id: i32, role: Role, message: &str, tool_data: &Value) -> Result<()> {
+    insert_message(conn, username, model_id, chat_id, message, false, (role, Some(tool_data)))
+}
+
+fn insert_message(conn: &Connection, username: String, model_id: i32, chat_id: i32, message: &str, truncated: bool, (role, tool_data): (Role, Option<&Value>)) -> Result<()> {
     let latest_msg_id: i32 = conn.query_row(
         "SELECT COALESCE(MAX(message_id), 0) FROM chats WHERE user_id = (SELECT id FROM users WHERE name = ?1) AND chat_id = ?2",
         params![username, chat_id],
@@ -102,29 +146,39 @@ pub fn add_message(conn: &Connection, username: String, model_id: i32, chat_id:
     )?;
 
     conn.execute(
-        "INSERT INTO chats (user_id, model_id, chat_id, message_id, message, timestamp) VALUES (?1, ?2, ?3, ?4, ?5, CURRENT_TIMESTAMP)",
-        params![user_id, model_id, chat_id, latest_msg_id + 1, message],
+        "INSERT INTO chats (user_id, model_id, chat_id, message_id, message, truncated, role, tool_data, timestamp) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, CURRENT_TIMESTAMP)",
+        params![user_id, model_id, chat_id, latest_msg_id + 1, message, truncated, role.as_str(), tool_data.map(Value::to_string)],
     )?;
     Ok(())
 }
  
-pub fn retrieve_chat(conn: &Connection, user_id: i32, chat_id: i32) -> Result<Vec<(i32, String, String)>> {
-    let mut messages: Vec<(i32, String, String)> = Vec::new();
-    let mut stmt = conn.prepare(
-        "SELECT message_id, message, timestamp FROM chats WHERE user_id = ?1 AND chat_id = ?2 ORDER BY message_id",
-    )?;
+pub fn retrieve_chat(conn: &Connection, user_id: i32, chat_id: i32) -> Result<Vec<StoredMessage>> {
+    let mut messages: Vec<StoredMessage> = Vec::new();
+    let mut stmt = conn.prepare(&format!(
+        "SELECT message_id, message, timestamp, {MESSAGE_ROLE}, tool_data, truncated FROM chats WHERE user_id = ?1 AND chat_id = ?2 ORDER BY message_id",
+    ))?;
 
     let message_iter = stmt.query_map([user_id, chat_id], |row| {
         Ok((
-            row.get::<_, i32>(0)?,     // message_id
-            row.get::<_, String>(1)?,  // message
-            row.get::<_, String>(2)?,  // timestamp
+            row.get::<_, i32>(0)?,             // message_id
+            row.get::<_, String>(1)?,          // message
+            row.get::<_, String>(2)?,          // timestamp
+            row.get::<_, String>(3)?,          // role
+            row.get::<_, Option<String>>(4)?,  // tool_data
+            row.get::<_, bool>(5)?,            // truncated
         ))
     })?;
 
     for msg in message_iter {
-        let (message_id, message, timestamp) = msg?;
-        messages.push((message_id, message, timestamp));
+        let (message_id, message, timestamp, role, tool_data, truncated) = msg?;
+        messages.push(StoredMessage {
+            message_id,
+            role,
+            message,
+            tool_data: tool_data.and_then(|data| serde_json::from_str(&data).ok()),
+            truncated,
+            timestamp,
+        });
     }
     Ok(messages)
 }
@@ -191,15 +245,15 @@ pub async fn fetch_history(
         Err(_) => return Json(Vec::new()),
     };
 
-    let mut stmt = match conn.prepare(
-        "SELECT chat_id, message FROM chats
+    let mut stmt = match conn.prepare(&format!(
+        "SELECT chat_id, message, {MESSAGE_ROLE}, tool_data FROM chats
         WHERE user_id = ?1 AND message_id = 
             (SELECT MAX(message_id) 
             FROM chats AS c2
             WHERE c2.user_id = ?1 AND c2.chat_id = chats.chat_id
             )
         ORDER BY chat_id",
-    ) {
+    )) {
         Ok(stmt) => stmt,
         Err(_) => return Json(Vec::new()),
     };
@@ -208,19 +262,17 @@ pub async fn fetch_history(
         Ok(HistoryResponse {
             chat_id: row.get(0)?,
             latest_msg: row.get(1)?,
+            role: row.get(2)?,
+            tool_data: row
+                .get::<_, Option<String>>(3)?
+                .and_then(|data| serde_json::from_str(&data).ok()),
         })
     }) {
         Ok(iter) => iter,
         Err(_) => return Json(Vec::new()),
     };
 
-    let mut history = Vec::new();
-
-    for item in history_iter {
-        if let Ok(record) = item {
-            history.push(record);
-        }
-    }
+    let history: Vec<HistoryResponse> = history_iter.flatten().collect();
 
     Json(history)
 }
@@ -265,7 +317,11 @@ pub fn delete_user(conn: &Connection, user_id: i32) -> Result<()> {
 pub fn initialize_database() -> Result<Connection, Box<dyn Error>> {
     // initialize connection to database
     let conn = Connection::open("chats.sqlite")?;
+    create_tables(&conn)?;
+    Ok(conn)
+}
 
+pub(crate) fn create_tables(conn: &Connection) -> Result<()> {
     // create table to store users
     conn.execute( // execute runs SQL statement
         "
@@ -298,6 +354,9 @@ pub fn initialize_database() -> Result<Connection, Box<dyn Error>> {
             chat_id                 INTEGER NOT NULL,
             message_id              INTEGER NOT NULL,
             message                 TEXT NOT NULL,
+            truncated               INTEGER NOT NULL DEFAULT 0,
+            role                    TEXT,
+            tool_data               TEXT,
             timestamp               DATETIME DEFAULT CURRENT_TIMESTAMP,
             FOREIGN KEY(user_id)    REFERENCES users(id)
             FOREIGN KEY(model_id)   REFERENCES models(id)
@@ -306,5 +365,104 @@ pub fn initialize_database() -> Result<Connection, Box<dyn Error>> {
         [],
     )?;
 
-    Ok(conn)
+    // databases created before answers could be cancelled are missing the truncated column,
+    // the ones created before tool calling the role and tool_data columns
+    add_missing_column(conn, "truncated", "INTEGER NOT NULL DEFAULT 0")?;
+    add_missing_column(conn, "role", "TEXT")?;
+    add_missing_column(conn, "tool_data", "TEXT")?;
+
+    Ok(())
+}
+
+fn add_missing_column(conn: &Connection, name: &str, definition: &str) -> Result<()> {
+    let has_column: bool = conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM pragma_table_info('chats') WHERE name = ?1)",
+        [name],
+        |row| row.get(0),
+    )?;
+    if !has_column {
+        conn.execute(
+            &format!("ALTER TABLE chats ADD COLUMN {name} {definition}"),
+            [],
+        )?;
+    }
+    Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn database() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        create_tables(&conn).unwrap();
+        add_user(&conn, "alice".to_string()).unwrap();
+        add_model(&conn, "test-model").unwrap();
+        conn
+    }
+
+    fn roles(conn: &Connection, chat_id: i32) -> Vec<String> {
+        let user_id = get_user_id(conn, "alice").unwrap();
+        retrieve_chat(conn, user_id, chat_id)
+            .unwrap()
+            .into_iter()
+            .map(|message| message.role)
+            .collect()
+    }
+
+    #[test]
+    fn roles_survive_a_user_message_without_answer() {
+        let conn = database();
+        // the first generation failed, so its user message has no answer
+        add_message(&conn, "alice".to_string(), 1, 1, Role::User, "hi", false).unwrap();
+        add_message(&conn, "alice".to_string(), 1, 1, Role::User, "hello?", false).unwrap();
+        add_message(&conn, "alice".to_string(), 1, 1, Role::Assistant, "hello", false).unwrap();
+        assert_eq!(roles(&conn, 1), ["user", "user", "assistant"]);
+    }
+
+    #[test]
+    fn cancelled_answers_come_back_truncated() {
+        let conn = database();
+        add_message(&conn, "alice".to_string(), 1, 1, Role::User, "tell me a story", false).unwrap();
+        add_message(&conn, "alice".to_string(), 1, 1, Role::Assistant, "Once upon", true).unwrap();
+        add_message(&conn, "alice".to_string(), 1, 1, Role::User, "go on", false).unwrap();
+        add_message(&conn, "alice".to_string(), 1, 1, Role::Assistant, "The end.", false).unwrap();
+        let user_id = get_user_id(&conn, "alice").unwrap();
+        let truncated: Vec<bool> = retrieve_chat(&conn, user_id, 1)
+            .unwrap()
+            .into_iter()
+            .map(|message| message.truncated)
+            .collect();
+        assert_eq!(truncated, [false, true, false, false]);
+    }
+
+    #[test]
+    fn tool_rows_keep_their_role() {
+        let conn = database();
+        let calls = json!({ "tool_calls": [{ "id": "call_1", "name": "weather", "arguments": {} }] });
+        add_message(&conn, "alice".to_string(), 1, 1, Role::User, "weather?", false).unwrap();
+        add_tool_message(&conn, "alice".to_string(), 1, 1, Role::Assistant, "", &calls).unwrap();
+        add_tool_message(&conn, "alice".to_string(), 1, 1, Role::Tool, "12C", &json!({ "tool_call_id": "call_1" }))
+            .unwrap();
+        add_message(&conn, "alice".to_string(), 1, 1, Role::Assistant, "it is 12C", false).unwrap();
+        assert_eq!(roles(&conn, 1), ["user", "assistant", "tool", "assistant"]);
+        let user_id = get_user_id(&conn, "alice").unwrap();
+        let messages = retrieve_chat(&conn, user_id, 1).unwrap();
+        assert_eq!(messages[1].tool_data, Some(calls));
+    }
+
+    #[test]
+    fn rows_without_a_role_take_turns() {
+        let conn = database();
+        for message_id in 1..=4 {
+            conn.execute(
+                "INSERT INTO chats (user_id, model_id, chat_id, message_id, message) VALUES (1, 1, 1, ?1, 'text')",
+                [message_id],
+            )
+            .unwrap();
+        }
+        assert_eq!(roles(&conn, 1), ["user", "assistant", "user", "assistant"]);
+    }
+}
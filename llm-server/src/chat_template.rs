@@ -0,0 +1,211 @@
+use serde_json::Value;
+
+use crate::tools::template_messages;
+use crate::types::{ChatMessage, Role, Tool};
+
+/*
+ * Built-in chat templates used to turn structured chat messages into the prompt format a model
+ * was fine-tuned on. The template is picked from the `chat_template` in the model's
+ * tokenizer_config.json (by looking for the special markers each family uses), or set by name
+ * in the server config when detection is not good enough.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChatTemplate {
+    // <|system|> / <|user|> / <|assistant|> blocks ended by </s>, used by TinyLlama chat
+    Zephyr,
+    // <|im_start|>role ... <|im_end|>, used by Qwen, OpenHermes, SmolLM, ...
+    ChatMl,
+    // [INST] ... [/INST] with an optional <<SYS>> block, used by Llama 2 chat and Mistral instruct
+    Llama2,
+}
+
+impl ChatTemplate {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "zephyr" | "tinyllama" => Some(Self::Zephyr),
+            "chatml" => Some(Self::ChatMl),
+            "llama2" | "llama-2" | "mistral" => Some(Self::Llama2),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Zephyr => "zephyr",
+            Self::ChatMl => "chatml",
+            Self::Llama2 => "llama2",
+        }
+    }
+
+    // markers that start a new turn, the model writing one means its answer is over
+    pub fn turn_markers(&self) -> &'static [&'static str] {
+        match self {
+            Self::Zephyr => &["<|user|>", "<|system|>"],
+            Self::ChatMl => &["<|im_end|>", "<|im_start|>"],
+            Self::Llama2 => &["[INST]"],
+        }
+    }
+
+    // pick the built-in template that matches the jinja chat template in tokenizer_config.json
+    pub fn detect(tokenizer_config: &Value) -> Option<Self> {
+        let template = match tokenizer_config.get("chat_template")? {
+            Value::String(template) => template.as_str(),
+            // some models ship several named templates, the default one is used for chat
+            Value::Array(templates) => templates
+                .iter()
+                .find(|template| template.get("name").and_then(Value::as_str) == Some("default"))?
+                .get("template")?
+                .as_str()?,
+            _ => return None,
+        };
+
+        if template.contains("<|im_start|>") {
+            Some(Self::ChatMl)
+        } else if template.contains("<|user|>") {
+            Some(Self::Zephyr)
+        } else if template.contains("[INST]") {
+            Some(Self::Llama2)
+        } else {
+            None
+        }
+    }
+
+    // render the conversation and open an assistant turn for the model to complete
+    // the tokenizer adds the BOS token, so it is not part of the rendered text
+    // tools are described in the system prompt, tool calls and results are written out as text
+    pub fn render(&self, messages: &[ChatMessage], tools: &[Tool]) -> String {
+        let messages = template_messages(messages, tools);
+        let mut prompt = String::new();
+        match self {
+            Self::Zephyr => {
+                for message in &messages {
+                    prompt.push_str(&format!("<|{}|>\n{}</s>\n", message.role.as_str(), message.content));
+                }
+                prompt.push_str("<|assistant|>\n");
+            }
+            Self::ChatMl => {
+                for message in &messages {
+                    prompt.push_str(&format!(
+                        "<|im_start|>{}\n{}<|im_end|>\n",
+                        message.role.as_str(),
+                        message.content
+                    ));
+                }
+                prompt.push_str("<|im_start|>assistant\n");
+            }
+            Self::Llama2 => {
+                // the system prompt is folded into the first user turn
+                let mut system: Option<&str> = None;
+                let mut first_turn = true;
+                for message in &messages {
+                    match message.role {
+                        Role::System => system = Some(&message.content),
+                        Role::User | Role::Tool => {
+                            if !first_turn {
+                                prompt.push_str("<s>");
+                            }
+                            prompt.push_str("[INST] ");
+                            if let Some(system) = system.take() {
+                                prompt.push_str(&format!("<<SYS>>\n{system}\n<</SYS>>\n\n"));
+                            }
+                            prompt.push_str(&format!("{} [/INST]", message.content));
+                            first_turn = false;
+                        }
+                        Role::Assistant => {
+                            prompt.push_str(&format!(" {} </s>", message.content));
+                        }
+                    }
+                }
+            }
+        }
+        prompt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn message(role: Role, content: &str) -> ChatMessage {
+        ChatMessage::new(role, content.to_string())
+    }
+
+    // system prompt, a finished exchange and a follow-up question
+    fn conversation() -> Vec<ChatMessage> {
+        vec![
+            message(Role::System, "Be brief."),
+            message(Role::User, "Hi"),
+            message(Role::Assistant, "Hello!"),
+            message(Role::User, "How are you?"),
+        ]
+    }
+
+    #[test]
+    fn zephyr_renders_every_turn_in_its_own_block() {
+        assert_eq!(
+            ChatTemplate::Zephyr.render(&[message(Role::User, "Hi")], &[]),
+            "<|user|>\nHi</s>\n<|assistant|>\n"
+        );
+        assert_eq!(
+            ChatTemplate::Zephyr.render(&conversation(), &[]),
+            "<|system|>\nBe brief.</s>\n<|user|>\nHi</s>\n<|assistant|>\nHello!</s>\n<|user|>\nHow are you?</s>\n<|assistant|>\n"
+        );
+    }
+
+    #[test]
+    fn chatml_renders_every_turn_in_its_own_block() {
+        assert_eq!(
+            ChatTemplate::ChatMl.render(&[message(Role::User, "Hi")], &[]),
+            "<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\n"
+        );
+        assert_eq!(
+            ChatTemplate::ChatMl.render(&conversation(), &[]),
+            "<|im_start|>system\nBe brief.<|im_end|>\n<|im_start|>user\nHi<|im_end|>\n\
+             <|im_start|>assistant\nHello!<|im_end|>\n<|im_start|>user\nHow are you?<|im_end|>\n\
+             <|im_start|>assistant\n"
+        );
+    }
+
+    #[test]
+    fn llama2_folds_the_system_prompt_into_the_first_turn() {
+        assert_eq!(ChatTemplate::Llama2.render(&[message(Role::User, "Hi")], &[]), "[INST] Hi [/INST]");
+        assert_eq!(
+            ChatTemplate::Llama2.render(&conversation(), &[]),
+            "[INST] <<SYS>>\nBe brief.\n<</SYS>>\n\nHi [/INST] Hello! </s><s>[INST] How are you? [/INST]"
+        );
+    }
+
+    #[test]
+    fn detect_picks_the_template_by_its_markers() {
+        let detect = |template: &str| ChatTemplate::detect(&json!({ "chat_template": template }));
+        assert_eq!(
+            detect("{% for message in messages %}<|im_start|>{{ message['role'] }}\n{% endfor %}"),
+            Some(ChatTemplate::ChatMl)
+        );
+        assert_eq!(
+            detect("{% if message['role'] == 'user' %}{{ '<|user|>\n' + message['content'] + eos_token }}{% endif %}"),
+            Some(ChatTemplate::Zephyr)
+        );
+        assert_eq!(
+            detect("{{ bos_token + '[INST] ' + message['content'] + ' [/INST]' }}"),
+            Some(ChatTemplate::Llama2)
+        );
+        assert_eq!(detect("{{ message['content'] }}"), None);
+        assert_eq!(ChatTemplate::detect(&json!({})), None);
+    }
+
+    #[test]
+    fn detect_uses_the_default_of_several_named_templates() {
+        let tokenizer_config = json!({
+            "chat_template": [
+                { "name": "tool_use", "template": "[INST] {{ tools }} [/INST]" },
+                { "name": "default", "template": "<|im_start|>{{ message['role'] }}" },
+            ]
+        });
+        assert_eq!(ChatTemplate::detect(&tokenizer_config), Some(ChatTemplate::ChatMl));
+        let without_default = json!({ "chat_template": [{ "name": "tool_use", "template": "[INST]" }] });
+        assert_eq!(ChatTemplate::detect(&without_default), None);
+    }
+}
@@ -0,0 +1,353 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+use crate::engine::{EXAMPLE_GGUF_REPO, EXAMPLE_MODEL};
+use crate::kv_cache::PreemptionMode;
+use crate::types::OverflowPolicy;
+
+// config file read at startup if it exists and no --config path is given
+const DEFAULT_CONFIG_PATH: &str = "server_config.json";
+
+/*
+ * Server settings, read from a JSON file (every field optional) and then overridden by
+ * command line flags, e.g.
+ *   cargo run -- --config my_config.json --workers 4
+ * Several models can be served at once, either listed under "models" in the file or with one
+ * --model flag each; per-model flags (--model-path, --gguf-file, ...) apply to the model named
+ * by the --model flag before them, e.g.
+ *   cargo run -- --model TinyLlama/TinyLlama-1.1B-Chat-v1.0 --model Qwen/Qwen2.5-0.5B-Instruct --chat-template chatml
+*/
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    // number of worker threads that take prefill and decode work, all of them share the loaded weights
+    pub num_workers: usize,
+    // threads per model that prepare requests (tokenize, fit into the context window, summarize)
+    // before they are queued for prefill
+    pub prep_threads: usize,
+    // max number of client request sessions decoded together in one batched forward pass
+    pub max_decode_batch_size: usize,
+    // decode passes per session before its batch goes back to the queue (ends early for a waiting prefill)
+    pub decode_quantum: usize,
+    // tokens a draft model proposes per decode pass for models that have one, 0 turns speculative decoding off
+    pub speculative_tokens: usize,
+    // max prompt tokens run in one prefill pass, longer prompts are prefilled in chunks interleaved
+    // with decode batches, 0 to prefill every prompt at once
+    pub prefill_chunk_size: usize,
+    // max number of sessions one user can have prefilling or decoding at once (per model), 0 for no limit
+    pub max_sessions_per_user: usize,
+    // admission limits per model, requests over them are turned away with 429/503 and Retry-After
+    // max number of requests waiting for prefill, 0 for no limit
+    pub max_queued_prefills: usize,
+    // max number of sessions prefilling or decoding at once, further requests wait for prefill, 0 for no limit
+    pub max_decode_sessions: usize,
+    // KV cache memory budget shared by all sessions of a model (accounted in blocks, preemption starts
+    // once it is used up), 0 for no limit
+    pub kv_cache_memory_mb: u64,
+    // what happens to a session whose KV cache blocks are needed by others: recompute or swap
+    pub preemption: PreemptionMode,
+    // host memory for swapped out KV caches (preemption swap), 0 for no limit
+    pub swap_space_mb: u64,
+    // seconds clients are told to wait before retrying a turned away request
+    pub retry_after_secs: u64,
+    // number of chats whose KV cache is kept between turns (least recently used is evicted), 0 disables it
+    pub prefix_cache_capacity: usize,
+    // seconds a streamed generation keeps running (and its events stay buffered for resuming)
+    // after its client disconnected, 0 cancels it right away
+    pub stream_resume_secs: u64,
+    // what to do with prompts that do not fit into the context window, requests can override it
+    pub overflow_policy: OverflowPolicy,
+    // only read model files from the local HF cache, never from the network
+    pub offline: bool,
+    // models to load at startup, the first one serves requests that do not name a model
+    pub models: Vec<ModelConfig>,
+}
+
+// one model to load, every model gets its own inference engine and worker pool
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ModelConfig {
+    // hub model id (e.g. TinyLlama/TinyLlama-1.1B-Chat-v1.0)
+    pub model_id: String,
+    // name clients pass as `model` in requests, the model id if not set
+    pub name: Option<String>,
+    // chat template used to render `messages` (zephyr, chatml or llama2), detected from the model if not set
+    pub chat_template: Option<String>,
+    // quantized GGUF weights file (e.g. a Q4_K_M or Q8_0 file) to load instead of the f32 safetensors
+    pub gguf_file: Option<String>,
+    // hub repo the GGUF file is downloaded from, the tokenizer still comes from the model's own repo
+    pub gguf_repo: Option<String>,
+    // local directory with config.json, tokenizer.json and the weights, nothing is downloaded if set
+    pub model_path: Option<PathBuf>,
+    // small model with the same tokenizer that proposes tokens for speculative decoding, only its
+    // model_id, model_path, gguf_file and gguf_repo are used
+    pub draft: Option<Box<ModelConfig>>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            num_workers: 2,
+            prep_threads: 4,
+            max_decode_batch_size: 8,
+            decode_quantum: 1,
+            speculative_tokens: 4,
+            prefill_chunk_size: 512,
+            max_sessions_per_user: 0,
+            max_queued_prefills: 64,
+            max_decode_sessions: 32,
+            kv_cache_memory_mb: 2048,
+            preemption: PreemptionMode::Recompute,
+            swap_space_mb: 0,
+            retry_after_secs: 1,
+            prefix_cache_capacity: 8,
+            stream_resume_secs: 30,
+            overflow_policy: OverflowPolicy::DropOldest,
+            offline: false,
+            models: vec![ModelConfig::default()],
+        }
+    }
+}
+
+impl Default for ModelConfig {
+    fn default() -> Self {
+        Self::new(EXAMPLE_MODEL)
+    }
+}
+
+impl ModelConfig {
+    fn new(model_id: &str) -> Self {
+        Self {
+            model_id: model_id.to_string(),
+            name: None,
+            chat_template: None,
+            gguf_file: None,
+            gguf_repo: None,
+            model_path: None,
+            draft: None,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.model_id)
+    }
+
+    // hub repo to take the GGUF file from, quantized versions of the example model have a known home
+    pub fn gguf_repo(&self) -> Option<&str> {
+        match &self.gguf_repo {
+            Some(repo) => Some(repo),
+            None if self.model_id == EXAMPLE_MODEL => Some(EXAMPLE_GGUF_REPO),
+            None => None,
+        }
+    }
+}
+
+impl ServerConfig {
+    // build the config from the config file and the process' command line arguments
+    pub fn load() -> Result<Self> {
+        Self::from_args(std::env::args().skip(1))
+    }
+
+    fn from_args(args: impl Iterator<Item = String>) -> Result<Self> {
+        let args: Vec<String> = args.collect();
+
+        // the config file has to be read first so the other flags can override it
+        let config_path = flag_value(&args, "--config")?.map(PathBuf::from);
+        let mut config = match config_path {
+            Some(path) => Self::from_file(&path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Self::from_file(Path::new(DEFAULT_CONFIG_PATH))?
+            }
+            None => Self::default(),
+        };
+
+        // models given on the command line replace the ones from the config file
+        let mut cli_models: Vec<ModelConfig> = Vec::new();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| {
+                args.next()
+                    .with_context(|| format!("missing value for {name}"))
+            };
+            match arg.as_str() {
+                "--config" => {
+                    value("--config")?;
+                }
+                "--workers" => config.num_workers = parse_number(value("--workers")?, "--workers")?,
+                "--prep-threads" => {
+                    config.prep_threads = parse_number(value("--prep-threads")?, "--prep-threads")?
+                }
+                "--max-batch-size" => {
+                    config.max_decode_batch_size =
+                        parse_number(value("--max-batch-size")?, "--max-batch-size")?
+                }
+                "--speculative-tokens" => {
+                    config.speculative_tokens =
+                        parse_number(value("--speculative-tokens")?, "--speculative-tokens")?
+                }
+                "--decode-quantum" => {
+                    config.decode_quantum = parse_number(value("--decode-quantum")?, "--decode-quantum")?
+                }
+                "--prefill-chunk-size" => {
+                    config.prefill_chunk_size =
+                        parse_number(value("--prefill-chunk-size")?, "--prefill-chunk-size")?
+                }
+                "--max-sessions-per-user" => {
+                    config.max_sessions_per_user = parse_number(
+                        value("--max-sessions-per-user")?,
+                        "--max-sessions-per-user",
+                    )?
+                }
+                "--max-queued-prefills" => {
+                    config.max_queued_prefills =
+                        parse_number(value("--max-queued-prefills")?, "--max-queued-prefills")?
+                }
+                "--max-decode-sessions" => {
+                    config.max_decode_sessions =
+                        parse_number(value("--max-decode-sessions")?, "--max-decode-sessions")?
+                }
+                "--kv-cache-memory-mb" => {
+                    config.kv_cache_memory_mb =
+                        parse_number(value("--kv-cache-memory-mb")?, "--kv-cache-memory-mb")? as u64
+                }
+                "--preemption" => {
+                    let name = value("--preemption")?;
+                    config.preemption = PreemptionMode::from_name(name).with_context(|| {
+                        format!("--preemption expects recompute or swap, got `{name}`")
+                    })?
+                }
+                "--swap-space-mb" => {
+                    config.swap_space_mb = parse_number(value("--swap-space-mb")?, "--swap-space-mb")? as u64
+                }
+                "--retry-after" => {
+                    config.retry_after_secs = parse_number(value("--retry-after")?, "--retry-after")? as u64
+                }
+                "--prefix-cache-capacity" => {
+                    config.prefix_cache_capacity = parse_number(
+                        value("--prefix-cache-capacity")?,
+                        "--prefix-cache-capacity",
+                    )?
+                }
+                "--stream-resume-secs" => {
+                    config.stream_resume_secs =
+                        parse_number(value("--stream-resume-secs")?, "--stream-resume-secs")? as u64
+                }
+                "--model" => cli_models.push(ModelConfig::new(value("--model")?)),
+                "--model-name" => {
+                    current_model(&mut cli_models, &mut config)?.name =
+                        Some(value("--model-name")?.clone())
+                }
+                "--chat-template" => {
+                    current_model(&mut cli_models, &mut config)?.chat_template =
+                        Some(value("--chat-template")?.clone())
+                }
+                "--overflow-policy" => {
+                    let name = value("--overflow-policy")?;
+                    config.overflow_policy = OverflowPolicy::from_name(name).with_context(|| {
+                        format!("--overflow-policy expects reject, drop_oldest or summarize, got `{name}`")
+                    })?
+                }
+                "--gguf-file" => {
+                    current_model(&mut cli_models, &mut config)?.gguf_file =
+                        Some(value("--gguf-file")?.clone())
+                }
+                "--gguf-repo" => {
+                    current_model(&mut cli_models, &mut config)?.gguf_repo =
+                        Some(value("--gguf-repo")?.clone())
+                }
+                "--model-path" => {
+                    current_model(&mut cli_models, &mut config)?.model_path =
+                        Some(PathBuf::from(value("--model-path")?))
+                }
+                "--draft-model" => {
+                    current_model(&mut cli_models, &mut config)?.draft =
+                        Some(Box::new(ModelConfig::new(value("--draft-model")?)))
+                }
+                "--offline" => config.offline = true,
+                other => bail!("unknown argument `{other}`"),
+            }
+        }
+
+        if !cli_models.is_empty() {
+            config.models = cli_models;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Self> {
+        let contents = std::fs::read(path)
+            .with_context(|| format!("read config file {}", path.display()))?;
+        serde_json::from_slice(&contents)
+            .with_context(|| format!("parse config file {}", path.display()))
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.num_workers == 0 {
+            bail!("num_workers must be at least 1");
+        }
+        if self.prep_threads == 0 {
+            bail!("prep_threads must be at least 1");
+        }
+        if self.max_decode_batch_size == 0 {
+            bail!("max_decode_batch_size must be at least 1");
+        }
+        if self.decode_quantum == 0 {
+            bail!("decode_quantum must be at least 1");
+        }
+        if self.models.is_empty() {
+            bail!("at least one model has to be configured");
+        }
+        for (i, model) in self.models.iter().enumerate() {
+            let name = model.name();
+            if self.models[..i].iter().any(|other| other.name() == name) {
+                bail!("model name `{name}` is used more than once, set a different name for one of them");
+            }
+            check_gguf(model, &format!("model `{name}`"))?;
+            if let Some(draft) = &model.draft {
+                check_gguf(draft, &format!("draft model of `{name}`"))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn check_gguf(model: &ModelConfig, label: &str) -> Result<()> {
+    if model.gguf_repo.is_some() && model.gguf_file.is_none() {
+        bail!("{label}: gguf_repo is set but gguf_file is not, pick the GGUF file to load from it");
+    }
+    if model.gguf_file.is_some() && model.model_path.is_none() && model.gguf_repo().is_none() {
+        bail!("{label}: gguf_file needs a gguf_repo (or model_path) to load it from");
+    }
+    Ok(())
+}
+
+// model that per-model flags apply to: the last --model given so far, or the first configured model
+fn current_model<'a>(
+    cli_models: &'a mut [ModelConfig],
+    config: &'a mut ServerConfig,
+) -> Result<&'a mut ModelConfig> {
+    match cli_models.last_mut() {
+        Some(model) => Ok(model),
+        None => config.models.first_mut().context("no model configured"),
+    }
+}
+
+fn flag_value(args: &[String], flag: &str) -> Result<Option<String>> {
+    match args.iter().position(|arg| arg == flag) {
+        Some(i) => args
+            .get(i + 1)
+            .cloned()
+            .map(Some)
+            .with_context(|| format!("missing value for {flag}")),
+        None => Ok(None),
+    }
+}
+
+fn parse_number(value: &str, flag: &str) -> Result<usize> {
+    value
+        .parse()
+        .with_context(|| format!("{flag} expects a number, got `{value}`"))
+}
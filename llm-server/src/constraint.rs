@@ -0,0 +1,883 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Result};
+use candle_core::Tensor;
+use regex_automata::dfa::{dense, Automaton, StartKind};
+use regex_automata::util::primitives::StateID;
+use regex_automata::util::start;
+use regex_automata::Anchored;
+use serde_json::Value;
+use tokenizers::{DecoderWrapper, Tokenizer};
+
+use crate::types::{GenerationParams, ResponseFormat};
+
+// compiled regex DFAs bigger than this are rejected instead of slowing every decode step down
+const DFA_SIZE_LIMIT: usize = 32 << 20;
+// how deep `$ref`s in a JSON schema are followed, recursive schemas cannot be turned into a regex
+const MAX_SCHEMA_DEPTH: usize = 16;
+// whitespace allowed between JSON tokens in schema constrained output
+const WS: &str = r"[ \t\n]*";
+
+/*
+ * Constrained decoding: the answer has to match a grammar, either any JSON object
+ * (response_format json_object), a JSON schema (response_format json_schema, turned into a regex)
+ * or a regex. Before every sampling step the grammar's current state is run over the bytes of every
+ * vocabulary token, and tokens that would break the grammar get their logits masked to -inf. The
+ * tokens allowed from a state are cached (per DFA state, or per JSON parser state), so an answer
+ * only walks the vocabulary once for every state it passes through.
+ * End of sequence and stop tokens are only allowed once the answer is complete, and once nothing
+ * can follow the answer the session ends on its own.
+ * The state lives in the session, so it carries over from the prefill's first token to the
+ * decode steps and every session in a decode batch is masked with its own state.
+*/
+pub enum Grammar {
+    Regex(Box<RegexGrammar>),
+    JsonObject,
+}
+
+pub struct RegexGrammar {
+    dfa: dense::DFA<Vec<u32>>,
+    start: StateID,
+    // states from which the rest of the answer can still complete a match
+    live: HashSet<StateID>,
+}
+
+impl Grammar {
+    // grammar requested by the client, errors are messages for the client
+    pub fn from_params(params: &GenerationParams) -> Result<Option<Self>, String> {
+        let pattern = match (&params.response_format, &params.regex) {
+            (Some(_), Some(_)) => {
+                return Err("params.response_format and params.regex cannot be used together".to_string())
+            }
+            (None, None) | (Some(ResponseFormat::Text), None) => return Ok(None),
+            (Some(ResponseFormat::JsonObject), None) => return Ok(Some(Self::JsonObject)),
+            (Some(ResponseFormat::JsonSchema { schema }), None) => {
+                SchemaRegex { root: schema }.value(schema, 0)?
+            }
+            (None, Some(regex)) => regex.clone(),
+        };
+        Self::regex(&pattern).map(Some)
+    }
+
+    // the whole answer has to match the pattern
+    fn regex(pattern: &str) -> Result<Self, String> {
+        let config = dense::Config::new()
+            .start_kind(StartKind::Anchored)
+            .dfa_size_limit(Some(DFA_SIZE_LIMIT))
+            .determinize_size_limit(Some(DFA_SIZE_LIMIT));
+        let dfa = dense::Builder::new()
+            .configure(config)
+            .build(pattern)
+            .map_err(|err| format!("invalid constraint pattern: {err}"))?;
+        let start = dfa
+            .start_state(&start::Config::new().anchored(Anchored::Yes))
+            .map_err(|err| format!("invalid constraint pattern: {err}"))?;
+
+        // dense DFAs report a match one byte late, so a state that is neither dead nor a match
+        // state can still be one no answer gets out of, only states that reach a match count
+        let mut reachable = vec![start];
+        let mut seen = HashSet::from([start]);
+        let mut parents: HashMap<StateID, Vec<StateID>> = HashMap::new();
+        while let Some(state) = reachable.pop() {
+            for byte in 0..=255u8 {
+                let next = dfa.next_state(state, byte);
+                if dfa.is_dead_state(next) || dfa.is_quit_state(next) {
+                    continue;
+                }
+                parents.entry(next).or_default().push(state);
+                if seen.insert(next) {
+                    reachable.push(next);
+                }
+            }
+        }
+        let mut live: HashSet<StateID> = seen
+            .into_iter()
+            .filter(|&state| dfa.is_match_state(dfa.next_eoi_state(state)))
+            .collect();
+        let mut pending: Vec<StateID> = live.iter().copied().collect();
+        while let Some(state) = pending.pop() {
+            for &parent in parents.get(&state).into_iter().flatten() {
+                if live.insert(parent) {
+                    pending.push(parent);
+                }
+            }
+        }
+        if !live.contains(&start) {
+            return Err("constraint pattern cannot match anything".to_string());
+        }
+        Ok(Self::Regex(Box::new(RegexGrammar { dfa, start, live })))
+    }
+
+    fn start(&self) -> MatchState {
+        match self {
+            Self::Regex(regex) => MatchState::Regex(regex.start),
+            Self::JsonObject => MatchState::Json(JsonMatcher::default()),
+        }
+    }
+}
+
+// bytes of every vocabulary token in a trie, so tokens sharing a prefix share the grammar steps for it
+pub struct TokenVocab {
+    nodes: Vec<TrieNode>,
+    token_bytes: HashMap<u32, Vec<u8>>,
+}
+
+#[derive(Default)]
+struct TrieNode {
+    children: Vec<(u8, usize)>,
+    // tokens whose bytes end at this node
+    tokens: Vec<u32>,
+}
+
+impl TokenVocab {
+    // special tokens have no bytes and can never be part of a constrained answer
+    pub fn new(tokenizer: &Tokenizer) -> Self {
+        let byte_level = matches!(tokenizer.get_decoder(), Some(DecoderWrapper::ByteLevel(_)));
+        let byte_decoder = byte_level.then(gpt2_byte_decoder);
+        let special: Vec<u32> = tokenizer
+            .get_added_tokens_decoder()
+            .into_iter()
+            .filter(|(_, token)| token.special)
+            .map(|(id, _)| id)
+            .collect();
+
+        let mut vocab = Self {
+            nodes: vec![TrieNode::default()],
+            token_bytes: HashMap::new(),
+        };
+        for (token, id) in tokenizer.get_vocab(true) {
+            if special.contains(&id) {
+                continue;
+            }
+            let bytes = match &byte_decoder {
+                Some(byte_decoder) => token.chars().map(|c| byte_decoder.get(&c).copied()).collect(),
+                None => sentencepiece_bytes(&token),
+            };
+            if let Some(bytes) = bytes.filter(|bytes: &Vec<u8>| !bytes.is_empty()) {
+                vocab.insert(&bytes, id);
+            }
+        }
+        vocab
+    }
+
+    fn insert(&mut self, bytes: &[u8], id: u32) {
+        let mut node = 0;
+        for &byte in bytes {
+            node = match self.nodes[node].children.iter().find(|(b, _)| *b == byte) {
+                Some(&(_, child)) => child,
+                None => {
+                    self.nodes.push(TrieNode::default());
+                    let child = self.nodes.len() - 1;
+                    self.nodes[node].children.push((byte, child));
+                    child
+                }
+            };
+        }
+        self.nodes[node].tokens.push(id);
+        self.token_bytes.insert(id, bytes.to_vec());
+    }
+}
+
+// llama style vocabularies: "▁" stands for a space and "<0x0A>" for a raw byte
+fn sentencepiece_bytes(token: &str) -> Option<Vec<u8>> {
+    if let Some(hex) = token.strip_prefix("<0x").and_then(|rest| rest.strip_suffix('>')) {
+        return u8::from_str_radix(hex, 16).ok().map(|byte| vec![byte]);
+    }
+    Some(token.replace('\u{2581}', " ").into_bytes())
+}
+
+// GPT-2 style byte level vocabularies spell every byte as a printable char
+fn gpt2_byte_decoder() -> HashMap<char, u8> {
+    let mut byte_decoder = HashMap::with_capacity(256);
+    let mut unprintable = 0;
+    for byte in 0..=255u8 {
+        let printable = matches!(byte, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF);
+        let c = if printable {
+            char::from(byte)
+        } else {
+            unprintable += 1;
+            char::from_u32(255 + unprintable).unwrap()
+        };
+        byte_decoder.insert(c, byte);
+    }
+    byte_decoder
+}
+
+// tokens a grammar allows from each state seen so far
+type AllowedTokens = Mutex<HashMap<StateKey, Arc<[u32]>>>;
+
+// a grammar and how far the answer got through it, one per constrained session
+// sessions forked off the same request share the cache of allowed tokens
+#[derive(Clone)]
+pub struct TokenConstraint {
+    grammar: Arc<Grammar>,
+    vocab: Arc<TokenVocab>,
+    state: MatchState,
+    allowed_tokens: Arc<AllowedTokens>,
+}
+
+impl TokenConstraint {
+    pub fn new(grammar: Arc<Grammar>, vocab: Arc<TokenVocab>) -> Self {
+        let state = grammar.start();
+        Self {
+            grammar,
+            vocab,
+            state,
+            allowed_tokens: Arc::default(),
+        }
+    }
+
+    // set the logits of every token the grammar does not allow next to -inf
+    // end_token_ids are allowed once the answer is complete
+    pub fn mask(&self, logits: &Tensor, end_token_ids: &[u32]) -> Result<Tensor> {
+        let mut mask = vec![f32::NEG_INFINITY; logits.dim(0)?];
+        let mut allowed = 0;
+        let mut allow = |id: u32| {
+            if let Some(value) = mask.get_mut(id as usize) {
+                *value = 0.0;
+                allowed += 1;
+            }
+        };
+
+        self.allowed_tokens().iter().for_each(|&id| allow(id));
+        if self.state.is_accepting(&self.grammar) {
+            end_token_ids.iter().for_each(|&id| allow(id));
+        }
+        if allowed == 0 {
+            bail!("no token can continue the constrained answer");
+        }
+
+        let mask = Tensor::new(mask, logits.device())?.to_dtype(logits.dtype())?;
+        Ok(logits.add(&mask)?)
+    }
+
+    // vocabulary tokens the grammar allows from the current state, from the cache or by walking the
+    // vocabulary trie once
+    fn allowed_tokens(&self) -> Arc<[u32]> {
+        let key = self.state.key();
+        if let Some(tokens) = self.allowed_tokens.lock().unwrap().get(&key) {
+            return Arc::clone(tokens);
+        }
+
+        let mut tokens = Vec::new();
+        let mut pending = vec![(0, self.state.clone())];
+        while let Some((node, state)) = pending.pop() {
+            for &(byte, child) in &self.vocab.nodes[node].children {
+                // cheap to clone: a DFA state id, or a JSON parser whose stack is shared
+                let mut next = state.clone();
+                if next.advance(&self.grammar, byte) {
+                    tokens.extend_from_slice(&self.vocab.nodes[child].tokens);
+                    pending.push((child, next));
+                }
+            }
+        }
+        let tokens: Arc<[u32]> = tokens.into();
+        self.allowed_tokens.lock().unwrap().insert(key, Arc::clone(&tokens));
+        tokens
+    }
+
+    // move the grammar state past a sampled token (one that is not an end token)
+    pub fn advance(&mut self, token: u32) -> Result<()> {
+        let Some(bytes) = self.vocab.token_bytes.get(&token) else {
+            bail!("sampled token {token} is not part of the constrained vocabulary");
+        };
+        for &byte in bytes {
+            if !self.state.advance(&self.grammar, byte) {
+                bail!("sampled token {token} does not match the constraint");
+            }
+        }
+        Ok(())
+    }
+
+    // the answer is complete and nothing else could be appended to it
+    pub fn is_finished(&self) -> bool {
+        self.state.is_accepting(&self.grammar)
+            && !(0..=255u8).any(|byte| self.state.clone().advance(&self.grammar, byte))
+    }
+}
+
+#[derive(Clone)]
+enum MatchState {
+    Regex(StateID),
+    Json(JsonMatcher),
+}
+
+// what the allowed tokens of a state depend on
+#[derive(PartialEq, Eq, Hash)]
+enum StateKey {
+    Regex(StateID),
+    // parser mode and open objects / arrays, outermost first
+    Json(JsonMode, Vec<bool>),
+}
+
+impl MatchState {
+    fn key(&self) -> StateKey {
+        match self {
+            Self::Regex(state) => StateKey::Regex(*state),
+            Self::Json(matcher) => StateKey::Json(matcher.mode, matcher.stack.to_vec()),
+        }
+    }
+
+    // returns false if the byte breaks the grammar
+    fn advance(&mut self, grammar: &Grammar, byte: u8) -> bool {
+        match (self, grammar) {
+            (Self::Regex(state), Grammar::Regex(regex)) => {
+                *state = regex.dfa.next_state(*state, byte);
+                regex.live.contains(state)
+            }
+            (Self::Json(matcher), Grammar::JsonObject) => matcher.advance(byte),
+            _ => false,
+        }
+    }
+
+    fn is_accepting(&self, grammar: &Grammar) -> bool {
+        match (self, grammar) {
+            // dense DFAs report a match one byte late, the end of input counts as that byte
+            (Self::Regex(state), Grammar::Regex(regex)) => {
+                regex.dfa.is_match_state(regex.dfa.next_eoi_state(*state))
+            }
+            (Self::Json(matcher), Grammar::JsonObject) => matcher.mode == JsonMode::Done,
+            _ => false,
+        }
+    }
+}
+
+/*
+ * Byte by byte JSON parser that accepts exactly the prefixes of a JSON object (what json_object
+ * asks for). A regex cannot count brackets, so nesting is tracked on a stack.
+*/
+#[derive(Debug, Clone, Default)]
+struct JsonMatcher {
+    // open objects (true) and arrays (false)
+    stack: JsonStack,
+    mode: JsonMode,
+}
+
+// persistent stack: the parser is cloned for every byte the vocabulary walk tries, the clones share
+// their frames and only a push allocates
+#[derive(Debug, Clone, Default)]
+struct JsonStack(Option<Arc<StackFrame>>);
+
+#[derive(Debug)]
+struct StackFrame {
+    object: bool,
+    parent: JsonStack,
+}
+
+impl JsonStack {
+    fn push(&mut self, object: bool) {
+        let parent = std::mem::take(self);
+        self.0 = Some(Arc::new(StackFrame { object, parent }));
+    }
+
+    fn pop(&mut self) -> Option<bool> {
+        let frame = self.0.take()?;
+        *self = frame.parent.clone();
+        Some(frame.object)
+    }
+
+    fn last(&self) -> Option<bool> {
+        self.0.as_ref().map(|frame| frame.object)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_none()
+    }
+
+    // outermost first
+    fn to_vec(&self) -> Vec<bool> {
+        let mut open = Vec::new();
+        let mut frame = self.0.as_ref();
+        while let Some(current) = frame {
+            open.push(current.object);
+            frame = current.parent.0.as_ref();
+        }
+        open.reverse();
+        open
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+enum JsonMode {
+    // before the top-level object
+    #[default]
+    Start,
+    // a value has to follow
+    Value,
+    // right after "[": a value or "]"
+    ValueOrEnd,
+    // right after "{": a key or "}"
+    KeyOrEnd,
+    // after "," in an object
+    Key,
+    Colon,
+    String { key: bool, escape: Escape },
+    Number(NumberPart),
+    // rest of true, false or null
+    Literal(&'static [u8]),
+    // after a value inside an object or array: ",", "}" or "]"
+    AfterValue,
+    // the top-level object is closed
+    Done,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Escape {
+    None,
+    Backslash,
+    // hex digits left in a \u escape
+    Unicode(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum NumberPart {
+    Minus,
+    Zero,
+    Integer,
+    Dot,
+    Fraction,
+    Exponent,
+    ExponentSign,
+    ExponentDigits,
+}
+
+impl NumberPart {
+    // the number could end here
+    fn is_complete(self) -> bool {
+        matches!(self, Self::Zero | Self::Integer | Self::Fraction | Self::ExponentDigits)
+    }
+
+    fn next(self, byte: u8) -> Option<Self> {
+        match (self, byte) {
+            (Self::Minus, b'0') => Some(Self::Zero),
+            (Self::Minus, b'1'..=b'9') => Some(Self::Integer),
+            (Self::Integer, b'0'..=b'9') => Some(Self::Integer),
+            (Self::Zero | Self::Integer, b'.') => Some(Self::Dot),
+            (Self::Dot | Self::Fraction, b'0'..=b'9') => Some(Self::Fraction),
+            (Self::Zero | Self::Integer | Self::Fraction, b'e' | b'E') => Some(Self::Exponent),
+            (Self::Exponent, b'+' | b'-') => Some(Self::ExponentSign),
+            (Self::Exponent | Self::ExponentSign | Self::ExponentDigits, b'0'..=b'9') => {
+                Some(Self::ExponentDigits)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl JsonMatcher {
+    fn advance(&mut self, byte: u8) -> bool {
+        let whitespace = matches!(byte, b' ' | b'\t' | b'\n' | b'\r');
+        match self.mode {
+            JsonMode::Start if whitespace => true,
+            JsonMode::Start if byte == b'{' => {
+                self.stack.push(true);
+                self.mode = JsonMode::KeyOrEnd;
+                true
+            }
+            JsonMode::Value | JsonMode::ValueOrEnd | JsonMode::KeyOrEnd | JsonMode::Key
+            | JsonMode::Colon | JsonMode::AfterValue
+                if whitespace =>
+            {
+                true
+            }
+            JsonMode::ValueOrEnd if byte == b']' => self.close(false),
+            JsonMode::Value | JsonMode::ValueOrEnd => self.start_value(byte),
+            JsonMode::KeyOrEnd if byte == b'}' => self.close(true),
+            JsonMode::KeyOrEnd | JsonMode::Key if byte == b'"' => {
+                self.mode = JsonMode::String { key: true, escape: Escape::None };
+                true
+            }
+            JsonMode::Colon if byte == b':' => {
+                self.mode = JsonMode::Value;
+                true
+            }
+            JsonMode::String { key, escape } => self.string(key, escape, byte),
+            JsonMode::Number(part) => match part.next(byte) {
+                Some(next) => {
+                    self.mode = JsonMode::Number(next);
+                    true
+                }
+                // the byte after a number belongs to whatever follows the number
+                None if part.is_complete() => {
+                    self.end_value();
+                    self.advance(byte)
+                }
+                None => false,
+            },
+            JsonMode::Literal(rest) if rest[0] == byte => {
+                if rest.len() == 1 {
+                    self.end_value();
+                } else {
+                    self.mode = JsonMode::Literal(&rest[1..]);
+                }
+                true
+            }
+            JsonMode::AfterValue => match (byte, self.stack.last()) {
+                (b',', Some(true)) => {
+                    self.mode = JsonMode::Key;
+                    true
+                }
+                (b',', Some(false)) => {
+                    self.mode = JsonMode::Value;
+                    true
+                }
+                (b'}', Some(true)) => self.close(true),
+                (b']', Some(false)) => self.close(false),
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    fn start_value(&mut self, byte: u8) -> bool {
+        self.mode = match byte {
+            b'{' => {
+                self.stack.push(true);
+                JsonMode::KeyOrEnd
+            }
+            b'[' => {
+                self.stack.push(false);
+                JsonMode::ValueOrEnd
+            }
+            b'"' => JsonMode::String { key: false, escape: Escape::None },
+            b'-' => JsonMode::Number(NumberPart::Minus),
+            b'0' => JsonMode::Number(NumberPart::Zero),
+            b'1'..=b'9' => JsonMode::Number(NumberPart::Integer),
+            b't' => JsonMode::Literal(b"rue"),
+            b'f' => JsonMode::Literal(b"alse"),
+            b'n' => JsonMode::Literal(b"ull"),
+            _ => return false,
+        };
+        true
+    }
+
+    fn string(&mut self, key: bool, escape: Escape, byte: u8) -> bool {
+        let escape = match (escape, byte) {
+            (Escape::None, b'"') => {
+                if key {
+                    self.mode = JsonMode::Colon;
+                } else {
+                    self.end_value();
+                }
+                return true;
+            }
+            (Escape::None, b'\\') => Escape::Backslash,
+            (Escape::None, 0x00..=0x1F) => return false,
+            (Escape::None, _) => Escape::None,
+            (Escape::Backslash, b'"' | b'\\' | b'/' | b'b' | b'f' | b'n' | b'r' | b't') => Escape::None,
+            (Escape::Backslash, b'u') => Escape::Unicode(4),
+            (Escape::Unicode(left), b'0'..=b'9' | b'a'..=b'f' | b'A'..=b'F') => {
+                if left == 1 {
+                    Escape::None
+                } else {
+                    Escape::Unicode(left - 1)
+                }
+            }
+            _ => return false,
+        };
+        self.mode = JsonMode::String { key, escape };
+        true
+    }
+
+    fn close(&mut self, object: bool) -> bool {
+        if self.stack.pop() != Some(object) {
+            return false;
+        }
+        self.end_value();
+        true
+    }
+
+    fn end_value(&mut self) {
+        self.mode = if self.stack.is_empty() {
+            JsonMode::Done
+        } else {
+            JsonMode::AfterValue
+        };
+    }
+}
+
+// turns a JSON schema into a regex matching the JSON documents it describes (properties in schema order)
+struct SchemaRegex<'a> {
+    // schema `$ref`s are resolved against
+    root: &'a Value,
+}
+
+impl SchemaRegex<'_> {
+    fn value(&self, schema: &Value, depth: usize) -> Result<String, String> {
+        if depth > MAX_SCHEMA_DEPTH {
+            return Err("json_schema is nested too deep (recursive schemas are not supported)".to_string());
+        }
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            let target = reference
+                .strip_prefix('#')
+                .and_then(|pointer| self.root.pointer(pointer))
+                .ok_or_else(|| format!("json_schema: cannot resolve $ref `{reference}`"))?;
+            return self.value(target, depth + 1);
+        }
+        if let Some(value) = schema.get("const") {
+            return Ok(literal(value));
+        }
+        if let Some(values) = schema.get("enum").and_then(Value::as_array) {
+            return Ok(alternatives(values.iter().map(literal)));
+        }
+        for key in ["anyOf", "oneOf"] {
+            if let Some(schemas) = schema.get(key).and_then(Value::as_array) {
+                let options = schemas
+                    .iter()
+                    .map(|schema| self.value(schema, depth + 1))
+                    .collect::<Result<Vec<_>, _>>()?;
+                return Ok(alternatives(options.into_iter()));
+            }
+        }
+
+        match schema.get("type") {
+            Some(Value::String(ty)) => self.typed(ty, schema, depth),
+            Some(Value::Array(types)) => {
+                let options = types
+                    .iter()
+                    .map(|ty| match ty.as_str() {
+                        Some(ty) => self.typed(ty, schema, depth),
+                        None => Err("json_schema: type must be a string".to_string()),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(alternatives(options.into_iter()))
+            }
+            // objects are the most common schema and are often written without a type
+            None if schema.get("properties").is_some() => self.typed("object", schema, depth),
+            _ => Err("json_schema: every schema needs a type, enum, const, anyOf or $ref".to_string()),
+        }
+    }
+
+    fn typed(&self, ty: &str, schema: &Value, depth: usize) -> Result<String, String> {
+        match ty {
+            "object" => self.object(schema, depth),
+            "array" => self.array(schema, depth),
+            "string" => Ok(string(schema)),
+            "integer" => Ok(r"-?(0|[1-9][0-9]*)".to_string()),
+            "number" => Ok(r"-?(0|[1-9][0-9]*)(\.[0-9]+)?([eE][+-]?[0-9]+)?".to_string()),
+            "boolean" => Ok("(true|false)".to_string()),
+            "null" => Ok("null".to_string()),
+            other => Err(format!("json_schema: unsupported type `{other}`")),
+        }
+    }
+
+    fn object(&self, schema: &Value, depth: usize) -> Result<String, String> {
+        let Some(properties) = schema.get("properties").and_then(Value::as_object) else {
+            return Err("json_schema: objects need `properties` (use response_format json_object for free-form objects)".to_string());
+        };
+        let required: Vec<&str> = schema
+            .get("required")
+            .and_then(Value::as_array)
+            .map(|required| required.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+        let members = properties
+            .iter()
+            .map(|(name, property)| {
+                let member = format!(
+                    "{}{WS}:{WS}{}",
+                    literal(&Value::String(name.clone())),
+                    self.value(property, depth + 1)?
+                );
+                Ok((member, required.contains(&name.as_str())))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        // members after the first one written are prefixed with a comma, optional members can be
+        // left out, so which member comes first is only known once a required one is reached
+        let mut after_first = String::new();
+        let mut first = String::new();
+        for (member, is_required) in members.iter().rev() {
+            let with_comma = format!(",{WS}{member}");
+            if *is_required {
+                first = format!("{member}{WS}{after_first}");
+                after_first = format!("{with_comma}{WS}{after_first}");
+            } else {
+                first = format!("({member}{WS}{after_first}|{first})");
+                after_first = format!("({with_comma}{WS})?{after_first}");
+            }
+        }
+        Ok(format!(r"\{{{WS}{first}\}}"))
+    }
+
+    fn array(&self, schema: &Value, depth: usize) -> Result<String, String> {
+        let item = match schema.get("items") {
+            Some(items) => self.value(items, depth + 1)?,
+            None => return Err("json_schema: arrays need `items`".to_string()),
+        };
+        let min = schema.get("minItems").and_then(Value::as_u64).unwrap_or(0);
+        let max = schema.get("maxItems").and_then(Value::as_u64);
+        let more = format!("{WS},{WS}{item}");
+        let rest = match max {
+            Some(max) => format!("({more}){{{},{}}}", min.saturating_sub(1), max.saturating_sub(1)),
+            None => format!("({more}){{{},}}", min.saturating_sub(1)),
+        };
+        let items = if max == Some(0) {
+            String::new()
+        } else if min == 0 {
+            format!("({item}{rest})?")
+        } else {
+            format!("{item}{rest}")
+        };
+        Ok(format!(r"\[{WS}{items}{WS}\]"))
+    }
+}
+
+fn string(schema: &Value) -> String {
+    if let Some(pattern) = schema.get("pattern").and_then(Value::as_str) {
+        let pattern = pattern.strip_prefix('^').unwrap_or(pattern);
+        let pattern = pattern.strip_suffix('$').unwrap_or(pattern);
+        return format!("\"({pattern})\"");
+    }
+    let char = r#"([^"\\\x00-\x1F]|\\["\\/bfnrt]|\\u[0-9a-fA-F]{4})"#;
+    let min = schema.get("minLength").and_then(Value::as_u64).unwrap_or(0);
+    match schema.get("maxLength").and_then(Value::as_u64) {
+        Some(max) => format!("\"{char}{{{min},{max}}}\""),
+        None => format!("\"{char}{{{min},}}\""),
+    }
+}
+
+// regex matching exactly the JSON encoding of a value
+fn literal(value: &Value) -> String {
+    regex_syntax::escape(&value.to_string())
+}
+
+fn alternatives(options: impl Iterator<Item = String>) -> String {
+    format!("({})", options.collect::<Vec<_>>().join("|"))
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device};
+    use serde_json::json;
+
+    use super::*;
+
+    const EOS: u32 = 100;
+
+    // vocabulary of the given tokens, a token's id is its index
+    fn vocab(tokens: &[&str]) -> Arc<TokenVocab> {
+        let mut vocab = TokenVocab {
+            nodes: vec![TrieNode::default()],
+            token_bytes: HashMap::new(),
+        };
+        for (id, token) in tokens.iter().enumerate() {
+            vocab.insert(token.as_bytes(), id as u32);
+        }
+        Arc::new(vocab)
+    }
+
+    fn constraint(grammar: Grammar, tokens: &[&str]) -> TokenConstraint {
+        TokenConstraint::new(Arc::new(grammar), vocab(tokens))
+    }
+
+    // ids of the tokens left unmasked
+    fn allowed(constraint: &TokenConstraint) -> Vec<u32> {
+        let logits = Tensor::zeros(EOS as usize + 1, DType::F32, &Device::Cpu).unwrap();
+        let masked: Vec<f32> = constraint.mask(&logits, &[EOS]).unwrap().to_vec1().unwrap();
+        (0..masked.len() as u32)
+            .filter(|&id| masked[id as usize].is_finite())
+            .collect()
+    }
+
+    fn schema_grammar(schema: Value) -> Grammar {
+        let params = GenerationParams {
+            response_format: Some(ResponseFormat::JsonSchema { schema }),
+            ..GenerationParams::default()
+        };
+        Grammar::from_params(&params).unwrap().unwrap()
+    }
+
+    #[test]
+    fn regex_mask_allows_only_matching_tokens() {
+        let mut constraint = constraint(Grammar::regex("[0-9]+").unwrap(), &["1", "23", "a", "1a"]);
+        assert_eq!(allowed(&constraint), vec![0, 1]);
+
+        // EOS only once the answer matches
+        constraint.advance(1).unwrap();
+        assert_eq!(allowed(&constraint), vec![0, 1, EOS]);
+        assert!(constraint.advance(2).is_err());
+    }
+
+    #[test]
+    fn regex_mask_is_cached_per_state() {
+        let mut constraint = constraint(Grammar::regex("[0-9]+").unwrap(), &["1", "23", "a"]);
+        for _ in 0..2 {
+            constraint.advance(1).unwrap();
+            allowed(&constraint);
+        }
+        let states = constraint.allowed_tokens.lock().unwrap().len();
+        // more digits loop through the same DFA states, their tokens come from the cache
+        for _ in 0..3 {
+            constraint.advance(1).unwrap();
+            assert_eq!(allowed(&constraint), vec![0, 1, EOS]);
+        }
+        assert_eq!(constraint.allowed_tokens.lock().unwrap().len(), states);
+    }
+
+    #[test]
+    fn json_mask_follows_nesting() {
+        let tokens = ["{", "}", "\"a\"", ":", "[", "]", "1", "x", "{}", "]}"];
+        let mut constraint = constraint(Grammar::JsonObject, &tokens);
+        // only an object can start the answer
+        assert_eq!(allowed(&constraint), vec![0, 8]);
+
+        for id in [0, 2, 3, 4, 4, 6] {
+            constraint.advance(id).unwrap();
+        }
+        // inside `{"a":[[1`: more digits, or close the inner array, never the object
+        assert_eq!(allowed(&constraint), vec![5, 6]);
+
+        constraint.advance(5).unwrap();
+        assert_eq!(allowed(&constraint), vec![5, 9]);
+        constraint.advance(9).unwrap();
+        assert!(constraint.is_finished());
+        assert_eq!(allowed(&constraint), vec![EOS]);
+    }
+
+    #[test]
+    fn json_clones_share_the_stack() {
+        let mut matcher = JsonMatcher::default();
+        for &byte in b"{\"a\":[{" {
+            assert!(matcher.advance(byte));
+        }
+        let mut branch = matcher.clone();
+        assert!(branch.advance(b'}'));
+        assert!(branch.advance(b']'));
+        // closing in the clone leaves the original's stack as it was
+        assert_eq!(matcher.stack.to_vec(), vec![true, false, true]);
+        assert_eq!(branch.stack.to_vec(), vec![true]);
+    }
+
+    #[test]
+    fn json_schema_mask_follows_properties() {
+        let grammar = schema_grammar(json!({
+            "type": "object",
+            "properties": { "ok": { "type": "boolean" } },
+            "required": ["ok"],
+        }));
+        let tokens = ["{", "\"ok\"", ":", "true", "1", "}", "\"no\""];
+        let mut constraint = constraint(grammar, &tokens);
+        assert_eq!(allowed(&constraint), vec![0]);
+
+        constraint.advance(0).unwrap();
+        assert_eq!(allowed(&constraint), vec![1]);
+        constraint.advance(1).unwrap();
+        constraint.advance(2).unwrap();
+        // a boolean, not a number
+        assert_eq!(allowed(&constraint), vec![3]);
+        constraint.advance(3).unwrap();
+        assert_eq!(allowed(&constraint), vec![5]);
+        constraint.advance(5).unwrap();
+        assert!(constraint.is_finished());
+    }
+
+    #[test]
+    fn json_schema_rejects_unsupported_types() {
+        let params = GenerationParams {
+            response_format: Some(ResponseFormat::JsonSchema { schema: json!({ "type": "date" }) }),
+            ..GenerationParams::default()
+        };
+        assert!(Grammar::from_params(&params).is_err());
+    }
+}
@@ -1,78 +1,241 @@
-use std::{
-    collections::VecDeque,
-    sync::{Arc, Condvar, Mutex},
-    thread,
+use std::collections::{BTreeMap, VecDeque};
+use std::pin::Pin;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    mpsc as std_mpsc,
+    Arc,
 };
-use anyhow::{anyhow, Context, Result};
-use candle_core::{DType, Device, Tensor};
+use std::task::{self, Poll};
+use anyhow::{anyhow, bail, Context, Result};
+use candle_core::{DType, Device, Tensor, D};
 use candle_examples::token_output_stream::TokenOutputStream;
 use candle_nn::VarBuilder;
 use candle_transformers::generation::{LogitsProcessor, Sampling};
 use candle_transformers::models::llama as llama_model;
-use candle_transformers::models::llama::{Llama, LlamaConfig};
-use hf_hub::{api::sync::Api, Repo, RepoType};
+use candle_transformers::models::llama::{LlamaConfig, LlamaEosToks};
+use serde::Serialize;
 use tokenizers::Tokenizer;
-use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::mpsc::{self, error::TrySendError, Sender};
+use tokio_stream::Stream;
 
-use crate::types::GenerationParams;
+use crate::chat_template::ChatTemplate;
+use crate::config::{ModelConfig, ServerConfig};
+use crate::constraint::{Grammar, TokenConstraint, TokenVocab};
+use crate::kv_cache::{blocks_for, BlockBudget, KvCache, BLOCK_SIZE};
+use crate::model::{Llama, SequenceCache};
+use crate::model_source::ModelSource;
+use crate::prefix_cache::{common_prefix_len, CachedPrefix, ChatKey, PrefixCache};
+use crate::scheduler::{Overloaded, QueueSlot, QueueStats, Scheduler};
+use crate::stop::{StopConditions, StopScan};
+use crate::tools::{ToolCallParser, ToolScan};
+use crate::types::{
+    ChatMessage, EmbedInput, GenerationParams, OverflowPolicy, PromptInput, Priority, Role, Tool, ToolCall,
+};
 
 pub const EXAMPLE_MODEL: &str = "TinyLlama/TinyLlama-1.1B-Chat-v1.0";
+// where quantized versions of the example model are published, e.g. tinyllama-1.1b-chat-v1.0.Q4_K_M.gguf
+pub const EXAMPLE_GGUF_REPO: &str = "TheBloke/TinyLlama-1.1B-Chat-v1.0-GGUF";
+
+// max length of the summary that replaces old turns under the summarize overflow policy
+const SUMMARY_MAX_TOKENS: usize = 128;
+// room kept for the summary message around the summary itself (template markers, prefix text)
+const SUMMARY_OVERHEAD_TOKENS: usize = 32;
+// events a request's channel holds before its client counts as too slow and is dropped, decode
+// workers never wait for a client to catch up
+pub const EVENT_CHANNEL_CAPACITY: usize = 1024;
+const SUMMARY_INSTRUCTION: &str = "Summarize the following conversation in a few sentences. \
+Keep names, facts and decisions the user mentioned.";
 
 // in-memory Candle model plus tokenizer so we can reuse one instance for different prompts
 /* 
- * InferenceEngine contains 2 models:
- * Prefill Llama model runs synchronously and fully processes each client request, then the
- * DecodeRuntimeManager round robins through decoding tokens for concurrent client requests 
- * on a separate decode model.
+ * InferenceEngine tokenizes client requests and hands them to the Scheduler, whose pool of
+ * worker threads all share the loaded model:
+ * a worker either prefills (a chunk of) a new prompt to build its KV cache, or decodes a slice of
+ * tokens for a batch of active client requests in batched forward passes, depending on which queue
+ * has work.
+ * Finished sessions leave their KV cache in the PrefixCache so the next turn of the same chat
+ * only prefills the part of the prompt that is new.
+ * KV cache memory is accounted in blocks of one BlockBudget per model (see kv_cache.rs).
+ * Models configured with a draft model decode speculatively (see run_decode_batch).
 */
 pub struct InferenceEngine {
     tokenizer: Tokenizer,
-    prefill_llama: Mutex<Llama>,
-    decode_runtime_manager: Arc<DecodeRuntimeManager>,
-    device: Device,
-    dtype: DType,
+    chat_template: ChatTemplate,
+    scheduler: Arc<Scheduler>,
+    prefix_cache: Arc<PrefixCache>,
+    // KV cache memory budget of this model, in blocks
+    blocks: Arc<BlockBudget>,
     config: llama_model::Config,
+    // config of the draft model that proposes tokens for speculative decoding, if the model has one
+    draft_config: Option<llama_model::Config>,
+    // tokens the draft model proposes per decode pass
+    speculative_tokens: usize,
+    overflow_policy: OverflowPolicy,
+    // end of sequence tokens and turn markers that end every answer of this model
+    stop: StopConditions,
+    // token bytes that constrained requests match against their grammar
+    vocab: Arc<TokenVocab>,
 }
 
 #[derive(Debug)]
 // events emitted to the server during llm streaming
+// choice is the index of the answer the event belongs to, for requests with n or best_of
 pub enum EventToServer {
-    Token {token: String, index: usize},
-    Done {total_tokens: usize},
+    // logprobs holds one entry per sampled token in this piece of text, if the client asked for them
+    Token {token: String, index: usize, choice: usize, logprobs: Vec<TokenLogprob>},
+    // the model called one of the request's tools, the call's text is not sent as tokens
+    ToolCall {call: ToolCall, choice: usize},
+    // cumulative_logprob is the answer's summed token logprobs, set for best_of requests
+    Done {total_tokens: usize, finish_reason: FinishReason, truncation: Truncation, usage: Usage, choice: usize, cumulative_logprob: Option<f32>},
+    // prompt does not fit into the context window and the overflow policy could not make it fit
+    ContextOverflow {prompt_tokens: usize, max_tokens: usize, context_length: usize},
+    // generation stopped early because the client cancelled the request
+    Cancelled {total_tokens: usize, choice: usize},
+    // request is waiting for prefill, position 1 is next in line
+    Queued {position: usize},
     Error {message: String},
 }
 
+impl EventToServer {
+    // answer the event belongs to, None for events about the whole request
+    pub fn choice(&self) -> Option<usize> {
+        match self {
+            Self::Token { choice, .. }
+            | Self::ToolCall { choice, .. }
+            | Self::Done { choice, .. }
+            | Self::Cancelled { choice, .. } => Some(*choice),
+            _ => None,
+        }
+    }
+
+    fn set_choice(&mut self, index: usize) {
+        if let Self::Token { choice, .. }
+        | Self::ToolCall { choice, .. }
+        | Self::Done { choice, .. }
+        | Self::Cancelled { choice, .. } = self
+        {
+            *choice = index;
+        }
+    }
+}
+
 // Client request sent from the /generate handler into the inference engine worker thread to generate LLM responses
 pub struct ClientRequest {
-    pub prompt: String,
+    pub input: PromptInput,
     pub params: GenerationParams,
-    pub sender: UnboundedSender<EventToServer>,
+    pub sender: Sender<EventToServer>,
+    pub cancel_token: CancellationToken,
+    // chat this request belongs to, used to reuse the KV cache of the chat's previous turn
+    pub chat_key: Option<ChatKey>,
+    // grammar the answer has to follow (response_format or regex), compiled when the request is validated
+    pub grammar: Option<Arc<Grammar>>,
+    // tools the model can call, only used with chat messages
+    pub tools: Vec<Tool>,
+    // user the request is scheduled for, requests without one share a fair share bucket
+    pub user: Option<String>,
+    pub priority: Priority,
+    // admission slot, given back once the request's prefill starts
+    pub queue_slot: Option<QueueSlot>,
 }
 
-impl InferenceEngine {
-    pub fn new() -> Result<Self> {
-        Self::from_model(EXAMPLE_MODEL)
+// how the prompt was shortened to fit into the context window, reported in the DONE event
+#[derive(Debug, Clone, Copy)]
+pub struct Truncation {
+    pub policy: OverflowPolicy,
+    // prompt tokens that were cut (or replaced by a summary)
+    pub dropped_tokens: usize,
+}
+
+// log probability of a sampled token and of the most likely tokens at that step
+#[derive(Debug, Clone, Serialize)]
+pub struct TokenLogprob {
+    pub id: u32,
+    pub token: String,
+    pub logprob: f32,
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TopLogprob {
+    pub id: u32,
+    pub token: String,
+    pub logprob: f32,
+}
+
+// why a generation ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FinishReason {
+    // the model sampled one of its end of sequence tokens
+    Eos,
+    // a stop sequence or stop token was generated
+    Stop,
+    // max_tokens was reached
+    Length,
+    // the client cancelled the request
+    Cancelled,
+    // the answer called tools and waits for their results
+    ToolCalls,
+}
+
+impl FinishReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Eos => "eos",
+            Self::Stop => "stop",
+            Self::Length => "length",
+            Self::Cancelled => "cancelled",
+            Self::ToolCalls => "tool_calls",
+        }
     }
+}
 
-    pub fn from_model(model_id: &str) -> Result<Self> {
-        let api = Api::new()?;
-        let repo = api.repo(Repo::with_revision(
-            model_id.to_string(),
-            RepoType::Model,
-            "main".to_string(),
-        ));
+// number of tokens that went into the model and came out of it
+#[derive(Debug, Clone, Copy)]
+pub struct Usage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+}
 
-        let tokenizer_path = repo
-            .get("tokenizer.json")
-            .context("download tokenizer.json")?;
-        let config_path = repo.get("config.json").context("download config.json")?;
-        let weight_paths =
-            candle_examples::hub_load_safetensors(&repo, "model.safetensors.index.json")
-                .or_else(|_| repo.get("model.safetensors").map(|path| vec![path]))
-                .context("download model weights")?;
+// outcome of fitting a prompt into the context window
+enum PromptFit {
+    Fits { tokens: Vec<u32>, truncation: Truncation },
+    // reject policy, or the prompt is still too long after dropping everything that may be dropped
+    Overflow { prompt_tokens: usize },
+}
+
+// chat messages left after dropping the oldest turns
+struct DroppedTurns {
+    kept: Vec<ChatMessage>,
+    dropped: Vec<ChatMessage>,
+    // rendered and tokenized kept messages
+    tokens: Vec<u32>,
+}
+
+// shared flag the server sets to stop an in-flight generation, checked by the workers before every step
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl InferenceEngine {
+    pub fn from_model(model_config: &ModelConfig, server_config: &ServerConfig) -> Result<Self> {
+        let source = ModelSource::new(model_config, server_config.offline)?;
+        let tokenizer_path = source.get("tokenizer.json")?;
 
         let tokenizer = Tokenizer::from_file(&tokenizer_path)
             .map_err(|err| anyhow!("load tokenizer: {err}"))?;
+        let chat_template = select_chat_template(
+            model_config.chat_template.as_deref(),
+            source.get("tokenizer_config.json").ok().as_deref(),
+        )?;
 
         #[cfg(feature = "metal")]
         let device = match Device::new_metal(0) {
@@ -84,279 +247,1854 @@ impl InferenceEngine {
         };
         #[cfg(not(feature = "metal"))]
         let device = Device::Cpu;
-        let dtype = DType::F32;
 
-        let llama_config: LlamaConfig =
-            serde_json::from_slice(&std::fs::read(config_path)?).context("parse config.json")?;
-        let config = llama_config.into_config(false);
-        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&weight_paths, dtype, &device)? };
-        let prefill_llama = Llama::load(vb.clone(), &config)?;
-        let decode_llama = Llama::load(vb, &config)?;
+        let (llama, config) = load_llama(model_config, &source, server_config.offline, &device)?;
+
+        // the draft model proposes tokens the main model checks, so both have to use the same token ids
+        let draft = match &model_config.draft {
+            Some(draft_model) => {
+                let draft_source = ModelSource::new(draft_model, server_config.offline)?;
+                let (draft_llama, draft_config) =
+                    load_llama(draft_model, &draft_source, server_config.offline, &device)
+                        .with_context(|| format!("load draft model {}", draft_model.model_id))?;
+                if draft_config.vocab_size != config.vocab_size {
+                    bail!(
+                        "draft model {} has a vocabulary of {} tokens, {} has {}, they need the same tokenizer",
+                        draft_model.model_id,
+                        draft_config.vocab_size,
+                        model_config.model_id,
+                        config.vocab_size
+                    );
+                }
+                Some((draft_llama, draft_config))
+            }
+            None => None,
+        };
+        Ok(Self::new(tokenizer, chat_template, (llama, config), draft, device, server_config))
+    }
 
-        // start decode manager in background thread to allocate time slices to each client request's decode session
-        let decode_runtime_manager = Arc::new(DecodeRuntimeManager::new());
-        Arc::clone(&decode_runtime_manager).start(decode_llama, device.clone());
+    // engine around a loaded model (and draft model), starts the model's worker threads
+    fn new(
+        tokenizer: Tokenizer,
+        chat_template: ChatTemplate,
+        (llama, config): (Llama, llama_model::Config),
+        draft: Option<(Llama, llama_model::Config)>,
+        device: Device,
+        server_config: &ServerConfig,
+    ) -> Self {
+        let (draft_llama, draft_config) = draft.unzip();
 
-        Ok(Self {
-            tokenizer,
-            prefill_llama: Mutex::new(prefill_llama),
-            decode_runtime_manager,
+        // answers end on the tokenizer's </s> and the eos tokens from config.json
+        let mut eos_token_ids: Vec<u32> = tokenizer.token_to_id("</s>").into_iter().collect();
+        match &config.eos_token_id {
+            Some(LlamaEosToks::Single(id)) => eos_token_ids.push(*id),
+            Some(LlamaEosToks::Multiple(ids)) => eos_token_ids.extend(ids),
+            None => {}
+        }
+        eos_token_ids.sort_unstable();
+        eos_token_ids.dedup();
+        let stop = StopConditions::new(&tokenizer, eos_token_ids, chat_template.turn_markers());
+        let vocab = Arc::new(TokenVocab::new(&tokenizer));
+
+        let bytes_per_token = SequenceCache::bytes_per_token(&config);
+        let blocks = Arc::new(BlockBudget::new(server_config.kv_cache_memory_mb << 20, bytes_per_token));
+        let prefix_cache = Arc::new(PrefixCache::new(server_config.prefix_cache_capacity));
+
+        // start worker threads that take prefill and decode work for every client request session
+        let scheduler = Arc::new(Scheduler::new(
+            server_config,
+            Arc::clone(&blocks),
+            Arc::clone(&prefix_cache),
+            bytes_per_token,
             device,
-            dtype,
+        ));
+        scheduler.start(llama, draft_llama);
+
+        Self {
+            tokenizer,
+            chat_template,
+            scheduler,
+            prefix_cache,
+            blocks,
             config,
-        })
+            draft_config,
+            speculative_tokens: server_config.speculative_tokens,
+            overflow_policy: server_config.overflow_policy,
+            stop,
+            vocab,
+        }
     }
 
-    // run token generation and push tokens over the axum SENDER CHANNEL which is then streamed to the client
-    pub fn generate(
-        &self,
-        prompt: &str,
-        params: &GenerationParams,
-        sender: &UnboundedSender<EventToServer>,
-    ) -> Result<()> {
-        let tokens = self
-            .tokenizer
-            .encode(prompt, true)
-            .map_err(anyhow::Error::msg)?
-            .get_ids()
-            .to_vec();
+    // max number of prompt plus generated tokens
+    pub fn context_length(&self) -> usize {
+        self.config.max_position_embeddings
+    }
+
+    pub fn vocab_size(&self) -> usize {
+        self.config.vocab_size
+    }
+
+    pub fn chat_template(&self) -> ChatTemplate {
+        self.chat_template
+    }
+
+    // most answers (n / best_of) one request can ask for, see Scheduler::max_choices
+    pub fn max_choices(&self) -> usize {
+        self.scheduler.max_choices()
+    }
+
+    // take an admission slot for a new request, see Scheduler::admit
+    pub fn admit(&self) -> Result<QueueSlot, Overloaded> {
+        self.scheduler.admit()
+    }
+
+    // seconds turned away clients are told to wait
+    pub fn retry_after_secs(&self) -> u64 {
+        self.scheduler.retry_after_secs()
+    }
+
+    pub fn queue_stats(&self) -> QueueStats {
+        self.scheduler.stats()
+    }
+
+    // token ids of a text, the way the model's tokenizer splits it
+    pub fn tokenize(&self, text: &str, add_special_tokens: bool) -> Result<Vec<u32>> {
+        self.encode(text, add_special_tokens)
+    }
+
+    // text of token ids, ids outside the tokenizer's vocabulary are an error
+    pub fn detokenize(&self, tokens: &[u32], skip_special_tokens: bool) -> Result<String> {
+        let vocab_size = self.tokenizer.get_vocab_size(true);
+        if let Some(id) = tokens.iter().find(|&&id| id as usize >= vocab_size) {
+            bail!("token id {id} is not in the vocabulary of {vocab_size} tokens");
+        }
+        self.tokenizer
+            .decode(tokens, skip_special_tokens)
+            .map_err(anyhow::Error::msg)
+    }
+
+    // prompt tokens the model would see for this input, before any overflow policy is applied
+    pub fn count_prompt_tokens(&self, input: &PromptInput, tools: &[Tool]) -> Result<usize> {
+        Ok(self.encode(&self.render_prompt(input, tools), true)?.len())
+    }
+
+    // tokenize the inputs of an embedding request, returns a message for the client if one cannot be embedded
+    pub fn embedding_tokens(&self, inputs: Vec<EmbedInput>) -> Result<Vec<Vec<u32>>, String> {
+        inputs
+            .into_iter()
+            .enumerate()
+            .map(|(index, input)| {
+                let tokens = match input {
+                    EmbedInput::Text(text) => self.encode(&text, true).map_err(|err| err.to_string())?,
+                    EmbedInput::Tokens(tokens) => tokens,
+                };
+                if tokens.is_empty() {
+                    return Err(format!("input {index} is empty"));
+                }
+                if tokens.len() > self.config.max_position_embeddings {
+                    return Err(format!(
+                        "input {index} is {} tokens, the model's context window is {} tokens",
+                        tokens.len(),
+                        self.config.max_position_embeddings
+                    ));
+                }
+                if let Some(id) = tokens.iter().find(|&&id| id as usize >= self.config.vocab_size) {
+                    return Err(format!("input {index} holds token id {id}, which is not in the vocabulary"));
+                }
+                Ok(tokens)
+            })
+            .collect()
+    }
+
+    // embed tokenized inputs on the workers and wait for the vectors, optionally scaled to unit length
+    pub fn embed(&self, inputs: Vec<Vec<u32>>, normalize: bool, queue_slot: QueueSlot) -> Result<Vec<Vec<f32>>> {
+        let (sender, receiver) = std_mpsc::sync_channel(1);
+        self.scheduler.submit_embedding(EmbedJob {
+            inputs,
+            config: self.config.clone(),
+            sender,
+            queue_slot: Some(queue_slot),
+        });
+        let mut vectors = receiver
+            .recv()
+            .map_err(|_| anyhow!("embedding job was dropped without a result"))??;
+        if normalize {
+            for vector in &mut vectors {
+                let norm = vector.iter().map(|value| value * value).sum::<f32>().sqrt();
+                if norm > 0.0 {
+                    vector.iter_mut().for_each(|value| *value /= norm);
+                }
+            }
+        }
+        Ok(vectors)
+    }
+
+    // queue token generation, the workers push tokens over the axum SENDER CHANNEL which is then streamed to the client
+    pub fn generate(&self, client_request: &ClientRequest) -> Result<()> {
+        let policy = client_request
+            .params
+            .overflow_policy
+            .unwrap_or(self.overflow_policy);
+        let (tokens, truncation) = match self.fit_prompt(client_request, policy)? {
+            PromptFit::Fits { tokens, truncation } => (tokens, truncation),
+            PromptFit::Overflow { prompt_tokens } => {
+                let _ = client_request.sender.try_send(EventToServer::ContextOverflow {
+                    prompt_tokens,
+                    max_tokens: client_request.params.max_tokens,
+                    context_length: self.config.max_position_embeddings,
+                });
+                return Ok(());
+            }
+        };
+        // a prompt bigger than the whole KV cache memory can never be prefilled, and every answer of an
+        // n / best_of request starts with its own copy of it
+        if let Some(total_blocks) = self.blocks.total_blocks() {
+            let choices = client_request.params.choices();
+            if choices * blocks_for(tokens.len() + 1) > total_blocks {
+                let _ = client_request.sender.try_send(EventToServer::Error {
+                    message: format!(
+                        "prompt is {} tokens for {choices} answers, the KV cache memory of this model holds {} tokens",
+                        tokens.len(),
+                        total_blocks * BLOCK_SIZE
+                    ),
+                });
+                return Ok(());
+            }
+        }
         let stream = TokenOutputStream::new(self.tokenizer.clone());
-        let cache = llama_model::Cache::new(true, self.dtype, &self.config, &self.device)?;
-        let sampler = LogitsProcessor::from_sampling(
-            13,
-            Sampling::TopP {
-                p: 0.95,
-                temperature: 0.8,
-            },
-        );
-        let eos_token = stream.get_token("</s>");
+        let stop = self
+            .stop
+            .with_request(&client_request.params.stop, &client_request.params.stop_token_ids);
 
+        let cache = self.reusable_cache(client_request.chat_key.as_ref(), &tokens)?;
         let mut cur_client_request = ClientRequestSession::new(
             tokens,
             cache,
-            sampler,
             stream,
-            sender.clone(),
-            eos_token,
-            params.max_tokens,
+            client_request,
+            stop,
+            Arc::clone(&self.prefix_cache),
+            truncation,
         );
+        if let Some(grammar) = &client_request.grammar {
+            cur_client_request.constraint =
+                Some(TokenConstraint::new(Arc::clone(grammar), Arc::clone(&self.vocab)));
+        }
+        // the draft model only proposes tokens for answers that fit into its own context window
+        if let Some(draft_config) = &self.draft_config {
+            let max_len = cur_client_request.prompt_tokens + client_request.params.max_tokens;
+            if self.speculative_tokens > 0 && max_len <= draft_config.max_position_embeddings {
+                cur_client_request.draft = Some(Draft {
+                    cache: SequenceCache::new(draft_config),
+                    tokens: self.speculative_tokens,
+                });
+            }
+        }
 
-        // prompts are consumed and first processed serially using prefill model
-        let Ok(mut prefill_lock) = self.prefill_llama.lock() else {
-            let _ = sender.send(EventToServer::Error {
-                message: "failed to lock prefill model".to_string(),
-            });
-            return Ok(());
+        // prompt is prefilled by the next free worker, after that the session joins the decode batches
+        self.scheduler.submit(cur_client_request);
+        Ok(())
+    }
+}
+
+impl InferenceEngine {
+    // reuse the KV cache of this chat's previous turn if the new prompt starts with the same tokens,
+    // otherwise start from an empty cache
+    fn reusable_cache(&self, chat_key: Option<&ChatKey>, tokens: &[u32]) -> Result<KvCache> {
+        if let Some(prefix) = chat_key.and_then(|chat_key| self.prefix_cache.take(chat_key)) {
+            // at least one prompt token has to go through the model to get logits for the first new token
+            let reuse_len = common_prefix_len(&prefix.tokens, tokens).min(tokens.len().saturating_sub(1));
+            if reuse_len > 0 {
+                let mut cache = prefix.cache;
+                cache.truncate(reuse_len)?;
+                return Ok(cache);
+            }
+        }
+        Ok(KvCache::new(&self.config, Arc::clone(&self.blocks)))
+    }
+
+    // turn the client input into the prompt text the model sees
+    fn render_prompt(&self, input: &PromptInput, tools: &[Tool]) -> String {
+        match input {
+            PromptInput::Text(prompt) => prompt.clone(),
+            PromptInput::Messages(messages) => self.chat_template.render(messages, tools),
+        }
+    }
+
+    fn encode(&self, text: &str, add_special_tokens: bool) -> Result<Vec<u32>> {
+        Ok(self
+            .tokenizer
+            .encode(text, add_special_tokens)
+            .map_err(anyhow::Error::msg)?
+            .get_ids()
+            .to_vec())
+    }
+
+    // tokenize the prompt and apply the overflow policy if the prompt plus max_tokens is longer than the context window
+    fn fit_prompt(&self, client_request: &ClientRequest, policy: OverflowPolicy) -> Result<PromptFit> {
+        let tools = &client_request.tools;
+        let tokens = self.encode(&self.render_prompt(&client_request.input, tools), true)?;
+        let prompt_tokens = tokens.len();
+        // generated tokens have to fit into the context window too
+        let budget = self
+            .config
+            .max_position_embeddings
+            .saturating_sub(client_request.params.max_tokens);
+        if prompt_tokens <= budget {
+            let truncation = Truncation { policy, dropped_tokens: 0 };
+            return Ok(PromptFit::Fits { tokens, truncation });
+        }
+        if budget == 0 {
+            return Ok(PromptFit::Overflow { prompt_tokens });
+        }
+
+        match (policy, &client_request.input) {
+            (OverflowPolicy::Reject, _) => Ok(PromptFit::Overflow { prompt_tokens }),
+            // a raw prompt has no turns, so the oldest tokens are cut instead (the BOS token is kept)
+            (_, PromptInput::Text(_)) => {
+                let keep_bos = usize::from(tokens.first().copied() == self.tokenizer.token_to_id("<s>"));
+                let mut fitted = tokens[..keep_bos].to_vec();
+                fitted.extend_from_slice(&tokens[prompt_tokens - (budget - keep_bos)..]);
+                let truncation = Truncation {
+                    policy: OverflowPolicy::DropOldest,
+                    dropped_tokens: prompt_tokens - fitted.len(),
+                };
+                Ok(PromptFit::Fits { tokens: fitted, truncation })
+            }
+            (OverflowPolicy::DropOldest, PromptInput::Messages(messages)) => {
+                let Some(turns) = self.drop_oldest_turns(messages, tools, budget)? else {
+                    return Ok(PromptFit::Overflow { prompt_tokens });
+                };
+                let truncation = Truncation {
+                    policy,
+                    dropped_tokens: prompt_tokens - turns.tokens.len(),
+                };
+                Ok(PromptFit::Fits { tokens: turns.tokens, truncation })
+            }
+            (OverflowPolicy::Summarize, PromptInput::Messages(messages)) => {
+                // drop just enough old turns to leave room for their summary
+                let summary_budget = budget.saturating_sub(SUMMARY_MAX_TOKENS + SUMMARY_OVERHEAD_TOKENS);
+                let Some(turns) = self.drop_oldest_turns(messages, tools, summary_budget)? else {
+                    return Ok(PromptFit::Overflow { prompt_tokens });
+                };
+                let truncation = Truncation {
+                    policy,
+                    dropped_tokens: prompt_tokens - turns.tokens.len(),
+                };
+
+                let summary = self.summarize(&turns.dropped, &client_request.cancel_token)?;
+                let mut messages = turns.kept;
+                // summary goes right after the system prompt, in place of the turns it replaces
+                let position = messages
+                    .iter()
+                    .position(|message| message.role != Role::System)
+                    .unwrap_or(messages.len());
+                messages.insert(
+                    position,
+                    ChatMessage::new(Role::System, format!("Summary of the earlier conversation: {summary}")),
+                );
+                let tokens = self.encode(&self.chat_template.render(&messages, tools), true)?;
+                // an unusually long summary is left out rather than overflowing the context
+                let tokens = if tokens.len() <= budget { tokens } else { turns.tokens };
+                Ok(PromptFit::Fits { tokens, truncation })
+            }
+        }
+    }
+
+    // remove whole turns, oldest first, until the rendered messages fit into the budget
+    // system messages and the latest message are never dropped, returns None if that is still too long
+    fn drop_oldest_turns(
+        &self,
+        messages: &[ChatMessage],
+        tools: &[Tool],
+        budget: usize,
+    ) -> Result<Option<DroppedTurns>> {
+        let mut kept = messages.to_vec();
+        let mut dropped = Vec::new();
+        loop {
+            let tokens = self.encode(&self.chat_template.render(&kept, tools), true)?;
+            if tokens.len() <= budget {
+                return Ok(Some(DroppedTurns { kept, dropped, tokens }));
+            }
+            let Some(oldest) = kept
+                .iter()
+                .position(|message| message.role != Role::System)
+                .filter(|&index| index + 1 < kept.len())
+            else {
+                return Ok(None);
+            };
+            dropped.push(kept.remove(oldest));
+            // drop the answer to a dropped user message too (with the results of the tools it called),
+            // so the conversation still starts with a user turn
+            while oldest + 1 < kept.len() && matches!(kept[oldest].role, Role::Assistant | Role::Tool) {
+                dropped.push(kept.remove(oldest));
+            }
+        }
+    }
+
+    // have the model summarize the given turns, runs as a normal greedy session on the workers
+    fn summarize(&self, turns: &[ChatMessage], cancel_token: &CancellationToken) -> Result<String> {
+        let mut transcript: String = turns
+            .iter()
+            .map(|message| format!("{}: {}\n", message.role.as_str(), message.content))
+            .collect();
+        // the summary prompt has to fit into the context window as well, so only the latest part is kept
+        let transcript_budget = self
+            .config
+            .max_position_embeddings
+            .saturating_sub(SUMMARY_MAX_TOKENS + 2 * SUMMARY_OVERHEAD_TOKENS);
+        let transcript_tokens = self.encode(&transcript, false)?;
+        if transcript_tokens.len() > transcript_budget {
+            transcript = self
+                .tokenizer
+                .decode(&transcript_tokens[transcript_tokens.len() - transcript_budget..], true)
+                .map_err(anyhow::Error::msg)?;
+        }
+        let prompt = self.chat_template.render(
+            &[
+                ChatMessage::new(Role::System, SUMMARY_INSTRUCTION.to_string()),
+                ChatMessage::new(Role::User, transcript),
+            ],
+            &[],
+        );
+        let tokens = self.encode(&prompt, true)?;
+
+        let (sender, mut receiver) = mpsc::channel(EVENT_CHANNEL_CAPACITY);
+        let summary_request = ClientRequest {
+            input: PromptInput::Text(prompt),
+            params: GenerationParams {
+                max_tokens: SUMMARY_MAX_TOKENS,
+                greedy: true,
+                ..GenerationParams::default()
+            },
+            sender,
+            // cancelling the client request also stops its summary
+            cancel_token: cancel_token.clone(),
+            chat_key: None,
+            grammar: None,
+            tools: Vec::new(),
+            // the summary is part of the client request, which already holds its user's session slot
+            user: None,
+            priority: Priority::Interactive,
+            queue_slot: None,
         };
+        let stream = TokenOutputStream::new(self.tokenizer.clone());
+        let truncation = Truncation {
+            policy: OverflowPolicy::Reject,
+            dropped_tokens: 0,
+        };
+        self.scheduler.submit(ClientRequestSession::new(
+            tokens,
+            KvCache::new(&self.config, Arc::clone(&self.blocks)),
+            stream,
+            &summary_request,
+            self.stop.clone(),
+            Arc::clone(&self.prefix_cache),
+            truncation,
+        ));
+        // the session holds the only sender left, so the channel closes if it is dropped without finishing
+        drop(summary_request);
 
-        if !cur_client_request.run_prefill(&mut prefill_lock, &self.device)? {
-            return Ok(());
+        let mut summary = String::new();
+        while let Some(event) = receiver.blocking_recv() {
+            match event {
+                EventToServer::Token { token, .. } => summary.push_str(&token),
+                EventToServer::Queued { .. } | EventToServer::ToolCall { .. } => {}
+                // a cancelled client request is reported once its own session starts
+                EventToServer::Done { .. } | EventToServer::Cancelled { .. } => break,
+                EventToServer::Error { message } => bail!("summarize earlier conversation: {message}"),
+                EventToServer::ContextOverflow { .. } => bail!("summary prompt does not fit into the context window"),
+            }
         }
+        Ok(summary.trim().to_string())
+    }
+}
 
-        // prompt prefill done, release model lock
-        drop(prefill_lock);
+// load the weights of a model from safetensors, or from a GGUF file if one is configured
+fn load_llama(
+    model_config: &ModelConfig,
+    source: &ModelSource,
+    offline: bool,
+    device: &Device,
+) -> Result<(Llama, llama_model::Config)> {
+    match &model_config.gguf_file {
+        Some(gguf_file) => {
+            // a model directory holds the GGUF file itself, otherwise it comes from its own hub repo
+            let gguf_path = match model_config.gguf_repo() {
+                Some(gguf_repo) if model_config.model_path.is_none() => {
+                    ModelSource::from_hub(gguf_repo, offline)?.get(gguf_file)?
+                }
+                _ => source.get(gguf_file)?,
+            };
+            let mut reader = std::fs::File::open(&gguf_path)
+                .with_context(|| format!("open {}", gguf_path.display()))?;
+            Ok(Llama::load_gguf(&mut reader, device).context("load gguf model")?)
+        }
+        None => {
+            let config_path = source.get("config.json")?;
+            let weight_paths = source.safetensors()?;
+            let llama_config: LlamaConfig =
+                serde_json::from_slice(&std::fs::read(config_path)?).context("parse config.json")?;
+            let config = llama_config.into_config(false);
+            let vb = unsafe { VarBuilder::from_mmaped_safetensors(&weight_paths, DType::F32, device)? };
+            Ok((Llama::load(vb, &config)?, config))
+        }
+    }
+}
 
-        // add the request session to the queue of the DecodeRuntimeManager which will allocate processing time on decode model
-        self.decode_runtime_manager.add_request(cur_client_request);
-        Ok(())
+// chat template set in the server config wins, otherwise it is detected from tokenizer_config.json
+fn select_chat_template(
+    configured: Option<&str>,
+    tokenizer_config_path: Option<&std::path::Path>,
+) -> Result<ChatTemplate> {
+    if let Some(name) = configured {
+        return ChatTemplate::from_name(name)
+            .with_context(|| format!("unknown chat template `{name}`, expected zephyr, chatml or llama2"));
     }
+    let detected = tokenizer_config_path
+        .and_then(|path| std::fs::read(path).ok())
+        .and_then(|contents| serde_json::from_slice(&contents).ok())
+        .and_then(|tokenizer_config| ChatTemplate::detect(&tokenizer_config));
+    Ok(detected.unwrap_or_else(|| {
+        eprintln!("No known chat template in tokenizer_config.json, falling back to zephyr.");
+        ChatTemplate::Zephyr
+    }))
 }
 
-// Run on background thread to manage decoding tokens for concurrent client requests
-struct DecodeRuntimeManager {
-    queue: Mutex<VecDeque<ClientRequestSession>>,
-    cond: Condvar,
+// map the client's sampling params onto candle's sampling strategies
+fn sampling_from_params(params: &GenerationParams) -> Sampling {
+    if params.is_greedy() {
+        return Sampling::ArgMax;
+    }
+    let temperature = params.temperature;
+    match (params.top_k, params.top_p) {
+        (Some(k), Some(p)) if p < 1.0 => Sampling::TopKThenTopP { k, p, temperature },
+        (Some(k), _) => Sampling::TopK { k, temperature },
+        (None, Some(p)) if p < 1.0 => Sampling::TopP { p, temperature },
+        (None, _) => Sampling::All { temperature },
+    }
 }
 
-impl DecodeRuntimeManager {
-    fn new() -> Self {
-        Self {
-            queue: Mutex::new(VecDeque::new()),
-            cond: Condvar::new(),
+// min-p filtering: mask out tokens whose probability is below min_p * max probability
+// p_i >= min_p * p_max  <=>  logit_i >= logit_max + temperature * ln(min_p), so this can be done on the raw logits
+fn apply_min_p(logits: &Tensor, min_p: f64, temperature: f64) -> Result<Tensor> {
+    let mut values: Vec<f32> = logits.to_dtype(DType::F32)?.to_vec1()?;
+    let max_logit = values.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let threshold = max_logit + (temperature * min_p.ln()) as f32;
+    for value in values.iter_mut() {
+        if *value < threshold {
+            *value = f32::NEG_INFINITY;
         }
     }
+    Ok(Tensor::new(values, logits.device())?)
+}
 
-    fn start(self: Arc<Self>, mut llama: Llama, device: Device) {
-        // loop through queued client requests and decode one token at a time for each
-        thread::spawn(move || loop {
-            let mut session = {
-                let mut queue = self.queue.lock().unwrap();
-                while queue.is_empty() {
-                    queue = self.cond.wait(queue).unwrap();
-                }
-                queue.pop_front().unwrap()
-            };
+// channel for the events of one request, the handler that reads them also ranks best_of answers
+pub fn event_channel(params: &GenerationParams) -> (Sender<EventToServer>, EventReceiver) {
+    let (sender, receiver) = mpsc::channel(EVENT_CHANNEL_CAPACITY);
+    let best_of = params.ranks_choices().then(|| BestOf {
+        n: params.n,
+        choices: BTreeMap::new(),
+    });
+    (
+        sender,
+        EventReceiver {
+            receiver,
+            best_of,
+            ranked: VecDeque::new(),
+        },
+    )
+}
 
-            match session.run_decode_step(&mut llama, &device) {
-                // add to back of queue if not done LLM decoding yet
-                Ok(true) => {
-                    let mut queue = self.queue.lock().unwrap();
-                    queue.push_back(session);
-                }
-                Ok(false) => {
-                    // client request done processing
-                }
-                Err(err) => {
-                    let _ = session.sender.send(EventToServer::Error {
-                        message: err.to_string(),
-                    });
-                }
-            }
+// stream of a request's events, ends once every session of the request is dropped
+// for best_of requests the answers are held back until every one of them is done, then the n with
+// the highest cumulative logprob come out, renumbered 0..n in order of their logprob (cancelled
+// answers rank last)
+pub struct EventReceiver {
+    receiver: mpsc::Receiver<EventToServer>,
+    best_of: Option<BestOf>,
+    // events of the best answers, waiting to be handed out
+    ranked: VecDeque<EventToServer>,
+}
+
+struct BestOf {
+    n: usize,
+    // events and cumulative logprob of every answer
+    choices: BTreeMap<usize, (Vec<EventToServer>, Option<f32>)>,
+}
+
+impl BestOf {
+    // keep an answer's event, events about the whole request go out right away
+    fn hold(&mut self, event: EventToServer) -> Option<EventToServer> {
+        let Some(choice) = event.choice() else {
+            return Some(event);
+        };
+        let (events, logprob) = self.choices.entry(choice).or_default();
+        if let EventToServer::Done { cumulative_logprob, .. } = &event {
+            *logprob = *cumulative_logprob;
+        }
+        events.push(event);
+        None
+    }
+
+    fn rank(self) -> VecDeque<EventToServer> {
+        let mut ranked: Vec<_> = self.choices.into_values().collect();
+        ranked.sort_by(|(_, a), (_, b)| {
+            b.unwrap_or(f32::NEG_INFINITY).total_cmp(&a.unwrap_or(f32::NEG_INFINITY))
         });
+        ranked
+            .into_iter()
+            .take(self.n)
+            .enumerate()
+            .flat_map(|(choice, (events, _))| {
+                events.into_iter().map(move |mut event| {
+                    event.set_choice(choice);
+                    event
+                })
+            })
+            .collect()
+    }
+}
+
+impl Stream for EventReceiver {
+    type Item = EventToServer;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<EventToServer>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(event) = this.ranked.pop_front() {
+                return Poll::Ready(Some(event));
+            }
+            match task::ready!(this.receiver.poll_recv(cx)) {
+                Some(event) => match &mut this.best_of {
+                    Some(best_of) => {
+                        if let Some(event) = best_of.hold(event) {
+                            return Poll::Ready(Some(event));
+                        }
+                    }
+                    None => return Poll::Ready(Some(event)),
+                },
+                None => match this.best_of.take() {
+                    Some(best_of) => this.ranked = best_of.rank(),
+                    None => return Poll::Ready(None),
+                },
+            }
+        }
+    }
+}
+
+// decode one token for every session in the batch and retire the sessions that finished
+// with a draft model, the draft first proposes tokens for every session and the main model checks
+// them all in the same forward pass, so a session can get several tokens out of one pass
+pub fn run_decode_batch(
+    llama: &Llama,
+    draft_llama: Option<&Llama>,
+    sessions: &mut Vec<ClientRequestSession>,
+) -> DraftStats {
+    let mut stats = DraftStats::default();
+    // sessions can finish without a forward pass (e.g. max_tokens reached on prefill)
+    sessions.retain_mut(|session| !session.finish_if_done());
+    if sessions.is_empty() {
+        return stats;
+    }
+
+    let proposals = match draft_llama {
+        Some(draft_llama) => propose_drafts(draft_llama, sessions).unwrap_or_else(|_| {
+            // a failed draft pass only costs the speculation, the drafts are rebuilt on the next pass
+            for draft in sessions.iter_mut().filter_map(|session| session.draft.as_mut()) {
+                draft.cache.clear();
+            }
+            vec![Vec::new(); sessions.len()]
+        }),
+        None => vec![Vec::new(); sessions.len()],
+    };
+
+    // on decode only the last sampled token (and the draft's proposal) is fed in, past state is looked
+    // up from each KV cache
+    let inputs: Vec<Vec<u32>> = sessions
+        .iter()
+        .zip(&proposals)
+        .map(|(session, proposal)| {
+            let mut input = vec![*session.tokens.last().unwrap()];
+            input.extend(proposal);
+            input
+        })
+        .collect();
+    let input_refs: Vec<&[u32]> = inputs.iter().map(Vec::as_slice).collect();
+    let mut caches: Vec<&mut SequenceCache> =
+        sessions.iter_mut().map(|session| &mut session.cache.sequence).collect();
+
+    let logits = match llama.forward_all(&input_refs, &mut caches) {
+        Ok(logits) => logits,
+        Err(err) => {
+            // a failed forward pass leaves every cache in the batch in an unknown state
+            for session in sessions.drain(..) {
+                session.send_error(err.to_string());
+            }
+            return stats;
+        }
+    };
+
+    let mut row = 0;
+    let mut proposals = proposals.into_iter();
+    sessions.retain_mut(|session| {
+        let proposal = proposals.next().unwrap();
+        let result = logits
+            .narrow(0, row, proposal.len() + 1)
+            .map_err(anyhow::Error::from)
+            .and_then(|logits| session.sample_checked(&logits, &proposal));
+        row += proposal.len() + 1;
+        match result {
+            Ok((keep_decoding, accepted)) => {
+                stats.proposed += proposal.len() as u64;
+                stats.accepted += accepted as u64;
+                // keep in the batch if not done LLM decoding yet
+                keep_decoding
+            }
+            Err(err) => {
+                session.send_error(err.to_string());
+                false
+            }
+        }
+    });
+    stats
+}
+
+// let the draft model greedily propose the next tokens of every session that has a draft
+// the first draft pass also feeds the draft cache whatever it is missing of the answer so far
+// (the whole prompt after prefill, or the tokens after the last accepted proposal)
+fn propose_drafts(draft_llama: &Llama, sessions: &mut [ClientRequestSession]) -> Result<Vec<Vec<u32>>> {
+    let mut proposals = vec![Vec::new(); sessions.len()];
+    let mut inputs: Vec<Vec<u32>> = sessions
+        .iter()
+        .map(|session| match &session.draft {
+            Some(draft) => session.tokens[draft.cache.seq_len()..].to_vec(),
+            None => Vec::new(),
+        })
+        .collect();
+    let draft_lens: Vec<usize> = sessions.iter().map(ClientRequestSession::draft_len).collect();
+    for step in 0..draft_lens.iter().copied().max().unwrap_or(0) {
+        let proposing: Vec<usize> = (0..sessions.len()).filter(|&i| step < draft_lens[i]).collect();
+        let step_inputs: Vec<&[u32]> = proposing.iter().map(|&i| inputs[i].as_slice()).collect();
+        let mut caches: Vec<&mut SequenceCache> = sessions
+            .iter_mut()
+            .enumerate()
+            .filter(|(i, _)| step < draft_lens[*i])
+            .filter_map(|(_, session)| session.draft.as_mut().map(|draft| &mut draft.cache))
+            .collect();
+        let logits = draft_llama.forward(&step_inputs, &mut caches)?;
+        for (row, &i) in proposing.iter().enumerate() {
+            let next = logits.get(row)?.argmax(D::Minus1)?.to_scalar::<u32>()?;
+            proposals[i].push(next);
+            inputs[i] = vec![next];
+        }
     }
+    Ok(proposals)
+}
 
-    fn add_request(&self, session: ClientRequestSession) {
-        let mut queue = self.queue.lock().unwrap();
-        queue.push_back(session);
-        self.cond.notify_one();
+// inputs of an embedding request, run by a worker in place of a prefill
+// the KV caches of an embedding pass only live for that pass and are not taken from the block pool
+pub struct EmbedJob {
+    inputs: Vec<Vec<u32>>,
+    config: llama_model::Config,
+    sender: std_mpsc::SyncSender<Result<Vec<Vec<f32>>>>,
+    // admission slot, given back once a worker takes the job
+    queue_slot: Option<QueueSlot>,
+}
+
+impl EmbedJob {
+    pub fn leave_queue(&mut self) {
+        self.queue_slot = None;
     }
+
+    // mean pooled hidden states of every input, in batched passes of at most max_pass_tokens tokens
+    // (0 for no limit, a longer input gets a pass of its own)
+    pub fn run(self, llama: &Llama, max_pass_tokens: usize) {
+        let mut vectors = Vec::with_capacity(self.inputs.len());
+        let mut result = Ok(());
+        let mut start = 0;
+        while start < self.inputs.len() && result.is_ok() {
+            let mut end = start + 1;
+            let mut pass_tokens = self.inputs[start].len();
+            while end < self.inputs.len()
+                && (max_pass_tokens == 0 || pass_tokens + self.inputs[end].len() <= max_pass_tokens)
+            {
+                pass_tokens += self.inputs[end].len();
+                end += 1;
+            }
+            let batch: Vec<&[u32]> = self.inputs[start..end].iter().map(Vec::as_slice).collect();
+            result = llama
+                .embed(&batch, &self.config)
+                .and_then(|embeddings| embeddings.to_vec2::<f32>())
+                .map(|embeddings| vectors.extend(embeddings))
+                .map_err(anyhow::Error::from);
+            start = end;
+        }
+        let _ = self.sender.send(result.map(|()| vectors));
+    }
+}
+
+// draft model state of a session decoded speculatively
+#[derive(Clone)]
+struct Draft {
+    // draft model cache, behind the main cache after a prefill and caught up on the next draft pass
+    cache: SequenceCache,
+    // tokens proposed per decode pass
+    tokens: usize,
+}
+
+// draft tokens proposed and accepted by the main model in a decode pass, for the acceptance rate
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DraftStats {
+    pub proposed: u64,
+    pub accepted: u64,
 }
 
 // Client request session holds all state and functions needed to generate LLM responses and stream back to client
-struct ClientRequestSession {
+pub struct ClientRequestSession {
     tokens: Vec<u32>,
-    cache: llama_model::Cache,
+    cache: KvCache,
     sampler: LogitsProcessor,
     stream: TokenOutputStream,
-    sender: UnboundedSender<EventToServer>,
-    eos_token: Option<u32>,
-    ctx_index: usize,
+    sender: Sender<EventToServer>,
+    cancel_token: CancellationToken,
+    stop: StopConditions,
+    // decoded text held back because it could be the start of a stop sequence
+    pending_text: String,
+    // a stop sequence ended the answer, nothing decoded after it is sent
+    stop_sequence_hit: bool,
+    // logprobs of sampled tokens whose text has not been sent yet
+    pending_logprobs: Vec<TokenLogprob>,
+    // grammar state of a constrained answer
+    constraint: Option<TokenConstraint>,
+    // finds tool calls in the answer, for requests with tools
+    tool_parser: Option<ToolCallParser>,
+    // draft model state if the answer is decoded speculatively
+    draft: Option<Draft>,
+    user: Option<String>,
+    priority: Priority,
+    // last queue position sent to the client while waiting for prefill
+    queue_position: Option<usize>,
+    queue_slot: Option<QueueSlot>,
+    params: GenerationParams,
+    // where to leave the KV cache once the session finishes, if the request belongs to a chat
+    prefix_cache_slot: Option<(Arc<PrefixCache>, ChatKey)>,
+    truncation: Truncation,
+    prompt_tokens: usize,
     tokens_streamed: usize,
     tokens_generated: usize,
-    max_tokens: usize,
     done_streaming: bool,
+    // the whole prompt (and on recompute the answer so far) is in the cache
+    prefilled: bool,
+    // which of the request's answers (n / best_of) this session generates
+    choice: usize,
+    // sum of the logprobs of the sampled tokens, tracked for best_of
+    cumulative_logprob: f32,
+    // answers still to be forked off this session once its prompt is prefilled
+    unforked_choices: usize,
+    // sessions forked off after prefill, for the scheduler to pick up
+    forks: Vec<ClientRequestSession>,
 }
 
 impl ClientRequestSession {
     fn new(
         tokens: Vec<u32>,
-        cache: llama_model::Cache,
-        sampler: LogitsProcessor,
+        cache: KvCache,
         stream: TokenOutputStream,
-        sender: UnboundedSender<EventToServer>,
-        eos_token: Option<u32>,
-        max_tokens: usize,
+        client_request: &ClientRequest,
+        stop: StopConditions,
+        prefix_cache: Arc<PrefixCache>,
+        truncation: Truncation,
     ) -> Self {
+        let params = client_request.params.clone();
+        let prompt_tokens = tokens.len();
+        let unforked_choices = params.choices() - 1;
         Self {
             tokens,
             cache,
-            sampler,
+            sampler: LogitsProcessor::from_sampling(params.seed, sampling_from_params(&params)),
             stream,
-            sender,
-            eos_token,
-            ctx_index: 0,
+            sender: client_request.sender.clone(),
+            cancel_token: client_request.cancel_token.clone(),
+            stop,
+            pending_text: String::new(),
+            stop_sequence_hit: false,
+            pending_logprobs: Vec::new(),
+            constraint: None,
+            tool_parser: (!client_request.tools.is_empty()).then(ToolCallParser::default),
+            draft: None,
+            user: client_request.user.clone(),
+            priority: client_request.priority,
+            queue_position: None,
+            queue_slot: client_request.queue_slot.clone(),
+            params,
+            prefix_cache_slot: client_request
+                .chat_key
+                .clone()
+                .map(|chat_key| (prefix_cache, chat_key)),
+            truncation,
+            prompt_tokens,
             tokens_streamed: 0,
             tokens_generated: 0,
-            max_tokens,
             done_streaming: false,
+            prefilled: false,
+            choice: 0,
+            cumulative_logprob: 0.0,
+            unforked_choices,
+            forks: Vec::new(),
         }
     }
 
-    fn run_prefill(&mut self, llama: &mut Llama, device: &Device) -> Result<bool> {
-        self.generate_single_token(llama, device, true)
+    // another answer to the same prompt: shares the prefilled cache (as a copy) and the client's
+    // channel, samples with its own seed, and leaves the chat's prefix cache to the first answer
+    fn fork(&self, choice: usize) -> Self {
+        Self {
+            tokens: self.tokens.clone(),
+            cache: self.cache.fork(),
+            sampler: LogitsProcessor::from_sampling(
+                self.params.seed.wrapping_add(choice as u64),
+                sampling_from_params(&self.params),
+            ),
+            stream: TokenOutputStream::new(self.stream.tokenizer().clone()),
+            sender: self.sender.clone(),
+            cancel_token: self.cancel_token.clone(),
+            stop: self.stop.clone(),
+            pending_text: String::new(),
+            stop_sequence_hit: false,
+            pending_logprobs: Vec::new(),
+            constraint: self.constraint.clone(),
+            tool_parser: self.tool_parser.as_ref().map(|_| ToolCallParser::default()),
+            draft: self.draft.clone(),
+            user: self.user.clone(),
+            priority: self.priority,
+            queue_position: None,
+            queue_slot: None,
+            params: self.params.clone(),
+            prefix_cache_slot: None,
+            truncation: self.truncation,
+            prompt_tokens: self.prompt_tokens,
+            tokens_streamed: 0,
+            tokens_generated: 0,
+            done_streaming: false,
+            prefilled: true,
+            choice,
+            cumulative_logprob: 0.0,
+            unforked_choices: 0,
+            forks: Vec::new(),
+        }
     }
 
-    fn run_decode_step(&mut self, llama: &mut Llama, device: &Device) -> Result<bool> {
-        self.generate_single_token(llama, device, false)
+    // run the next chunk of the prompt (at most `chunk_size` tokens, 0 for all of it) through the model
+    // to build the KV cache of the input, and sample the first token after the last chunk
+    // tokens already in a reused cache are skipped, only the new suffix of the prompt is processed
+    // returns false once the session is done, is_prefilled tells whether more chunks follow
+    pub fn run_prefill(&mut self, llama: &Llama, chunk_size: usize) -> Result<bool> {
+        if self.finish_if_done() {
+            return Ok(false);
+        }
+        let start = self.cache.sequence.seq_len();
+        let end = start + self.prefill_chunk(chunk_size);
+        let logits = llama.forward(&[&self.tokens[start..end]], &mut [&mut self.cache.sequence])?;
+        // only the logits of the last chunk are sampled from
+        if end < self.tokens.len() {
+            return Ok(true);
+        }
+        self.prefilled = true;
+        let logits = logits.squeeze(0)?;
+        // the other answers of an n / best_of request are forked off the prefilled cache and
+        // sample their first token from the same logits (only once, not after a recompute)
+        for choice in 1..=std::mem::take(&mut self.unforked_choices) {
+            let mut fork = self.fork(choice);
+            if fork.sample_next_token(logits.clone())? {
+                self.forks.push(fork);
+            }
+        }
+        self.sample_next_token(logits)
     }
 
-    fn generate_single_token(&mut self, llama: &mut Llama, device: &Device, is_prefill: bool) -> Result<bool> {
-        if self.done_streaming {
-            return Ok(false);
+    // answers forked off on prefill that still decode
+    pub fn take_forks(&mut self) -> Vec<ClientRequestSession> {
+        std::mem::take(&mut self.forks)
+    }
+
+    // answers still to be forked off once the prompt is prefilled
+    pub fn pending_forks(&self) -> usize {
+        self.unforked_choices
+    }
+
+    // the session ends before its prompt is prefilled, the answers it did not fork never will be
+    pub fn drop_pending_forks(&mut self) -> usize {
+        std::mem::take(&mut self.unforked_choices)
+    }
+
+    // blocks the answers still to be forked off take, each one a copy of the prompt's cache plus
+    // its first token
+    pub fn fork_blocks_needed(&self) -> usize {
+        self.unforked_choices * blocks_for(self.prompt_tokens + 1)
+    }
+
+    pub fn is_prefilled(&self) -> bool {
+        self.prefilled
+    }
+
+    // tokens not yet in the cache, the prompt left to prefill before the first token is sampled
+    pub fn prefill_remaining(&self) -> usize {
+        self.tokens.len() - self.cache.sequence.seq_len()
+    }
+
+    // number of tokens the next prefill chunk runs through the model
+    pub fn prefill_chunk(&self, chunk_size: usize) -> usize {
+        let remaining = self.prefill_remaining();
+        if chunk_size == 0 {
+            remaining
+        } else {
+            remaining.min(chunk_size)
         }
+    }
 
-        if self.tokens_generated >= self.max_tokens {
-            self.stream_back_remaining()?;
-            return Ok(false);
+    // tokens in the cache after the next forward pass: the next chunk of the prompt while prefilling,
+    // otherwise decode_quantum more tokens (every token but the last sampled one is in the cache)
+    // a pass with a draft feeds the proposed tokens too, rejected ones are cut off again afterwards
+    pub fn next_cache_len(&self, prefill_chunk_size: usize, decode_quantum: usize) -> usize {
+        if self.prefilled {
+            let pass_len = 1 + self.draft_len();
+            (self.tokens.len() - 1 + decode_quantum * pass_len).min(self.prompt_tokens + self.params.max_tokens)
+        } else {
+            self.cache.sequence.seq_len() + self.prefill_chunk(prefill_chunk_size)
         }
+    }
+
+    // tokens the draft proposes on the next decode pass, never more than the answer has room for
+    fn draft_len(&self) -> usize {
+        self.draft.as_ref().map_or(0, |draft| {
+            draft.tokens.min(self.params.max_tokens.saturating_sub(self.tokens_generated + 1))
+        })
+    }
+
+    pub fn tokens_generated(&self) -> usize {
+        self.tokens_generated
+    }
+
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel_token.is_cancelled()
+    }
+
+    // blocks needed on top of the ones the session holds to grow the cache to `cache_len` tokens
+    pub fn blocks_needed(&self, cache_len: usize) -> usize {
+        blocks_for(cache_len).saturating_sub(self.cache.blocks.len())
+    }
+
+    pub fn held_blocks(&self) -> usize {
+        self.cache.blocks.len()
+    }
+
+    // take the blocks to grow the cache to `cache_len` tokens, false if the pool is short
+    pub fn reserve_blocks(&mut self, cache_len: usize) -> bool {
+        self.cache.blocks.grow_to(blocks_for(cache_len))
+    }
+
+    // preempted without swap: drop the cache, the next prefill runs over prompt and answer so far
+    pub fn drop_cache(&mut self) {
+        self.cache.clear();
+        if let Some(draft) = &mut self.draft {
+            draft.cache.clear();
+        }
+        self.prefilled = false;
+    }
+
+    // the draft cache is not swapped, it is rebuilt from the answer so far on the next decode pass
+    pub fn swap_out(&mut self, swap_allocator: &Arc<BlockBudget>) -> Result<bool> {
+        let swapped = self.cache.swap_out(swap_allocator)?;
+        if let (true, Some(draft)) = (swapped, &mut self.draft) {
+            draft.cache.clear();
+        }
+        Ok(swapped)
+    }
+
+    pub fn swap_in(&mut self, allocator: &Arc<BlockBudget>, device: &Device) -> Result<bool> {
+        Ok(self.cache.swap_in(allocator, device)?)
+    }
+
+    // the answer cannot grow because its KV cache alone fills the whole memory budget
+    pub fn finish_out_of_memory(&mut self) {
+        if let Err(err) = self.stream_back_remaining(FinishReason::Length) {
+            self.send_error(err.to_string());
+            self.done_streaming = true;
+        }
+    }
+
+    // the session left the prefill queue, its admission slot is free for the next request
+    pub fn leave_queue(&mut self) {
+        self.queue_slot = None;
+    }
 
-        let (context_size, offset) = if is_prefill {
-            // use full prompt on prefill to build KV cache of the input
-            (self.tokens.len(), 0)
+    // tell the client where its request waits in the prefill queue, only when the position changed
+    pub fn send_queued(&mut self, position: usize) {
+        if self.queue_position != Some(position) {
+            self.queue_position = Some(position);
+            self.send_event(EventToServer::Queued { position });
+        }
+    }
+
+    pub fn send_error(&self, message: String) {
+        self.send_event(EventToServer::Error { message });
+    }
+
+    // returns false if the client is gone; a client whose channel is full is too slow to keep up and
+    // is dropped, the whole request is cancelled
+    fn send_event(&self, event: EventToServer) -> bool {
+        match self.sender.try_send(event) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                self.cancel_token.cancel();
+                false
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+
+    // returns true (after sending DONE or CANCELLED) if the session should not be decoded any further
+    // the caller drops finished sessions, which frees their KV cache
+    fn finish_if_done(&mut self) -> bool {
+        if self.done_streaming {
+            return true;
+        }
+        let reason = if self.cancel_token.is_cancelled() {
+            FinishReason::Cancelled
+        } else if self.tokens_generated >= self.params.max_tokens {
+            FinishReason::Length
         } else {
-            // on decode, utilize KV cache so add single token context when generating tokens
-            (1, self.ctx_index)
+            return false;
         };
+        if let Err(err) = self.stream_back_remaining(reason) {
+            self.send_error(err.to_string());
+            self.done_streaming = true;
+        }
+        true
+    }
+
+    // sample from the main model's logits at every position of a decode pass: the draft's token is
+    // accepted as long as the sample matches it, the first sample that differs is the model's own
+    // next token, so the answer follows the main model's distribution exactly
+    // returns false once the session is done generating, and the number of accepted draft tokens
+    fn sample_checked(&mut self, logits: &Tensor, proposal: &[u32]) -> Result<(bool, usize)> {
+        let mut accepted = 0;
+        let mut keep_decoding = true;
+        for i in 0..=proposal.len() {
+            keep_decoding = self.sample_next_token(logits.get(i)?)?;
+            let matches = proposal.get(i) == self.tokens.last();
+            if matches {
+                accepted += 1;
+            }
+            if !keep_decoding || !matches {
+                break;
+            }
+        }
+        // the proposed tokens after the first rejected one were fed through both models, cut them off
+        let cached_len = self.tokens.len() - 1;
+        self.cache.sequence.truncate(cached_len)?;
+        if let Some(draft) = &mut self.draft {
+            let draft_len = draft.cache.seq_len().min(cached_len);
+            draft.cache.truncate(draft_len)?;
+        }
+        Ok((keep_decoding, accepted))
+    }
+
+    // sample the next token from the logits of the last forward pass and stream it back
+    // returns false once the session is done generating
+    fn sample_next_token(&mut self, mut logits: Tensor) -> Result<bool> {
+        // logprobs come from the model's own distribution, before penalties and sampling filters
+        let raw_logits = (self.params.logprobs.is_some() || self.params.ranks_choices()).then(|| logits.clone());
 
-        // ctx holds either the prompt for prefill
-        // or the last token for incremental decoding
-        // for cached decoding the model only needs the new token so it can look up past state from the KV cache
-        let ctx_start = self.tokens.len().saturating_sub(context_size);
-        let ctx = &self.tokens[ctx_start..];
-        let input = Tensor::new(ctx, device)?.unsqueeze(0)?;
-        let logits = llama.forward(&input, offset, &mut self.cache)?;
-        let mut logits = logits.squeeze(0)?;
-        
         // penalize tokens we just emitted so sampling avoids getting stuck in repeats (e.g. "hello hello hello")
-        if !self.tokens.is_empty() {
-            let start = self.tokens.len().saturating_sub(64);
+        if !self.tokens.is_empty() && self.params.repeat_penalty != 1.0 {
+            let start = self.tokens.len().saturating_sub(self.params.repeat_last_n);
             logits = candle_transformers::utils::apply_repeat_penalty(
                 &logits,
-                1.1,
+                self.params.repeat_penalty,
                 &self.tokens[start..],
             )?;
         }
 
+        // constrained answers only sample tokens that keep them inside the grammar, the answer can
+        // only end on an end of sequence or stop token once it is complete
+        if let Some(constraint) = &self.constraint {
+            let end_token_ids: Vec<u32> = self
+                .stop
+                .eos_token_ids
+                .iter()
+                .chain(&self.stop.stop_token_ids)
+                .copied()
+                .collect();
+            logits = constraint.mask(&logits, &end_token_ids)?;
+        }
+
+        if let Some(min_p) = self.params.min_p {
+            if !self.params.is_greedy() && min_p > 0.0 {
+                logits = apply_min_p(&logits, min_p, self.params.temperature)?;
+            }
+        }
+
         // sample the next token from the logits
-        self.ctx_index += ctx.len();
         let next = self.sampler.sample(&logits)?;
         self.tokens.push(next);
         self.tokens_generated += 1;
 
+        if let Some(raw_logits) = raw_logits {
+            let logprob = self.token_logprob(&raw_logits, next, self.params.logprobs.unwrap_or(0))?;
+            self.cumulative_logprob += logprob.logprob;
+            if self.params.logprobs.is_some() {
+                self.pending_logprobs.push(logprob);
+            }
+        }
+
+        // end of sequence and stop tokens end the answer without being streamed
+        if self.stop.eos_token_ids.contains(&next) {
+            self.stream_back_remaining(FinishReason::Eos)?;
+            return Ok(false);
+        }
+        if self.stop.stop_token_ids.contains(&next) {
+            self.stream_back_remaining(FinishReason::Stop)?;
+            return Ok(false);
+        }
+        if let Some(constraint) = &mut self.constraint {
+            constraint.advance(next)?;
+        }
+
         // push the generated/decoded token to the stream object and send to the server
 
         // stream object needed to convert token ids to strings
         // token ids dont map 1:1 to utf-8 strings, so we need the stream object to handle producing strings as tokens are generated (buffer tokens until a valid utf-8 string can be produced)
         // otherwise, we would need to wait until all tokens are generated to convert to string using tokenizer.decode
         if let Some(piece) = self.stream.next_token(next)? {
-            // if client has dropped, stop gen
-            if self
-                .sender
-                .send(EventToServer::Token {
-                    token: piece,
-                    index: self.tokens_streamed,
-                })
-                .is_err()
-            {
-                self.done_streaming = true;
-                return Ok(false);
+            match self.stop.scan(&mut self.pending_text, &piece) {
+                StopScan::Continue(text) => {
+                    // if client has dropped, stop gen
+                    if !self.send_text(text) {
+                        self.done_streaming = true;
+                        return Ok(false);
+                    }
+                }
+                StopScan::Stop(text) => {
+                    self.send_text(text);
+                    self.stop_sequence_hit = true;
+                    self.stream_back_remaining(FinishReason::Stop)?;
+                    return Ok(false);
+                }
             }
-            self.tokens_streamed += 1;
         }
 
-        if let Some(eos) = self.eos_token {
-            if next == eos {
-                self.stream_back_remaining()?;
-                return Ok(false);
-            }
+        // nothing can follow a finished constrained answer, end it as if the model sent end of sequence
+        if self.constraint.as_ref().is_some_and(TokenConstraint::is_finished) {
+            self.stream_back_remaining(FinishReason::Eos)?;
+            return Ok(false);
         }
 
-        if self.tokens_generated >= self.max_tokens {
-            self.stream_back_remaining()?;
+        if self.tokens_generated >= self.params.max_tokens {
+            self.stream_back_remaining(FinishReason::Length)?;
             return Ok(false);
         }
 
         Ok(true)
     }
 
-    // stream remaining bytes (if any) and send DONE event to client
-    fn stream_back_remaining(&mut self) -> Result<()> {
+    // send a piece of the answer to the client, returns false if the client is gone
+    // answers of requests with tools are scanned for tool calls, which are sent as their own events
+    fn send_text(&mut self, text: String) -> bool {
+        match &mut self.tool_parser {
+            Some(parser) => {
+                let scanned = parser.scan(&text);
+                self.send_scanned(scanned)
+            }
+            None => self.send_token(text),
+        }
+    }
+
+    fn send_scanned(&mut self, scanned: Vec<ToolScan>) -> bool {
+        let mut sent = true;
+        for piece in scanned {
+            sent &= match piece {
+                ToolScan::Text(text) => self.send_token(text),
+                ToolScan::Call(call) => self.send_event(EventToServer::ToolCall { call, choice: self.choice }),
+            };
+        }
+        sent
+    }
+
+    fn send_token(&mut self, text: String) -> bool {
+        if text.is_empty() {
+            return true;
+        }
+        let logprobs = std::mem::take(&mut self.pending_logprobs);
+        let sent = self.send_event(EventToServer::Token {
+            token: text,
+            index: self.tokens_streamed,
+            choice: self.choice,
+            logprobs,
+        });
+        self.tokens_streamed += 1;
+        sent
+    }
+
+    // log-softmax over the logits, with the sampled token and the top_n most likely tokens
+    fn token_logprob(&self, logits: &Tensor, id: u32, top_n: usize) -> Result<TokenLogprob> {
+        let logprobs: Vec<f32> =
+            candle_nn::ops::log_softmax(&logits.to_dtype(DType::F32)?, D::Minus1)?.to_vec1()?;
+        let descending = |a: &u32, b: &u32| logprobs[*b as usize].total_cmp(&logprobs[*a as usize]);
+        let mut top: Vec<u32> = (0..logprobs.len() as u32).collect();
+        if top_n < top.len() {
+            top.select_nth_unstable_by(top_n, descending);
+            top.truncate(top_n);
+        }
+        top.sort_unstable_by(descending);
+
+        let tokenizer = self.stream.tokenizer();
+        let token_text = |id: u32| tokenizer.decode(&[id], false).map_err(anyhow::Error::msg);
+        Ok(TokenLogprob {
+            id,
+            token: token_text(id)?,
+            logprob: logprobs[id as usize],
+            top_logprobs: top
+                .into_iter()
+                .map(|id| {
+                    Ok(TopLogprob {
+                        id,
+                        token: token_text(id)?,
+                        logprob: logprobs[id as usize],
+                    })
+                })
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    // stream remaining bytes and held back text (if any) and send DONE (or CANCELLED) event to client
+    fn stream_back_remaining(&mut self, mut reason: FinishReason) -> Result<()> {
         if self.done_streaming {
             return Ok(());
         }
 
-        if let Some(rest) = self.stream.decode_rest()? {
-            let _ = self.sender.send(EventToServer::Token {
-                token: rest,
-                index: self.tokens_streamed,
-            });
-            self.tokens_streamed += 1;
+        // a matched stop sequence already cut the answer, anything decoded after it is dropped
+        if !self.stop_sequence_hit {
+            let rest = self.stream.decode_rest()?.unwrap_or_default();
+            let text = match self.stop.scan(&mut self.pending_text, &rest) {
+                StopScan::Continue(text) => text + &std::mem::take(&mut self.pending_text),
+                StopScan::Stop(text) => {
+                    if reason != FinishReason::Cancelled {
+                        reason = FinishReason::Stop;
+                    }
+                    text
+                }
+            };
+            self.send_text(text);
+        }
+        // a tool call still open at the end counts if it is complete, an answer that called tools
+        // ends waiting for their results
+        if let Some(parser) = &mut self.tool_parser {
+            let scanned = parser.finish();
+            let called_tools = parser.calls() > 0;
+            self.send_scanned(scanned);
+            if called_tools && matches!(reason, FinishReason::Eos | FinishReason::Stop) {
+                reason = FinishReason::ToolCalls;
+            }
+        }
+
+        // keep this turn's KV cache so the next turn of the chat only prefills the new suffix
+        // (the last sampled token was never fed through the model, so it is not in the cache)
+        // a cache that is swapped out or was dropped on preemption is not worth keeping
+        if let Some((prefix_cache, chat_key)) = self.prefix_cache_slot.take() {
+            // draft tokens rejected in the last decode pass can still be at the end of the cache
+            let cached_len = self.cache.sequence.seq_len().min(self.tokens.len() - 1);
+            self.cache.truncate(cached_len)?;
+            if cached_len > 0 && !self.cache.is_swapped() {
+                prefix_cache.insert(
+                    chat_key,
+                    CachedPrefix {
+                        tokens: self.tokens[..cached_len].to_vec(),
+                        cache: self.cache.take(),
+                    },
+                );
+            }
         }
 
-        let _ = self.sender.send(EventToServer::Done {
-            total_tokens: self.tokens_streamed,
+        let total_tokens = self.tokens_streamed;
+        self.send_event(if reason == FinishReason::Cancelled {
+            EventToServer::Cancelled { total_tokens, choice: self.choice }
+        } else {
+            EventToServer::Done {
+                total_tokens,
+                finish_reason: reason,
+                truncation: self.truncation,
+                usage: Usage {
+                    prompt_tokens: self.prompt_tokens,
+                    completion_tokens: self.tokens_generated,
+                },
+                choice: self.choice,
+                cumulative_logprob: self.params.ranks_choices().then_some(self.cumulative_logprob),
+            }
         });
         self.done_streaming = true;
         Ok(())
     }
 }
+
+#[cfg(test)]
+impl ClientRequestSession {
+    // session with a prompt of `prompt_tokens` tokens waiting for prefill, without a model behind it,
+    // for scheduler tests; its cache takes its blocks from `blocks`, the receiver keeps the session's
+    // channel open
+    pub fn for_test(
+        user: Option<&str>,
+        priority: Priority,
+        prompt_tokens: usize,
+        blocks: &Arc<BlockBudget>,
+    ) -> (Self, mpsc::Receiver<EventToServer>) {
+        let config = llama_model::Config::config_7b_v2(false);
+        let (sender, receiver) = mpsc::channel(EVENT_CHANNEL_CAPACITY);
+        let client_request = ClientRequest {
+            input: PromptInput::Text(String::new()),
+            params: GenerationParams::default(),
+            sender,
+            cancel_token: CancellationToken::default(),
+            chat_key: None,
+            grammar: None,
+            tools: Vec::new(),
+            user: user.map(str::to_string),
+            priority,
+            queue_slot: None,
+        };
+        let tokenizer = Tokenizer::new(tokenizers::models::wordlevel::WordLevel::default());
+        let session = Self::new(
+            vec![0; prompt_tokens],
+            KvCache::new(&config, Arc::clone(blocks)),
+            TokenOutputStream::new(tokenizer),
+            &client_request,
+            StopConditions::default(),
+            Arc::new(PrefixCache::new(0)),
+            Truncation {
+                policy: OverflowPolicy::Reject,
+                dropped_tokens: 0,
+            },
+        );
+        (session, receiver)
+    }
+
+    // as if the prompt was prefilled and the first token sampled: the session holds the blocks for
+    // the prompt and decodes next
+    pub fn prefilled_for_test(&mut self) {
+        assert!(self.reserve_blocks(self.tokens.len()), "no blocks left for the prompt");
+        self.tokens.push(0);
+        self.tokens_generated = 1;
+        self.prefilled = true;
+    }
+
+    // as if the request asked for `forks` more answers, forked off after its prefill
+    pub fn forks_for_test(&mut self, forks: usize) {
+        self.unforked_choices = forks;
+    }
+}
+
+#[cfg(test)]
+impl EmbedJob {
+    // job with one short input and no model behind it, for scheduler tests; the receiver gets its result
+    pub fn for_test() -> (Self, std_mpsc::Receiver<Result<Vec<Vec<f32>>>>) {
+        let (sender, receiver) = std_mpsc::sync_channel(1);
+        let job = Self {
+            inputs: vec![vec![0; 4]],
+            config: llama_model::Config::config_7b_v2(false),
+            sender,
+            queue_slot: None,
+        };
+        (job, receiver)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_nn::VarMap;
+    use serde_json::{json, Value};
+    use tokio_stream::StreamExt;
+
+    use super::*;
+
+    // words of the test tokenizer besides its special tokens, enough for the summary prompt
+    const WORDS: &[&str] = &["Summary", "of", "the", "earlier", "conversation", ":", "hello"];
+    const SPECIAL_TOKENS: &[&str] = &["<unk>", "<s>", "</s>", "<|system|>", "<|user|>", "<|assistant|>"];
+
+    // word level tokenizer: the special tokens, WORDS and w0 to w49, split on whitespace
+    fn tokenizer() -> Tokenizer {
+        let tokens: Vec<String> = SPECIAL_TOKENS
+            .iter()
+            .chain(WORDS)
+            .map(|token| token.to_string())
+            .chain((0..50).map(|i| format!("w{i}")))
+            .collect();
+        let vocab: serde_json::Map<String, Value> =
+            tokens.iter().enumerate().map(|(id, token)| (token.clone(), json!(id))).collect();
+        let added_tokens: Vec<Value> = SPECIAL_TOKENS
+            .iter()
+            .enumerate()
+            .map(|(id, token)| {
+                json!({
+                    "id": id, "content": token, "single_word": false, "lstrip": false,
+                    "rstrip": false, "normalized": false, "special": true
+                })
+            })
+            .collect();
+        let tokenizer = json!({
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": added_tokens,
+            "normalizer": null,
+            "pre_tokenizer": { "type": "Whitespace" },
+            "post_processor": null,
+            "decoder": null,
+            "model": { "type": "WordLevel", "vocab": vocab, "unk_token": "<unk>" }
+        });
+        Tokenizer::from_bytes(serde_json::to_vec(&tokenizer).unwrap()).unwrap()
+    }
+
+    fn config(context_length: usize) -> llama_model::Config {
+        llama_model::Config {
+            hidden_size: 32,
+            intermediate_size: 64,
+            vocab_size: SPECIAL_TOKENS.len() + WORDS.len() + 50,
+            num_hidden_layers: 2,
+            num_attention_heads: 4,
+            num_key_value_heads: 2,
+            use_flash_attn: false,
+            rms_norm_eps: 1e-5,
+            rope_theta: 10000.,
+            bos_token_id: None,
+            eos_token_id: None,
+            rope_scaling: None,
+            max_position_embeddings: context_length,
+            tie_word_embeddings: false,
+        }
+    }
+
+    // tiny llama with fixed pseudo random weights picked by `seed`, so answers are the same every run
+    fn test_llama(config: &llama_model::Config, seed: u64) -> Llama {
+        let varmap = VarMap::new();
+        let llama = Llama::load(VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu), config).unwrap();
+        for (name, var) in varmap.data().lock().unwrap().iter() {
+            // norms keep their weights of one
+            if name.contains("norm") {
+                continue;
+            }
+            let salt = name.bytes().fold(seed, |hash, byte| hash.wrapping_mul(31).wrapping_add(byte as u64));
+            let step = (salt % 1000) as f64 * 1e-3 + 0.1;
+            let scale = 1. / (*var.dims().last().unwrap() as f64).sqrt();
+            let values: Vec<f32> =
+                (0..var.elem_count()).map(|i| ((i as f64 + 1.) * step * 12.9898).sin() as f32 * scale as f32).collect();
+            var.set(&Tensor::from_vec(values, var.dims(), &Device::Cpu).unwrap()).unwrap();
+        }
+        llama
+    }
+
+    fn engine(server_config: &ServerConfig, context_length: usize, llama: Llama, draft: Option<Llama>) -> InferenceEngine {
+        let config = config(context_length);
+        let draft = draft.map(|draft| (draft, config.clone()));
+        InferenceEngine::new(tokenizer(), ChatTemplate::Zephyr, (llama, config), draft, Device::Cpu, server_config)
+    }
+
+    fn request(input: PromptInput, params: GenerationParams) -> (ClientRequest, mpsc::Receiver<EventToServer>) {
+        let (sender, receiver) = mpsc::channel(EVENT_CHANNEL_CAPACITY);
+        let client_request = ClientRequest {
+            input,
+            params,
+            sender,
+            cancel_token: CancellationToken::default(),
+            chat_key: None,
+            grammar: None,
+            tools: Vec::new(),
+            user: None,
+            priority: Priority::Interactive,
+            queue_slot: None,
+        };
+        (client_request, receiver)
+    }
+
+    fn greedy(max_tokens: usize) -> GenerationParams {
+        GenerationParams {
+            max_tokens,
+            greedy: true,
+            ..GenerationParams::default()
+        }
+    }
+
+    // text and completion tokens of an answer, once it is done
+    fn answer(receiver: &mut mpsc::Receiver<EventToServer>) -> (String, usize) {
+        let mut text = String::new();
+        while let Some(event) = receiver.blocking_recv() {
+            match event {
+                EventToServer::Token { token, .. } => text.push_str(&token),
+                EventToServer::Done { usage, .. } => return (text, usage.completion_tokens),
+                EventToServer::Queued { .. } => {}
+                event => panic!("unexpected event {event:?}"),
+            }
+        }
+        panic!("answer ended without a done event")
+    }
+
+    #[test]
+    fn speculative_decoding_gives_the_same_answers() {
+        let server_config = ServerConfig {
+            num_workers: 1,
+            speculative_tokens: 3,
+            ..ServerConfig::default()
+        };
+        let llama = test_llama(&config(256), 3);
+        let plain = engine(&server_config, 256, llama.clone(), None);
+        // one draft model that mostly disagrees with the main model, and the main model itself as a
+        // draft, which only disagrees where the repeat penalty changes the main model's pick
+        let speculative = [
+            engine(&server_config, 256, llama.clone(), Some(test_llama(&config(256), 1))),
+            engine(&server_config, 256, llama.clone(), Some(llama)),
+        ];
+
+        // both prompts decode in the same batches, with drafts of different lengths near max_tokens
+        let prompts = ["w1 w2 w3", "w4 w5 w6 w7 w8 w9 w10"];
+        let answers = |engine: &InferenceEngine| -> Vec<(String, usize)> {
+            let mut receivers: Vec<_> = prompts
+                .iter()
+                .zip([24, 13])
+                .map(|(prompt, max_tokens)| {
+                    let (client_request, receiver) = request(PromptInput::Text(prompt.to_string()), greedy(max_tokens));
+                    engine.generate(&client_request).unwrap();
+                    receiver
+                })
+                .collect();
+            receivers.iter_mut().map(answer).collect()
+        };
+        let expected = answers(&plain);
+        for engine in &speculative {
+            assert_eq!(answers(engine), expected);
+            assert!(engine.queue_stats().draft_tokens_proposed > 0);
+        }
+        assert!(speculative[1].queue_stats().draft_tokens_accepted > 0);
+        assert_eq!(plain.queue_stats().draft_tokens_proposed, 0);
+    }
+
+    // `count` words starting at w`first`
+    fn words(first: usize, count: usize) -> String {
+        (first..first + count).map(|i| format!("w{}", i % 50)).collect::<Vec<_>>().join(" ")
+    }
+
+    fn fit(engine: &InferenceEngine, input: PromptInput, max_tokens: usize, policy: OverflowPolicy) -> PromptFit {
+        let (client_request, _receiver) = request(input, greedy(max_tokens));
+        engine.fit_prompt(&client_request, policy).unwrap()
+    }
+
+    fn roles(messages: &[ChatMessage]) -> Vec<Role> {
+        messages.iter().map(|message| message.role).collect()
+    }
+
+    #[test]
+    fn text_prompt_over_the_context_keeps_bos_and_newest_tokens() {
+        let engine = engine(&ServerConfig::default(), 32, test_llama(&config(32), 0), None);
+        let prompt = PromptInput::Text(format!("<s> {}", words(0, 30)));
+        // 31 prompt tokens, 24 fit next to 8 generated ones
+        let PromptFit::Fits { tokens, truncation } = fit(&engine, prompt.clone(), 8, OverflowPolicy::Summarize) else {
+            panic!("prompt should have been cut to fit");
+        };
+        assert_eq!(tokens.len(), 24);
+        assert_eq!(engine.detokenize(&tokens[..1], false).unwrap(), "<s>");
+        assert_eq!(engine.detokenize(&tokens[1..], true).unwrap(), words(7, 23));
+        // a raw prompt has no turns to summarize, its oldest tokens are dropped instead
+        assert_eq!(truncation.policy, OverflowPolicy::DropOldest);
+        assert_eq!(truncation.dropped_tokens, 7);
+
+        assert!(matches!(
+            fit(&engine, prompt.clone(), 8, OverflowPolicy::Reject),
+            PromptFit::Overflow { prompt_tokens: 31 }
+        ));
+        // nothing fits if max_tokens alone takes the whole context
+        assert!(matches!(fit(&engine, prompt, 32, OverflowPolicy::DropOldest), PromptFit::Overflow { .. }));
+    }
+
+    #[test]
+    fn oldest_turns_are_dropped_with_their_answers() {
+        let engine = engine(&ServerConfig::default(), 256, test_llama(&config(256), 0), None);
+        let messages = vec![
+            ChatMessage::new(Role::System, "hello".to_string()),
+            ChatMessage::new(Role::User, words(0, 10)),
+            ChatMessage::new(Role::Assistant, words(10, 10)),
+            ChatMessage::new(Role::User, words(20, 10)),
+            ChatMessage::new(Role::Assistant, words(30, 10)),
+            ChatMessage::new(Role::User, words(40, 10)),
+        ];
+        let budget = engine.encode(&engine.chat_template.render(&[messages[0].clone(), messages[5].clone()], &[]), true).unwrap().len();
+
+        let turns = engine.drop_oldest_turns(&messages, &[], budget).unwrap().unwrap();
+        assert_eq!(roles(&turns.kept), vec![Role::System, Role::User]);
+        assert_eq!(turns.kept[1].content, words(40, 10));
+        assert_eq!(roles(&turns.dropped), vec![Role::User, Role::Assistant, Role::User, Role::Assistant]);
+        assert_eq!(turns.tokens.len(), budget);
+        // one more turn fits with a bigger budget
+        let turns = engine.drop_oldest_turns(&messages, &[], budget + 26).unwrap().unwrap();
+        assert_eq!(roles(&turns.dropped), vec![Role::User, Role::Assistant]);
+        // the system prompt and the latest message are never dropped
+        assert!(engine.drop_oldest_turns(&messages, &[], budget - 1).unwrap().is_none());
+
+        let prompt_tokens = engine.count_prompt_tokens(&PromptInput::Messages(messages.clone()), &[]).unwrap();
+        let max_tokens = 256 - budget;
+        let PromptFit::Fits { tokens, truncation } =
+            fit(&engine, PromptInput::Messages(messages), max_tokens, OverflowPolicy::DropOldest)
+        else {
+            panic!("prompt should fit without the oldest turns");
+        };
+        assert_eq!(tokens.len(), budget);
+        assert_eq!(truncation.dropped_tokens, prompt_tokens - budget);
+    }
+
+    #[test]
+    fn summary_replaces_the_oldest_turns() {
+        let engine = engine(&ServerConfig::default(), 512, test_llama(&config(512), 0), None);
+        let mut messages = vec![ChatMessage::new(Role::System, "hello".to_string())];
+        for turn in 0..10 {
+            messages.push(ChatMessage::new(Role::User, words(turn * 7, 25)));
+            messages.push(ChatMessage::new(Role::Assistant, words(turn * 7 + 3, 25)));
+        }
+        messages.push(ChatMessage::new(Role::User, words(0, 5)));
+        let input = PromptInput::Messages(messages);
+        let prompt_tokens = engine.count_prompt_tokens(&input, &[]).unwrap();
+        assert!(prompt_tokens > 512 - 64);
+
+        let PromptFit::Fits { tokens, truncation } = fit(&engine, input, 64, OverflowPolicy::Summarize) else {
+            panic!("prompt should fit with a summary");
+        };
+        assert!(tokens.len() <= 512 - 64);
+        assert_eq!(truncation.policy, OverflowPolicy::Summarize);
+        assert!(truncation.dropped_tokens > 0);
+        // the summary comes right after the system prompt, the latest message stays last
+        let text = engine.detokenize(&tokens, true).unwrap();
+        assert!(text.starts_with("hello Summary of the earlier conversation :"), "{text}");
+        assert!(text.ends_with(&words(0, 5)), "{text}");
+    }
+
+    #[test]
+    fn summarize_overflows_if_the_latest_message_leaves_no_room_for_a_summary() {
+        let engine = engine(&ServerConfig::default(), 512, test_llama(&config(512), 0), None);
+        let messages = vec![
+            ChatMessage::new(Role::User, words(0, 100)),
+            ChatMessage::new(Role::Assistant, words(0, 100)),
+            // fits next to max_tokens on its own, but not next to a summary as well
+            ChatMessage::new(Role::User, words(0, 320)),
+        ];
+        let input = PromptInput::Messages(messages);
+        assert!(matches!(fit(&engine, input.clone(), 64, OverflowPolicy::DropOldest), PromptFit::Fits { .. }));
+        assert!(matches!(fit(&engine, input, 64, OverflowPolicy::Summarize), PromptFit::Overflow { .. }));
+    }
+
+    fn token(text: &str, choice: usize) -> EventToServer {
+        EventToServer::Token { token: text.to_string(), index: 0, choice, logprobs: Vec::new() }
+    }
+
+    fn done(choice: usize, cumulative_logprob: f32) -> EventToServer {
+        EventToServer::Done {
+            total_tokens: 1,
+            finish_reason: FinishReason::Eos,
+            truncation: Truncation { policy: OverflowPolicy::Reject, dropped_tokens: 0 },
+            usage: Usage { prompt_tokens: 1, completion_tokens: 1 },
+            choice,
+            cumulative_logprob: Some(cumulative_logprob),
+        }
+    }
+
+    #[tokio::test]
+    async fn best_of_sends_the_most_likely_answers_renumbered() {
+        let params = GenerationParams { n: 2, best_of: Some(3), ..GenerationParams::default() };
+        let (sender, receiver) = event_channel(&params);
+        for event in [
+            EventToServer::Queued { position: 1 },
+            token("a", 0),
+            token("b", 1),
+            token("c", 2),
+            done(0, -3.0),
+            EventToServer::Cancelled { total_tokens: 1, choice: 2 },
+            done(1, -1.0),
+        ] {
+            sender.send(event).await.unwrap();
+        }
+        drop(sender);
+
+        let events: Vec<_> = receiver.collect().await;
+        let summary: Vec<_> = events
+            .iter()
+            .map(|event| match event {
+                EventToServer::Queued { .. } => "queued".to_string(),
+                EventToServer::Token { token, choice, .. } => format!("{choice}:{token}"),
+                EventToServer::Done { choice, cumulative_logprob, .. } => format!("{choice}:{cumulative_logprob:?}"),
+                other => panic!("unexpected event {other:?}"),
+            })
+            .collect();
+        // the request's own events come right away, the cancelled answer ranks last and is left out
+        assert_eq!(summary, ["queued", "0:b", "0:Some(-1.0)", "1:a", "1:Some(-3.0)"]);
+    }
+
+    // run a first turn of alice's chat 1 and return the tokens its cached prefix holds
+    fn first_turn(engine: &InferenceEngine) -> Vec<u32> {
+        let (mut client_request, mut receiver) = request(PromptInput::Text(words(1, 6)), greedy(8));
+        client_request.chat_key = Some(chat_key("alice", 1));
+        engine.generate(&client_request).unwrap();
+        let (_, completion_tokens) = answer(&mut receiver);
+        let prefix = engine.prefix_cache.take(&chat_key("alice", 1)).expect("first turn was not cached");
+        let cached = prefix.tokens.clone();
+        // the prompt and every answer token but the last, which never went through the model
+        assert_eq!(cached.len(), engine.count_prompt_tokens(&client_request.input, &[]).unwrap() + completion_tokens - 1);
+        assert_eq!(prefix.cache.sequence.seq_len(), cached.len());
+        engine.prefix_cache.insert(chat_key("alice", 1), prefix);
+        cached
+    }
+
+    fn chat_key(username: &str, chat_id: i32) -> ChatKey {
+        ChatKey { username: username.to_string(), chat_id }
+    }
+
+    #[test]
+    fn next_turn_of_a_chat_reuses_its_cached_prefix() {
+        let server_config = ServerConfig { num_workers: 1, ..ServerConfig::default() };
+        let engine = engine(&server_config, 256, test_llama(&config(256), 3), None);
+        let cached = first_turn(&engine);
+        // the next turn resends the conversation so far and adds to it
+        let next_turn: Vec<u32> = cached.iter().copied().chain([20, 21]).collect();
+
+        // the same chat id of another user, or another chat of the same user, starts from scratch
+        for other in [chat_key("bob", 1), chat_key("alice", 2)] {
+            assert_eq!(engine.reusable_cache(Some(&other), &next_turn).unwrap().sequence.seq_len(), 0);
+        }
+        let cache = engine.reusable_cache(Some(&chat_key("alice", 1)), &next_turn).unwrap();
+        assert_eq!(cache.sequence.seq_len(), cached.len());
+        // the turn owns the cache now, it goes back once the turn is done
+        assert!(engine.prefix_cache.take(&chat_key("alice", 1)).is_none());
+    }
+
+    #[test]
+    fn diverging_prompt_only_reuses_the_tokens_it_shares() {
+        let server_config = ServerConfig { num_workers: 1, ..ServerConfig::default() };
+        let engine = engine(&server_config, 256, test_llama(&config(256), 3), None);
+        let mut edited = first_turn(&engine);
+        // an earlier message was edited: the cache is cut back to the tokens before the edit
+        edited[3] = 40;
+        let cache = engine.reusable_cache(Some(&chat_key("alice", 1)), &edited).unwrap();
+        assert_eq!(cache.sequence.seq_len(), 3);
+        drop(cache);
+
+        // nothing in common: the cached prefix is dropped and its blocks go back to the budget
+        let cached = first_turn(&engine);
+        let other: Vec<u32> = cached.iter().map(|&token| token + 1).collect();
+        let cache = engine.reusable_cache(Some(&chat_key("alice", 1)), &other).unwrap();
+        assert_eq!(cache.sequence.seq_len(), 0);
+        assert!(engine.prefix_cache.take(&chat_key("alice", 1)).is_none());
+        assert_eq!(engine.blocks.used_blocks(), 0);
+    }
+}
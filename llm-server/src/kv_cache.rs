@@ -0,0 +1,299 @@
+use std::sync::{Arc, Mutex};
+
+use candle_core::{Device, Result};
+use candle_transformers::models::llama::Config;
+use serde::Deserialize;
+
+use crate::model::SequenceCache;
+
+// tokens per KV cache block
+pub const BLOCK_SIZE: usize = 16;
+
+/*
+ * Admission control for KV cache memory: the memory budget of a model is counted in blocks of
+ * BLOCK_SIZE tokens shared by every session of the model. This is accounting only, the KV cache is
+ * not paged: a session's keys and values live in one contiguous tensor per layer (see
+ * SequenceCache), and the blocks count how much of the budget those tensors take. A session
+ * reserves the blocks for the tokens in its cache, one more block at a time as it decodes, and
+ * gives them all back when it ends or is cancelled (or hands them to the prefix cache). Blocks are
+ * reserved before a cache grows, so the budget bounds the real KV cache memory, give or take the
+ * partly filled last block of every session, the copies Tensor::cat makes while a cache grows, and
+ * embedding passes. When the budget runs dry the scheduler first evicts cached chat prefixes, then
+ * preempts sessions: their cache is either swapped out to host memory (a second, smaller budget)
+ * or dropped and recomputed by prefilling prompt and answer so far again once blocks are free.
+*/
+pub struct BlockBudget {
+    // None without a memory budget, blocks are then only counted
+    total_blocks: Option<usize>,
+    block_bytes: u64,
+    used: Mutex<usize>,
+}
+
+// what to do with a session whose blocks are needed for others
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PreemptionMode {
+    // drop the cache and prefill prompt plus answer so far again later
+    #[default]
+    Recompute,
+    // move the cache to host memory until blocks are free again, recompute if swap space is full
+    Swap,
+}
+
+impl PreemptionMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "recompute" => Some(Self::Recompute),
+            "swap" => Some(Self::Swap),
+            _ => None,
+        }
+    }
+}
+
+impl BlockBudget {
+    // budget_bytes 0 means no limit
+    pub fn new(budget_bytes: u64, bytes_per_token: u64) -> Self {
+        let block_bytes = BLOCK_SIZE as u64 * bytes_per_token;
+        let total_blocks = (budget_bytes > 0).then(|| (budget_bytes / block_bytes.max(1)) as usize);
+        Self {
+            total_blocks,
+            block_bytes,
+            used: Mutex::new(0),
+        }
+    }
+
+    // reserve all of the blocks or none of them
+    fn allocate(&self, count: usize) -> bool {
+        let mut used = self.used.lock().unwrap();
+        if self.total_blocks.is_some_and(|total| *used + count > total) {
+            return false;
+        }
+        *used += count;
+        true
+    }
+
+    fn release(&self, count: usize) {
+        *self.used.lock().unwrap() -= count;
+    }
+
+    pub fn total_blocks(&self) -> Option<usize> {
+        self.total_blocks
+    }
+
+    pub fn used_blocks(&self) -> usize {
+        *self.used.lock().unwrap()
+    }
+
+    // blocks left, None without a budget
+    pub fn free_blocks(&self) -> Option<usize> {
+        self.total_blocks.map(|total| total - self.used_blocks())
+    }
+
+    pub fn block_bytes(&self) -> u64 {
+        self.block_bytes
+    }
+}
+
+// number of blocks needed to hold `tokens` tokens
+pub fn blocks_for(tokens: usize) -> usize {
+    tokens.div_ceil(BLOCK_SIZE)
+}
+
+// blocks reserved by one cache, given back to the budget when dropped
+pub struct BlockReservation {
+    allocator: Arc<BlockBudget>,
+    blocks: usize,
+}
+
+impl BlockReservation {
+    pub fn new(allocator: Arc<BlockBudget>) -> Self {
+        Self {
+            allocator,
+            blocks: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks
+    }
+
+    // hold at least `count` blocks, returns false (holding what it held before) if the budget is short
+    pub fn grow_to(&mut self, count: usize) -> bool {
+        if count <= self.blocks {
+            return true;
+        }
+        if !self.allocator.allocate(count - self.blocks) {
+            return false;
+        }
+        self.blocks = count;
+        true
+    }
+
+    // give back every block after the first `count`
+    pub fn truncate(&mut self, count: usize) {
+        if count < self.blocks {
+            self.allocator.release(self.blocks - count);
+            self.blocks = count;
+        }
+    }
+
+    // move the blocks into a new reservation, leaving this one empty
+    pub fn take(&mut self) -> Self {
+        Self {
+            allocator: Arc::clone(&self.allocator),
+            blocks: std::mem::take(&mut self.blocks),
+        }
+    }
+}
+
+impl Drop for BlockReservation {
+    fn drop(&mut self) {
+        self.truncate(0);
+    }
+}
+
+// a sequence's KV tensors plus the blocks that account for them
+pub struct KvCache {
+    pub sequence: SequenceCache,
+    pub blocks: BlockReservation,
+    // tensors are in host memory and the blocks come from the swap pool
+    swapped: bool,
+}
+
+impl KvCache {
+    pub fn new(config: &Config, allocator: Arc<BlockBudget>) -> Self {
+        Self {
+            sequence: SequenceCache::new(config),
+            blocks: BlockReservation::new(allocator),
+            swapped: false,
+        }
+    }
+
+    // cut the cache down to its first `len` tokens and give back the blocks it no longer needs
+    pub fn truncate(&mut self, len: usize) -> Result<()> {
+        self.sequence.truncate(len)?;
+        self.blocks.truncate(blocks_for(len));
+        Ok(())
+    }
+
+    // drop every cached token, the blocks go back to the pool
+    pub fn clear(&mut self) {
+        self.sequence.clear();
+        self.blocks.truncate(0);
+    }
+
+    pub fn is_swapped(&self) -> bool {
+        self.swapped
+    }
+
+    // move the tensors to host memory and trade the blocks for blocks of the swap pool,
+    // returns false (leaving the cache as it is) if the swap pool is short
+    pub fn swap_out(&mut self, swap_allocator: &Arc<BlockBudget>) -> Result<bool> {
+        let swapped = self.move_to(swap_allocator, &Device::Cpu)?;
+        self.swapped |= swapped;
+        Ok(swapped)
+    }
+
+    // bring a swapped out cache back to the model's device
+    pub fn swap_in(&mut self, allocator: &Arc<BlockBudget>, device: &Device) -> Result<bool> {
+        let swapped_in = self.move_to(allocator, device)?;
+        self.swapped &= !swapped_in;
+        Ok(swapped_in)
+    }
+
+    fn move_to(&mut self, allocator: &Arc<BlockBudget>, device: &Device) -> Result<bool> {
+        let mut blocks = BlockReservation::new(Arc::clone(allocator));
+        if !blocks.grow_to(self.blocks.len()) {
+            return Ok(false);
+        }
+        self.sequence.move_to_device(device)?;
+        // the old blocks go back to their pool when the old reservation is dropped
+        self.blocks = blocks;
+        Ok(true)
+    }
+
+    // copy of the cached tokens for another answer to the same prompt, the copy holds no blocks
+    // until the scheduler reserves them for its first decode pass
+    pub fn fork(&self) -> Self {
+        Self {
+            sequence: self.sequence.clone(),
+            blocks: BlockReservation::new(Arc::clone(&self.blocks.allocator)),
+            swapped: self.swapped,
+        }
+    }
+
+    // move the cache out, leaving an empty one with the same block pool behind
+    pub fn take(&mut self) -> Self {
+        let sequence = self.sequence.clone();
+        self.sequence.clear();
+        Self {
+            sequence,
+            blocks: self.blocks.take(),
+            swapped: self.swapped,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn budget(total_blocks: u64) -> Arc<BlockBudget> {
+        Arc::new(BlockBudget::new(total_blocks * BLOCK_SIZE as u64, 1))
+    }
+
+    #[test]
+    fn reservations_take_all_blocks_or_none_and_give_them_back() {
+        let budget = budget(4);
+        let mut first = BlockReservation::new(Arc::clone(&budget));
+        let mut second = BlockReservation::new(Arc::clone(&budget));
+        assert!(first.grow_to(3));
+        assert!(!second.grow_to(2));
+        assert_eq!(second.len(), 0);
+        assert!(second.grow_to(1));
+        assert_eq!(budget.free_blocks(), Some(0));
+
+        first.truncate(1);
+        assert_eq!(budget.used_blocks(), 2);
+        let taken = first.take();
+        assert_eq!((first.len(), taken.len()), (0, 1));
+        drop(taken);
+        drop(second);
+        assert_eq!(budget.free_blocks(), Some(4));
+    }
+
+    #[test]
+    fn budget_without_limit_only_counts() {
+        let budget = budget(0);
+        let mut reservation = BlockReservation::new(Arc::clone(&budget));
+        assert!(reservation.grow_to(1000));
+        assert_eq!(budget.used_blocks(), 1000);
+        assert_eq!(budget.free_blocks(), None);
+    }
+
+    #[test]
+    fn swap_trades_blocks_between_budgets() {
+        let config = Config::config_7b_v2(false);
+        let device_budget = budget(4);
+        let mut cache = KvCache::new(&config, Arc::clone(&device_budget));
+        assert!(cache.blocks.grow_to(3));
+
+        // a swap pool that is short leaves the cache where it is
+        assert!(!cache.swap_out(&budget(2)).unwrap());
+        assert!(!cache.is_swapped());
+        assert_eq!(device_budget.used_blocks(), 3);
+
+        let swap_budget = budget(8);
+        assert!(cache.swap_out(&swap_budget).unwrap());
+        assert!(cache.is_swapped());
+        assert_eq!((device_budget.used_blocks(), swap_budget.used_blocks()), (0, 3));
+        // forks reserve their own blocks later
+        assert_eq!(cache.fork().blocks.len(), 0);
+
+        assert!(cache.swap_in(&device_budget, &Device::Cpu).unwrap());
+        assert!(!cache.is_swapped());
+        assert_eq!((device_budget.used_blocks(), swap_budget.used_blocks()), (3, 0));
+        cache.clear();
+        assert_eq!(device_budget.used_blocks(), 0);
+    }
+}
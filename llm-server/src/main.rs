@@ -1,18 +1,33 @@
+mod chat_template;
+mod config;
+mod constraint;
 mod engine;
+mod kv_cache;
+mod model;
+mod model_source;
+mod prefix_cache;
+mod registry;
 mod routes;
+mod scheduler;
 mod state;
+mod stop;
+mod stream_buffer;
+mod tools;
 mod types;
 mod chat_history;
 
-use std::sync::Arc;
-use std::sync::mpsc;
-use std::thread;
+use std::{sync::Arc, time::Duration};
 
 use axum::{routing::get, routing::post, Router};
-use routes::generate::generate;
+use registry::ModelRegistry;
+use routes::generate::{cancel_generate, generate, resume_generate};
+use routes::models::list_models;
+use routes::openai;
+use routes::queue::queue_status;
+use routes::tokens::{count_tokens, detokenize, tokenize};
 use state::AppState;
 use tokio::net::TcpListener;
-use chat_history::{add_model, fetch_chat, fetch_history, get_next_chat_id};
+use chat_history::{fetch_chat, fetch_history, get_next_chat_id};
 
 
 async fn test() -> &'static str {
@@ -21,42 +36,33 @@ async fn test() -> &'static str {
 
 #[tokio::main]
 async fn main() {
-    // shared inference engine instance used by the worker thread
-    let engine = Arc::new(
-        engine::InferenceEngine::new().expect("failed to initialize inference engine"),
-    );
-    let (client_request_sender, client_request_receiver) =
-        mpsc::channel::<engine::ClientRequest>();
-
-    // spawn an engine worker thread that receives client requests from /generate HTTP handler
-    // engine internally manages model synchronization (prefill, decode) between concurrent requests
-    let worker_engine = Arc::clone(&engine);
-    thread::spawn(move || {
-        while let Ok(client_request) = client_request_receiver.recv() {
-            if let Err(err) = worker_engine.generate(
-                &client_request.prompt,
-                &client_request.params,
-                &client_request.sender,
-            ) {
-                let _ = client_request.sender.send(engine::EventToServer::Error {
-                    message: err.to_string(),
-                });
-            }
-        }
-    });
+    let server_config = config::ServerConfig::load().expect("failed to load server config");
 
     // initialize sqlite database to store chat history
     let conn =
         chat_history::initialize_database().expect("failed to initialize sqlite database");
-    // test example model
-    add_model(&conn, "TinyLlama-1.1B-Chat-v1.0").unwrap();
 
-    let state = Arc::new(AppState::new(conn, client_request_sender));
+    // load every configured model, each with its own inference engine and engine thread
+    let models = ModelRegistry::load(&server_config, &conn).expect("failed to load models");
+
+    let resume_grace = Duration::from_secs(server_config.stream_resume_secs);
+    let state = Arc::new(AppState::new(conn, models, resume_grace));
 
     // axum router: test route and generation route
     let router = Router::new()
         .route("/", get(test))
         .route("/generate", post(generate))
+        .route("/generate/:request_id/cancel", post(cancel_generate))
+        .route("/generate/:request_id/stream", get(resume_generate))
+        .route("/models", get(list_models))
+        .route("/queue", get(queue_status))
+        .route("/tokenize", post(tokenize))
+        .route("/detokenize", post(detokenize))
+        .route("/count_tokens", post(count_tokens))
+        .route("/v1/chat/completions", post(openai::chat_completions))
+        .route("/v1/completions", post(openai::completions))
+        .route("/v1/embeddings", post(openai::embeddings))
+        .route("/v1/models", get(openai::list_models))
         .route("/fetch", get(fetch_chat))
         .route("/history", get(fetch_history))
         .route("/next_chat_id", get(get_next_chat_id))
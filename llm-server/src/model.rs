@@ -0,0 +1,663 @@
+use std::f32::consts::PI;
+use std::io::{Read, Seek};
+
+use candle_core::quantized::{gguf_file, QMatMul};
+use candle_core::{bail, DType, Device, Module, Result, Tensor};
+use candle_nn::{embedding, linear_no_bias, rms_norm, Embedding, Linear, RmsNorm, VarBuilder};
+use candle_transformers::models::llama::{Config, Llama3RopeConfig, Llama3RopeType, LlamaEosToks};
+
+/*
+ * Llama forward pass where every sequence in a batch owns its own KV cache.
+ * candle's llama::Llama only takes one Cache and one position per forward call, so it can only
+ * run a batch of 1 when every client is at a different point in its answer. Here the linear
+ * layers (where almost all the compute goes) run once over the tokens of every sequence in the
+ * batch, and only attention is done sequence by sequence against that sequence's cache.
+ * Weights are loaded with the same names as candle's llama so the same safetensors work, or from
+ * a GGUF file with the tensor names and metadata candle's quantized_llama reads, in which case the
+ * linear layers stay quantized (Q4_K, Q8_0, ...) and are multiplied through QMatMul.
+*/
+
+// KV cache for a single sequence: one (key, value) pair per transformer layer,
+// each with shape (1, num_kv_heads, seq_len, head_dim)
+#[derive(Debug, Clone)]
+pub struct SequenceCache {
+    kvs: Vec<Option<(Tensor, Tensor)>>,
+    seq_len: usize,
+}
+
+impl SequenceCache {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            kvs: vec![None; config.num_hidden_layers],
+            seq_len: 0,
+        }
+    }
+
+    // memory one token takes in the cache of every layer (f32 keys and values)
+    pub fn bytes_per_token(config: &Config) -> u64 {
+        let head_dim = config.hidden_size / config.num_attention_heads;
+        (2 * config.num_hidden_layers * config.num_key_value_heads * head_dim * DType::F32.size_in_bytes()) as u64
+    }
+
+    // number of tokens already in the cache, which is also the position of the next input token
+    pub fn seq_len(&self) -> usize {
+        self.seq_len
+    }
+
+    // drop everything after the first `len` tokens so the cache can be extended from there
+    pub fn truncate(&mut self, len: usize) -> Result<()> {
+        if len >= self.seq_len {
+            return Ok(());
+        }
+        for (k, v) in self.kvs.iter_mut().flatten() {
+            *k = k.narrow(2, 0, len)?;
+            *v = v.narrow(2, 0, len)?;
+        }
+        self.seq_len = len;
+        Ok(())
+    }
+
+    // drop every cached token
+    pub fn clear(&mut self) {
+        self.kvs.fill(None);
+        self.seq_len = 0;
+    }
+
+    // move the cached keys and values to another device (e.g. host memory while swapped out)
+    pub fn move_to_device(&mut self, device: &Device) -> Result<()> {
+        for (k, v) in self.kvs.iter_mut().flatten() {
+            *k = k.to_device(device)?;
+            *v = v.to_device(device)?;
+        }
+        Ok(())
+    }
+}
+
+// weight matrix of a linear layer, either full precision from safetensors or quantized from GGUF
+#[derive(Debug, Clone)]
+enum Proj {
+    Full(Linear),
+    Quantized(QMatMul),
+}
+
+impl Proj {
+    fn load(in_dim: usize, out_dim: usize, vb: VarBuilder) -> Result<Self> {
+        Ok(Self::Full(linear_no_bias(in_dim, out_dim, vb)?))
+    }
+
+    fn load_gguf<R: Read + Seek>(gguf: &mut Gguf<R>, name: &str) -> Result<Self> {
+        Ok(Self::Quantized(QMatMul::from_qtensor(gguf.tensor(name)?)?))
+    }
+}
+
+impl Module for Proj {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Full(linear) => linear.forward(x),
+            Self::Quantized(matmul) => matmul.forward(x),
+        }
+    }
+}
+
+// open GGUF file, tensors are read from it one by one while loading
+struct Gguf<'a, R> {
+    content: gguf_file::Content,
+    reader: &'a mut R,
+    device: Device,
+}
+
+impl<R: Read + Seek> Gguf<'_, R> {
+    fn tensor(&mut self, name: &str) -> Result<candle_core::quantized::QTensor> {
+        self.content.tensor(self.reader, name, &self.device)
+    }
+
+    // norms and embeddings are small, so they are dequantized and used as normal tensors
+    fn dequantized(&mut self, name: &str) -> Result<Tensor> {
+        self.tensor(name)?.dequantize(&self.device)
+    }
+
+    fn metadata_u32(&self, key: &str) -> Result<usize> {
+        match self.content.metadata.get(key) {
+            Some(value) => Ok(value.to_u32()? as usize),
+            None => bail!("cannot find {key} in gguf metadata"),
+        }
+    }
+
+    fn metadata_f32(&self, key: &str) -> Option<f32> {
+        self.content.metadata.get(key).and_then(|value| value.to_f32().ok())
+    }
+
+    // special token ids are optional, the tokenizer's own special tokens are used without them
+    fn metadata_token_id(&self, key: &str) -> Option<u32> {
+        self.content.metadata.get(key).and_then(|value| value.to_u32().ok())
+    }
+}
+
+// precomputed rotary embedding tables shared by every sequence
+#[derive(Debug, Clone)]
+struct RotaryEmbedding {
+    cos: Tensor,
+    sin: Tensor,
+    // GGUF conversion permutes the q/k weights for ggml's interleaved rope layout
+    interleaved: bool,
+}
+
+impl RotaryEmbedding {
+    fn new(config: &Config, interleaved: bool, dtype: DType, device: &Device) -> Result<Self> {
+        let head_dim = config.hidden_size / config.num_attention_heads;
+        let default_inv_freq: Vec<f32> = (0..head_dim)
+            .step_by(2)
+            .map(|i| 1f32 / config.rope_theta.powf(i as f32 / head_dim as f32))
+            .collect();
+        // llama 3 style frequency scaling, same as candle's llama::Cache
+        let inv_freq = match &config.rope_scaling {
+            None
+            | Some(Llama3RopeConfig {
+                rope_type: Llama3RopeType::Default,
+                ..
+            }) => default_inv_freq,
+            Some(scaling) => {
+                let original = scaling.original_max_position_embeddings as f32;
+                let low_freq_wavelen = original / scaling.low_freq_factor;
+                let high_freq_wavelen = original / scaling.high_freq_factor;
+                default_inv_freq
+                    .into_iter()
+                    .map(|freq| {
+                        let wavelen = 2. * PI / freq;
+                        if wavelen < high_freq_wavelen {
+                            freq
+                        } else if wavelen > low_freq_wavelen {
+                            freq / scaling.factor
+                        } else {
+                            let smooth = (original / wavelen - scaling.low_freq_factor)
+                                / (scaling.high_freq_factor - scaling.low_freq_factor);
+                            (1. - smooth) * freq / scaling.factor + smooth * freq
+                        }
+                    })
+                    .collect()
+            }
+        };
+        let inv_freq_len = inv_freq.len();
+        let inv_freq = Tensor::new(inv_freq, device)?.reshape((1, inv_freq_len))?;
+        let positions = Tensor::arange(0, config.max_position_embeddings as u32, device)?
+            .to_dtype(DType::F32)?
+            .reshape((config.max_position_embeddings, 1))?;
+        let freqs = positions.matmul(&inv_freq)?;
+        Ok(Self {
+            cos: freqs.cos()?.to_dtype(dtype)?,
+            sin: freqs.sin()?.to_dtype(dtype)?,
+            interleaved,
+        })
+    }
+
+    // x has shape (1, num_heads, seq_len, head_dim) and starts at position `offset`
+    fn apply(&self, x: &Tensor, offset: usize) -> Result<Tensor> {
+        let seq_len = x.dim(2)?;
+        let cos = self.cos.narrow(0, offset, seq_len)?;
+        let sin = self.sin.narrow(0, offset, seq_len)?;
+        if self.interleaved {
+            candle_nn::rotary_emb::rope_i(x, &cos, &sin)
+        } else {
+            candle_nn::rotary_emb::rope(x, &cos, &sin)
+        }
+    }
+}
+
+// additive causal mask for `seq_len` new tokens attending to `offset` cached tokens plus themselves
+fn causal_mask(seq_len: usize, offset: usize, device: &Device) -> Result<Tensor> {
+    let total = offset + seq_len;
+    let mask: Vec<f32> = (0..seq_len)
+        .flat_map(|i| {
+            (0..total).map(move |j| if j > offset + i { f32::NEG_INFINITY } else { 0.0 })
+        })
+        .collect();
+    Tensor::from_vec(mask, (seq_len, total), device)
+}
+
+#[derive(Debug, Clone)]
+struct Attention {
+    q_proj: Proj,
+    k_proj: Proj,
+    v_proj: Proj,
+    o_proj: Proj,
+    num_heads: usize,
+    num_kv_heads: usize,
+    head_dim: usize,
+}
+
+impl Attention {
+    fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        let head_dim = config.hidden_size / config.num_attention_heads;
+        let size_q = head_dim * config.num_attention_heads;
+        let size_kv = head_dim * config.num_key_value_heads;
+        Ok(Self {
+            q_proj: Proj::load(config.hidden_size, size_q, vb.pp("q_proj"))?,
+            k_proj: Proj::load(config.hidden_size, size_kv, vb.pp("k_proj"))?,
+            v_proj: Proj::load(config.hidden_size, size_kv, vb.pp("v_proj"))?,
+            o_proj: Proj::load(size_q, config.hidden_size, vb.pp("o_proj"))?,
+            num_heads: config.num_attention_heads,
+            num_kv_heads: config.num_key_value_heads,
+            head_dim,
+        })
+    }
+
+    fn load_gguf<R: Read + Seek>(gguf: &mut Gguf<R>, prefix: &str, config: &Config) -> Result<Self> {
+        Ok(Self {
+            q_proj: Proj::load_gguf(gguf, &format!("{prefix}.attn_q.weight"))?,
+            k_proj: Proj::load_gguf(gguf, &format!("{prefix}.attn_k.weight"))?,
+            v_proj: Proj::load_gguf(gguf, &format!("{prefix}.attn_v.weight"))?,
+            o_proj: Proj::load_gguf(gguf, &format!("{prefix}.attn_output.weight"))?,
+            num_heads: config.num_attention_heads,
+            num_kv_heads: config.num_key_value_heads,
+            head_dim: config.hidden_size / config.num_attention_heads,
+        })
+    }
+
+    // x holds the tokens of every sequence back to back with shape (total_tokens, hidden_size),
+    // spans[i] = (first row, number of rows) of sequence i in x
+    fn forward(
+        &self,
+        x: &Tensor,
+        spans: &[(usize, usize)],
+        layer_idx: usize,
+        caches: &mut [&mut SequenceCache],
+        rotary: &RotaryEmbedding,
+    ) -> Result<Tensor> {
+        let q = self.q_proj.forward(x)?;
+        let k = self.k_proj.forward(x)?;
+        let v = self.v_proj.forward(x)?;
+
+        let mut outputs = Vec::with_capacity(spans.len());
+        for (cache, &(start, seq_len)) in caches.iter_mut().zip(spans) {
+            let offset = cache.seq_len;
+            let q = q
+                .narrow(0, start, seq_len)?
+                .reshape((1, seq_len, self.num_heads, self.head_dim))?
+                .transpose(1, 2)?
+                .contiguous()?;
+            let k = k
+                .narrow(0, start, seq_len)?
+                .reshape((1, seq_len, self.num_kv_heads, self.head_dim))?
+                .transpose(1, 2)?
+                .contiguous()?;
+            let v = v
+                .narrow(0, start, seq_len)?
+                .reshape((1, seq_len, self.num_kv_heads, self.head_dim))?
+                .transpose(1, 2)?
+                .contiguous()?;
+
+            let q = rotary.apply(&q, offset)?;
+            let k = rotary.apply(&k, offset)?;
+
+            // append the new keys/values to this sequence's cache
+            let (k, v) = match &cache.kvs[layer_idx] {
+                Some((cache_k, cache_v)) => (
+                    Tensor::cat(&[cache_k, &k], 2)?.contiguous()?,
+                    Tensor::cat(&[cache_v, &v], 2)?.contiguous()?,
+                ),
+                None => (k, v),
+            };
+            cache.kvs[layer_idx] = Some((k.clone(), v.clone()));
+
+            let n_rep = self.num_heads / self.num_kv_heads;
+            let k = candle_transformers::utils::repeat_kv(k, n_rep)?;
+            let v = candle_transformers::utils::repeat_kv(v, n_rep)?;
+
+            let att = (q.matmul(&k.t()?)? / (self.head_dim as f64).sqrt())?;
+            let att = if seq_len > 1 {
+                att.broadcast_add(&causal_mask(seq_len, offset, x.device())?)?
+            } else {
+                att
+            };
+            let att = candle_nn::ops::softmax_last_dim(&att)?;
+            let y = att.matmul(&v.contiguous()?)?;
+            outputs.push(
+                y.transpose(1, 2)?
+                    .reshape((seq_len, self.num_heads * self.head_dim))?,
+            );
+        }
+
+        let y = Tensor::cat(&outputs, 0)?;
+        self.o_proj.forward(&y)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Mlp {
+    gate_proj: Proj,
+    up_proj: Proj,
+    down_proj: Proj,
+}
+
+impl Mlp {
+    fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        let (h_size, i_size) = (config.hidden_size, config.intermediate_size);
+        Ok(Self {
+            gate_proj: Proj::load(h_size, i_size, vb.pp("gate_proj"))?,
+            up_proj: Proj::load(h_size, i_size, vb.pp("up_proj"))?,
+            down_proj: Proj::load(i_size, h_size, vb.pp("down_proj"))?,
+        })
+    }
+
+    fn load_gguf<R: Read + Seek>(gguf: &mut Gguf<R>, prefix: &str) -> Result<Self> {
+        Ok(Self {
+            gate_proj: Proj::load_gguf(gguf, &format!("{prefix}.ffn_gate.weight"))?,
+            up_proj: Proj::load_gguf(gguf, &format!("{prefix}.ffn_up.weight"))?,
+            down_proj: Proj::load_gguf(gguf, &format!("{prefix}.ffn_down.weight"))?,
+        })
+    }
+
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let x = (candle_nn::ops::silu(&self.gate_proj.forward(x)?)? * self.up_proj.forward(x)?)?;
+        self.down_proj.forward(&x)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct DecoderLayer {
+    input_layernorm: RmsNorm,
+    self_attn: Attention,
+    post_attention_layernorm: RmsNorm,
+    mlp: Mlp,
+}
+
+impl DecoderLayer {
+    fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        Ok(Self {
+            input_layernorm: rms_norm(
+                config.hidden_size,
+                config.rms_norm_eps,
+                vb.pp("input_layernorm"),
+            )?,
+            self_attn: Attention::load(vb.pp("self_attn"), config)?,
+            post_attention_layernorm: rms_norm(
+                config.hidden_size,
+                config.rms_norm_eps,
+                vb.pp("post_attention_layernorm"),
+            )?,
+            mlp: Mlp::load(vb.pp("mlp"), config)?,
+        })
+    }
+
+    fn load_gguf<R: Read + Seek>(gguf: &mut Gguf<R>, layer_idx: usize, config: &Config) -> Result<Self> {
+        let prefix = format!("blk.{layer_idx}");
+        Ok(Self {
+            input_layernorm: RmsNorm::new(
+                gguf.dequantized(&format!("{prefix}.attn_norm.weight"))?,
+                config.rms_norm_eps,
+            ),
+            self_attn: Attention::load_gguf(gguf, &prefix, config)?,
+            post_attention_layernorm: RmsNorm::new(
+                gguf.dequantized(&format!("{prefix}.ffn_norm.weight"))?,
+                config.rms_norm_eps,
+            ),
+            mlp: Mlp::load_gguf(gguf, &prefix)?,
+        })
+    }
+
+    fn forward(
+        &self,
+        x: &Tensor,
+        spans: &[(usize, usize)],
+        layer_idx: usize,
+        caches: &mut [&mut SequenceCache],
+        rotary: &RotaryEmbedding,
+    ) -> Result<Tensor> {
+        let residual = x;
+        let x = self.input_layernorm.forward(x)?;
+        let x = (self.self_attn.forward(&x, spans, layer_idx, caches, rotary)? + residual)?;
+        let residual = &x;
+        let x = (self.mlp.forward(&self.post_attention_layernorm.forward(&x)?)? + residual)?;
+        Ok(x)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Llama {
+    embed_tokens: Embedding,
+    layers: Vec<DecoderLayer>,
+    norm: RmsNorm,
+    lm_head: Proj,
+    rotary: RotaryEmbedding,
+    device: Device,
+}
+
+impl Llama {
+    pub fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        let embed_tokens = embedding(
+            config.vocab_size,
+            config.hidden_size,
+            vb.pp("model.embed_tokens"),
+        )?;
+        let lm_head = if config.tie_word_embeddings {
+            Proj::Full(Linear::new(embed_tokens.embeddings().clone(), None))
+        } else {
+            Proj::load(config.hidden_size, config.vocab_size, vb.pp("lm_head"))?
+        };
+        let norm = rms_norm(config.hidden_size, config.rms_norm_eps, vb.pp("model.norm"))?;
+        let layers = (0..config.num_hidden_layers)
+            .map(|i| DecoderLayer::load(vb.pp(format!("model.layers.{i}")), config))
+            .collect::<Result<Vec<_>>>()?;
+        let rotary = RotaryEmbedding::new(config, false, vb.dtype(), vb.device())?;
+
+        Ok(Self {
+            embed_tokens,
+            layers,
+            norm,
+            lm_head,
+            rotary,
+            device: vb.device().clone(),
+        })
+    }
+
+    // load a quantized llama from a GGUF file, the model config comes from the file's metadata
+    pub fn load_gguf<R: Read + Seek>(reader: &mut R, device: &Device) -> Result<(Self, Config)> {
+        let content = gguf_file::Content::read(reader)?;
+        let mut gguf = Gguf {
+            content,
+            reader,
+            device: device.clone(),
+        };
+        let config = gguf_config(&gguf)?;
+
+        let embeddings = gguf.dequantized("token_embd.weight")?;
+        let embed_tokens = Embedding::new(embeddings, config.hidden_size);
+        // models without a separate output matrix reuse the (quantized) token embeddings
+        let lm_head = match gguf.tensor("output.weight") {
+            Ok(output) => Proj::Quantized(QMatMul::from_qtensor(output)?),
+            Err(_) => Proj::load_gguf(&mut gguf, "token_embd.weight")?,
+        };
+        let norm = RmsNorm::new(gguf.dequantized("output_norm.weight")?, config.rms_norm_eps);
+        let layers = (0..config.num_hidden_layers)
+            .map(|i| DecoderLayer::load_gguf(&mut gguf, i, &config))
+            .collect::<Result<Vec<_>>>()?;
+        let rotary = RotaryEmbedding::new(&config, true, DType::F32, device)?;
+
+        let llama = Self {
+            embed_tokens,
+            layers,
+            norm,
+            lm_head,
+            rotary,
+            device: device.clone(),
+        };
+        Ok((llama, config))
+    }
+
+    // run one forward pass over a batch of sequences.
+    // inputs[i] are the new tokens of sequence i (the whole prompt on prefill, a single token on decode),
+    // they are appended to caches[i]. returns the logits of the last input token of every sequence
+    // with shape (batch, vocab_size)
+    pub fn forward(&self, inputs: &[&[u32]], caches: &mut [&mut SequenceCache]) -> Result<Tensor> {
+        self.forward_rows(inputs, caches, false)
+    }
+
+    // same as forward, but returns the logits of every input token, the rows of sequence i follow
+    // the rows of sequence i - 1: shape (total input tokens, vocab_size)
+    // used to check the tokens a draft model proposed in one pass
+    pub fn forward_all(&self, inputs: &[&[u32]], caches: &mut [&mut SequenceCache]) -> Result<Tensor> {
+        self.forward_rows(inputs, caches, true)
+    }
+
+    // one embedding per input sequence: the mean of its final (normed) hidden states, shape (batch, hidden_size)
+    // every sequence runs against a fresh cache that is dropped afterwards
+    pub fn embed(&self, inputs: &[&[u32]], config: &Config) -> Result<Tensor> {
+        let mut caches: Vec<SequenceCache> = inputs.iter().map(|_| SequenceCache::new(config)).collect();
+        let mut cache_refs: Vec<&mut SequenceCache> = caches.iter_mut().collect();
+        let (x, spans) = self.hidden_states(inputs, &mut cache_refs)?;
+        let x = self.norm.forward(&x)?.to_dtype(DType::F32)?;
+        let pooled = spans
+            .iter()
+            .map(|&(start, seq_len)| x.narrow(0, start, seq_len)?.mean(0))
+            .collect::<Result<Vec<_>>>()?;
+        Tensor::stack(&pooled, 0)
+    }
+
+    fn forward_rows(&self, inputs: &[&[u32]], caches: &mut [&mut SequenceCache], all_rows: bool) -> Result<Tensor> {
+        let (x, spans) = self.hidden_states(inputs, caches)?;
+        if all_rows {
+            return self.lm_head.forward(&self.norm.forward(&x)?)?.to_dtype(DType::F32);
+        }
+        // only the last position of each sequence is needed to sample its next token
+        let last_rows: Vec<u32> = spans
+            .iter()
+            .map(|&(start, seq_len)| (start + seq_len - 1) as u32)
+            .collect();
+        let last_rows = Tensor::new(last_rows.as_slice(), &self.device)?;
+        let x = self.norm.forward(&x.index_select(&last_rows, 0)?)?;
+        self.lm_head.forward(&x)?.to_dtype(DType::F32)
+    }
+
+    // run the decoder layers over the new tokens of every sequence, returns the hidden states of all
+    // of them (before the final norm) and the (start row, length) span of each sequence
+    fn hidden_states(
+        &self,
+        inputs: &[&[u32]],
+        caches: &mut [&mut SequenceCache],
+    ) -> Result<(Tensor, Vec<(usize, usize)>)> {
+        if inputs.is_empty() || inputs.len() != caches.len() {
+            bail!(
+                "forward needs one cache per input sequence, got {} inputs and {} caches",
+                inputs.len(),
+                caches.len()
+            );
+        }
+
+        let mut spans = Vec::with_capacity(inputs.len());
+        let mut flat_tokens = Vec::new();
+        for input in inputs {
+            if input.is_empty() {
+                bail!("forward input sequence must not be empty");
+            }
+            spans.push((flat_tokens.len(), input.len()));
+            flat_tokens.extend_from_slice(input);
+        }
+
+        let input_ids = Tensor::new(flat_tokens.as_slice(), &self.device)?;
+        let mut x = self.embed_tokens.forward(&input_ids)?;
+        for (layer_idx, layer) in self.layers.iter().enumerate() {
+            x = layer.forward(&x, &spans, layer_idx, caches, &self.rotary)?;
+        }
+        for (cache, &(_, seq_len)) in caches.iter_mut().zip(&spans) {
+            cache.seq_len += seq_len;
+        }
+        Ok((x, spans))
+    }
+}
+
+// llama config from the metadata keys candle's quantized_llama reads
+fn gguf_config<R: Read + Seek>(gguf: &Gguf<R>) -> Result<Config> {
+    if gguf.metadata_u32("llama.expert_count").unwrap_or(0) > 1 {
+        bail!("mixture of experts GGUF models are not supported");
+    }
+    let hidden_size = gguf.metadata_u32("llama.embedding_length")?;
+    let num_attention_heads = gguf.metadata_u32("llama.attention.head_count")?;
+    let vocab_size = match gguf.content.tensor_infos.get("token_embd.weight") {
+        Some(info) => info.shape.dims()[0],
+        None => bail!("cannot find token_embd.weight in gguf file"),
+    };
+    Ok(Config {
+        hidden_size,
+        intermediate_size: gguf.metadata_u32("llama.feed_forward_length")?,
+        vocab_size,
+        num_hidden_layers: gguf.metadata_u32("llama.block_count")?,
+        num_attention_heads,
+        num_key_value_heads: gguf.metadata_u32("llama.attention.head_count_kv")?,
+        use_flash_attn: false,
+        rms_norm_eps: gguf
+            .metadata_f32("llama.attention.layer_norm_rms_epsilon")
+            .unwrap_or(1e-5) as f64,
+        rope_theta: gguf.metadata_f32("llama.rope.freq_base").unwrap_or(10000.),
+        bos_token_id: gguf.metadata_token_id("tokenizer.ggml.bos_token_id"),
+        eos_token_id: gguf
+            .metadata_token_id("tokenizer.ggml.eos_token_id")
+            .map(LlamaEosToks::Single),
+        rope_scaling: None,
+        max_position_embeddings: gguf.metadata_u32("llama.context_length")?,
+        tie_word_embeddings: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::Cursor;
+
+    use candle_core::quantized::GgmlDType;
+
+    use super::*;
+
+    // metadata of a small llama GGUF file, without any tensor data
+    fn content(metadata: &[(&str, u32)]) -> gguf_file::Content {
+        let token_embd = gguf_file::TensorInfo {
+            ggml_dtype: GgmlDType::F32,
+            shape: (32000, 64).into(),
+            offset: 0,
+        };
+        gguf_file::Content {
+            magic: gguf_file::VersionedMagic::GgufV3,
+            metadata: metadata
+                .iter()
+                .map(|&(key, value)| (key.to_string(), gguf_file::Value::U32(value)))
+                .collect(),
+            tensor_infos: HashMap::from([("token_embd.weight".to_string(), token_embd)]),
+            tensor_data_offset: 0,
+        }
+    }
+
+    fn config(metadata: &[(&str, u32)]) -> Config {
+        let mut reader = Cursor::new(Vec::new());
+        let gguf = Gguf {
+            content: content(metadata),
+            reader: &mut reader,
+            device: Device::Cpu,
+        };
+        gguf_config(&gguf).unwrap()
+    }
+
+    const LLAMA: &[(&str, u32)] = &[
+        ("llama.embedding_length", 64),
+        ("llama.attention.head_count", 4),
+        ("llama.attention.head_count_kv", 2),
+        ("llama.feed_forward_length", 128),
+        ("llama.block_count", 2),
+        ("llama.context_length", 2048),
+    ];
+
+    #[test]
+    fn gguf_config_reads_the_special_token_ids() {
+        let metadata: Vec<_> = LLAMA
+            .iter()
+            .copied()
+            .chain([("tokenizer.ggml.bos_token_id", 1), ("tokenizer.ggml.eos_token_id", 2)])
+            .collect();
+        let with_ids = config(&metadata);
+        assert_eq!(with_ids.vocab_size, 32000);
+        assert_eq!(with_ids.bos_token_id, Some(1));
+        assert!(matches!(with_ids.eos_token_id, Some(LlamaEosToks::Single(2))));
+
+        let without_ids = config(LLAMA);
+        assert_eq!(without_ids.bos_token_id, None);
+        assert!(without_ids.eos_token_id.is_none());
+    }
+}
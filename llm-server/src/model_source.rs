@@ -0,0 +1,92 @@
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+use hf_hub::{
+    api::sync::{Api, ApiRepo},
+    Cache, CacheRepo, Repo, RepoType,
+};
+use serde_json::Value;
+
+use crate::config::ModelConfig;
+
+/*
+ * Where the files of a model (config.json, tokenizer.json, weights, ...) are read from:
+ * - a local directory given with --model-path, nothing is downloaded
+ * - the Hugging Face Hub, files that are already in the local HF cache are not downloaded again
+ * - only the local HF cache (--offline or HF_HUB_OFFLINE=1), for machines without network access
+*/
+pub enum ModelSource {
+    Dir(PathBuf),
+    Hub { repo: ApiRepo, model_id: String },
+    Cache { repo: CacheRepo, model_id: String },
+}
+
+impl ModelSource {
+    // the configured model directory, or the hub repo of the model
+    pub fn new(model_config: &ModelConfig, offline: bool) -> Result<Self> {
+        match &model_config.model_path {
+            Some(dir) if !dir.is_dir() => bail!("model path {} is not a directory", dir.display()),
+            Some(dir) => Ok(Self::Dir(dir.clone())),
+            None => Self::from_hub(&model_config.model_id, offline),
+        }
+    }
+
+    pub fn from_hub(model_id: &str, offline: bool) -> Result<Self> {
+        let repo = Repo::with_revision(model_id.to_string(), RepoType::Model, "main".to_string());
+        let model_id = model_id.to_string();
+        if offline || hub_offline_env() {
+            return Ok(Self::Cache {
+                repo: Cache::from_env().repo(repo),
+                model_id,
+            });
+        }
+        Ok(Self::Hub {
+            repo: Api::new()?.repo(repo),
+            model_id,
+        })
+    }
+
+    // path of one file of the model, with an error that says where it was looked for
+    pub fn get(&self, file: &str) -> Result<PathBuf> {
+        match self {
+            Self::Dir(dir) => {
+                let path = dir.join(file);
+                if !path.is_file() {
+                    bail!("{file} not found in model directory {}", dir.display());
+                }
+                Ok(path)
+            }
+            Self::Hub { repo, model_id } => repo.get(file).with_context(|| {
+                format!("download {file} from {model_id} (use --offline to only read the HF cache)")
+            }),
+            Self::Cache { repo, model_id } => repo.get(file).with_context(|| {
+                format!(
+                    "{file} of {model_id} is not in the HF cache, download it once with network access or use --model-path"
+                )
+            }),
+        }
+    }
+
+    // weight files: a single model.safetensors, or the shards listed in model.safetensors.index.json
+    pub fn safetensors(&self) -> Result<Vec<PathBuf>> {
+        let Ok(index_path) = self.get("model.safetensors.index.json") else {
+            return Ok(vec![self.get("model.safetensors")?]);
+        };
+        let index: Value = serde_json::from_slice(&std::fs::read(&index_path)?)
+            .context("parse model.safetensors.index.json")?;
+        let weight_map = index
+            .get("weight_map")
+            .and_then(Value::as_object)
+            .context("model.safetensors.index.json has no weight_map")?;
+        let mut files: Vec<&str> = weight_map.values().filter_map(Value::as_str).collect();
+        files.sort_unstable();
+        files.dedup();
+        files.into_iter().map(|file| self.get(file)).collect()
+    }
+}
+
+// same switch the python huggingface_hub library uses to stay off the network
+fn hub_offline_env() -> bool {
+    std::env::var("HF_HUB_OFFLINE")
+        .is_ok_and(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+}
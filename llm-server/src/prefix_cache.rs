@@ -0,0 +1,72 @@
+use std::{collections::VecDeque, sync::Mutex};
+
+use crate::kv_cache::KvCache;
+
+/*
+ * PrefixCache keeps the KV cache of each chat's last turn so the next turn does not have to
+ * prefill the whole conversation again. The client resends the full chat history on every turn,
+ * which starts with exactly the tokens that are already in the previous turn's cache, so only
+ * the new suffix (last answer + new user message) needs a forward pass.
+ * Bounded LRU: the least recently used chat is evicted once `capacity` chats are cached, or
+ * earlier when running sessions need its KV cache blocks.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChatKey {
+    pub username: String,
+    pub chat_id: i32,
+}
+
+// KV cache plus the tokens it holds, in order
+pub struct CachedPrefix {
+    pub tokens: Vec<u32>,
+    pub cache: KvCache,
+}
+
+pub struct PrefixCache {
+    capacity: usize,
+    // most recently used chat at the front
+    entries: Mutex<VecDeque<(ChatKey, CachedPrefix)>>,
+}
+
+impl PrefixCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    // remove and return the cached prefix of a chat, the session that takes it owns it until it finishes
+    pub fn take(&self, key: &ChatKey) -> Option<CachedPrefix> {
+        let mut entries = self.entries.lock().unwrap();
+        let index = entries.iter().position(|(entry_key, _)| entry_key == key)?;
+        entries.remove(index).map(|(_, prefix)| prefix)
+    }
+
+    // store the cache of a finished turn, evicting the least recently used chats if full
+    pub fn insert(&self, key: ChatKey, prefix: CachedPrefix) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|(entry_key, _)| *entry_key != key);
+        entries.push_front((key, prefix));
+        entries.truncate(self.capacity);
+    }
+
+    // drop the least recently used chat to free its KV cache blocks, false if nothing is cached
+    pub fn evict_oldest(&self) -> bool {
+        self.entries.lock().unwrap().pop_back().is_some()
+    }
+
+    // KV cache blocks held by the cached chats
+    pub fn num_blocks(&self) -> usize {
+        let entries = self.entries.lock().unwrap();
+        entries.iter().map(|(_, prefix)| prefix.cache.blocks.len()).sum()
+    }
+}
+
+// number of leading tokens two sequences have in common
+pub fn common_prefix_len(a: &[u32], b: &[u32]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
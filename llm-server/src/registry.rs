@@ -0,0 +1,147 @@
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+
+use crate::chat_history::add_model;
+use crate::config::ServerConfig;
+use crate::engine::{ClientRequest, EventToServer, InferenceEngine};
+use crate::scheduler::{OverloadReason, Overloaded};
+
+// requests waiting for a prep thread when the model has no queue limit
+const DEFAULT_INTAKE_CAPACITY: usize = 64;
+
+/*
+ * Models loaded at startup. Every model has its own InferenceEngine, and with it its own scheduler,
+ * worker pool and prefix cache, plus a fixed pool of prep threads that take the model's client
+ * requests from the /generate handler and prepare them for the scheduler. Requests pick a model by name, the first configured model is the default.
+*/
+pub struct ModelRegistry {
+    models: Vec<LoadedModel>,
+}
+
+pub struct LoadedModel {
+    // name clients pass as `model`
+    pub name: String,
+    pub model_id: String,
+    // row of the model in the models table, stored with every chat message it answers
+    pub db_id: i32,
+    // GGUF file the model was loaded from, if quantized
+    pub gguf_file: Option<String>,
+    // unix time the model was loaded at
+    pub created: u64,
+    pub engine: Arc<InferenceEngine>,
+    // channel sender to send client /generate requests to this model's prep threads, requests
+    // hold an admission slot while they are in it, so it never holds more than the queue limit
+    client_request_sender: mpsc::SyncSender<ClientRequest>,
+}
+
+impl LoadedModel {
+    // hand a request to the prep threads, without waiting if they are too far behind
+    pub fn submit(&self, client_request: ClientRequest) -> Result<(), Overloaded> {
+        self.client_request_sender
+            .try_send(client_request)
+            .map_err(|err| Overloaded {
+                reason: OverloadReason::Intake,
+                message: match err {
+                    mpsc::TrySendError::Full(_) => "too many requests waiting to be prepared, retry later".to_string(),
+                    mpsc::TrySendError::Disconnected(_) => "the model's prep threads stopped".to_string(),
+                },
+                retry_after_secs: self.engine.retry_after_secs(),
+            })
+    }
+}
+
+impl ModelRegistry {
+    // load every configured model and make sure each one has a row in the models table
+    pub fn load(server_config: &ServerConfig, conn: &Connection) -> Result<Self> {
+        let mut models = Vec::with_capacity(server_config.models.len());
+        for model_config in &server_config.models {
+            let name = model_config.name().to_string();
+            println!("Loading model `{name}` ({})", model_config.model_id);
+            let engine = Arc::new(
+                InferenceEngine::from_model(model_config, server_config)
+                    .with_context(|| format!("load model `{name}`"))?,
+            );
+            let db_id = add_model(conn, &name)?;
+            models.push(LoadedModel {
+                client_request_sender: spawn_prep_threads(&name, Arc::clone(&engine), server_config)
+                    .with_context(|| format!("start prep threads of model `{name}`"))?,
+                name,
+                model_id: model_config.model_id.clone(),
+                db_id,
+                gguf_file: model_config.gguf_file.clone(),
+                created: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|elapsed| elapsed.as_secs())
+                    .unwrap_or_default(),
+                engine,
+            });
+        }
+        Ok(Self { models })
+    }
+
+    // model with the given name, or the default model if no name is given
+    pub fn get(&self, name: Option<&str>) -> Option<&LoadedModel> {
+        match name {
+            Some(name) => self.models.iter().find(|model| model.name == name),
+            None => self.models.first(),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &LoadedModel> {
+        self.models.iter()
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.models
+            .iter()
+            .map(|model| model.name.as_str())
+            .collect()
+    }
+}
+
+// spawn the prep threads that receive client requests for one model from the /generate HTTP handler
+// engine internally manages model synchronization (prefill, decode) between concurrent requests
+// several threads share the channel, summarizing an overflowing chat runs the model before the
+// request can be queued and should not hold up every other request
+fn spawn_prep_threads(
+    name: &str,
+    engine: Arc<InferenceEngine>,
+    server_config: &ServerConfig,
+) -> Result<mpsc::SyncSender<ClientRequest>> {
+    let capacity = match server_config.max_queued_prefills {
+        0 => DEFAULT_INTAKE_CAPACITY,
+        max_queued_prefills => max_queued_prefills,
+    };
+    let (client_request_sender, client_request_receiver) = mpsc::sync_channel::<ClientRequest>(capacity);
+    let client_request_receiver = Arc::new(Mutex::new(client_request_receiver));
+    for thread_id in 0..server_config.prep_threads {
+        let engine = Arc::clone(&engine);
+        let client_request_receiver = Arc::clone(&client_request_receiver);
+        thread::Builder::new()
+            .name(format!("prep-{name}-{thread_id}"))
+            .spawn(move || loop {
+                // the lock is only held while waiting for the next request, not while preparing it
+                let Ok(client_request) = client_request_receiver.lock().unwrap().recv() else {
+                    break;
+                };
+                if let Err(err) = engine.generate(&client_request) {
+                    let _ = client_request.sender.try_send(EventToServer::Error {
+                        message: err.to_string(),
+                    });
+                }
+            })?;
+    }
+    Ok(client_request_sender)
+}
+
+#[cfg(test)]
+impl ModelRegistry {
+    // registry without models, for handler tests that never reach a model
+    pub fn empty() -> Self {
+        Self { models: Vec::new() }
+    }
+}
@@ -1,125 +1,678 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use axum::{
-    extract::State,
+    extract::{Path, Query, State},
     Error,
-    response::sse::{Event, KeepAlive, Sse},
+    http::{header, HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
     Json,
 };
-use serde_json::json;
+use rusqlite::Connection;
+use serde::Deserialize;
+use serde_json::{json, Value};
 use tokio::sync::mpsc;
 use tokio::task;
-use tokio_stream::{wrappers::UnboundedReceiverStream, Stream, StreamExt};
-use std::sync::Mutex;
+use tokio_stream::{iter, wrappers::ReceiverStream, StreamExt};
 
 use crate::{
-    engine::{EventToServer, EXAMPLE_MODEL, ClientRequest},
+    constraint::Grammar,
+    engine::{event_channel, EventReceiver, EventToServer, ClientRequest, TokenLogprob, Truncation, Usage},
+    scheduler::{OverloadReason, Overloaded},
+    prefix_cache::ChatKey,
     state::AppState,
-    types::GenerateRequest,
-    chat_history::{add_message, add_user, next_chat_id, get_user_id},
+    stream_buffer::{BufferedEvent, StreamBuffer},
+    types::{GenerateRequest, Role, ToolCall},
+    chat_history::{add_message, add_tool_message, add_user, next_chat_id, get_user_id},
 };
 
+// buffers the answer (of the first choice) as it comes in and stores it in the database once it
+// is done, or cancelled; with n / best_of the other answers are only sent to the client
+struct AnswerRecorder {
+    state: Arc<AppState>,
+    username: String,
+    model_db_id: i32,
+    chat_id: i32,
+    text: String,
+    tool_calls: Vec<ToolCall>,
+}
+
+impl AnswerRecorder {
+    fn record(&mut self, event: &EventToServer) -> rusqlite::Result<()> {
+        match event {
+            EventToServer::Token { token, choice: 0, .. } => self.text.push_str(token),
+            EventToServer::ToolCall { call, choice: 0 } => self.tool_calls.push(call.clone()),
+            EventToServer::Done { choice: 0, .. } => {
+                let text = std::mem::take(&mut self.text);
+                let tool_calls = std::mem::take(&mut self.tool_calls);
+                if tool_calls.is_empty() {
+                    add_message(
+                        &self.state.db_conn.lock().unwrap(), 
+                        self.username.clone(), 
+                        self.model_db_id,
+                        self.chat_id,
+                        Role::Assistant,
+                        &text,
+                        false)?;
+                } else {
+                    add_tool_message(
+                        &self.state.db_conn.lock().unwrap(),
+                        self.username.clone(),
+                        self.model_db_id,
+                        self.chat_id,
+                        Role::Assistant,
+                        &text,
+                        &json!({ "tool_calls": tool_calls }))?;
+                }
+            }
+            EventToServer::Cancelled { choice: 0, .. } => {
+                // keep the partial answer, marked as truncated
+                let text = std::mem::take(&mut self.text);
+                self.tool_calls.clear();
+                add_message(
+                    &self.state.db_conn.lock().unwrap(),
+                    self.username.clone(),
+                    self.model_db_id,
+                    self.chat_id,
+                    Role::Assistant,
+                    &text,
+                    true)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+// username of the client a request belongs to, required to resume or cancel it
+#[derive(Deserialize)]
+pub struct RequestOwner {
+    pub username: String,
+}
+
+// removes the request from the active requests once it is dropped (generation finished, or the
+// client of a request that is not resumable disconnected), and stops generating for a client that left
+pub struct ActiveRequestGuard {
+    pub state: Arc<AppState>,
+    pub request_id: u64,
+}
+
+impl ActiveRequestGuard {
+    fn cancel(&self) {
+        if let Some(request) = self.state.active_requests.lock().unwrap().get(&self.request_id) {
+            request.cancel_token.cancel();
+        }
+    }
+}
+
+impl Drop for ActiveRequestGuard {
+    fn drop(&mut self) {
+        self.cancel();
+        self.state.finish_request(self.request_id);
+    }
+}
+
 // axum handler that bridges HTTP requests with the blocking inference engine
 pub async fn generate(
     State(state): State<Arc<AppState>>,
     Json(request): Json<GenerateRequest>,
-) -> Sse<impl Stream<Item = Result<Event, Error>>> {
+) -> Response {
+    let started_at = Instant::now();
     // Channel for engine (server) to send events and HTTP handler to read (client)
-    let (sender, receiver) = mpsc::unbounded_channel();
+    let (sender, receiver) = event_channel(&request.params);
 
     // VALIDATE USER REQUEST
-    // 1. prompt not empty
-    // 2. model is supported and loaded
-    // send error events if invalid
-    let mut invalid = false;
-    if request.prompt.trim().is_empty() {
-        let _ = sender.send(EventToServer::Error {
-            message: "prompt must not be empty".to_string(),
-        });
-        invalid = true;
-    } else if let Some(model) = request.model.as_deref() {
-        // TODO: handle more models
-        if model != EXAMPLE_MODEL {
-            let _ = sender.send(EventToServer::Error {
-                message: format!(
-                    "requested model `{model}` is unavailable, only `{EXAMPLE_MODEL}` is loaded"
-                ),
-            });
-            invalid = true;
-        }
-    }
-
-    add_user(&state.db_conn.lock().unwrap(), request.username.clone()).unwrap();
-    let user_id = get_user_id(&state.db_conn.lock().unwrap(), &request.username).unwrap();
-    let chat_id = request
-        .chat_id
-        .unwrap_or_else(|| next_chat_id(&state.db_conn.lock().unwrap(), user_id).unwrap());
-
-    // START GENERATION IN BLOCKING THREAD
-    if !invalid {
-        let blocking_state = Arc::clone(&state);
-        let blocking_sender = sender.clone();
-        let blocking_request = request.clone();
-        let _ = task::spawn_blocking(move || {
-            // store only cur user message in the database
-            add_message(
-                &blocking_state.db_conn.lock().unwrap(),
-                blocking_request.username.clone(),
-                1,
+    // 1. prompt or messages given, and not empty
+    // 2. generation params are in range
+    // 3. model is loaded (no model means the default one)
+    // 4. response_format / regex compile to a grammar
+    // send an error event if invalid, or answer with the error status right away without streaming
+    let mut error = None;
+    let mut grammar = None;
+    let model = state.models.get(request.model.as_deref());
+    let prompt_input = request.prompt_input();
+    if let Err(message) = &prompt_input {
+        error = Some((StatusCode::BAD_REQUEST, message.clone()));
+    } else if let Err(message) = request.params.validate() {
+        error = Some((StatusCode::BAD_REQUEST, message));
+    } else if model.is_none() {
+        let message = format!(
+            "requested model `{}` is unavailable, loaded models: {}",
+            request.model.as_deref().unwrap_or_default(),
+            state.models.names().join(", ")
+        );
+        error = Some((StatusCode::NOT_FOUND, message));
+    } else if let Some(Err(message)) = model.map(|model| {
+        request
+            .params
+            .validate_for_model(model.engine.vocab_size(), model.engine.context_length(), model.engine.max_choices())
+    }) {
+        error = Some((StatusCode::BAD_REQUEST, message));
+    } else {
+        match Grammar::from_params(&request.params) {
+            Ok(compiled) => grammar = compiled.map(Arc::new),
+            Err(message) => error = Some((StatusCode::BAD_REQUEST, message)),
+        }
+    }
+    let invalid = error.is_some();
+    if let Some((status, message)) = error {
+        if !request.stream {
+            return (status, Json(json!({ "error": message }))).into_response();
+        }
+        let _ = sender.try_send(EventToServer::Error { message });
+    }
+    // requests over the model's admission limits are turned away before anything is stored
+    let queue_slot = match model.filter(|_| !invalid).map(|model| model.engine.admit()) {
+        Some(Err(overloaded)) => return overloaded_response(&overloaded),
+        Some(Ok(queue_slot)) => Some(queue_slot),
+        None => None,
+    };
+    // database id of the model, stored with both the user message and the answer
+    let model_db_id = model.map(|model| model.db_id).unwrap_or_default();
+
+    // the database is only touched once the request is valid, an invalid one streams its error
+    // with the chat id it was sent with
+    let chat_id = if invalid {
+        request.chat_id
+    } else {
+        match open_chat(&state.db_conn.lock().unwrap(), &request) {
+            Ok(chat_id) => Some(chat_id),
+            Err(err) => return database_error(&err),
+        }
+    };
+
+    // request id lets the client cancel this generation through /generate/{request_id}/cancel
+    let (request_id, cancel_token) = state.register_request(Some(&request.username));
+    let request_guard = ActiveRequestGuard {
+        state: Arc::clone(&state),
+        request_id,
+    };
+
+    // HAND THE REQUEST TO THE MODEL'S PREP THREADS
+    if let (false, Ok(input), Some(model), Some(chat_id)) = (invalid, prompt_input, model, chat_id) {
+        let client_request = ClientRequest {
+            input,
+            params: request.params.clone(),
+            sender: sender.clone(),
+            cancel_token,
+            chat_key: Some(ChatKey {
+                username: request.username.clone(),
                 chat_id,
-                &blocking_request.user_message,
-            )
-            .unwrap();
-            
-            // send client request into the inference engine worker thread
-            let client_request = ClientRequest {
-                prompt: blocking_request.prompt.clone(),
-                params: blocking_request.params.clone(),
-                sender: blocking_sender.clone(),
-            };
-
-            if let Err(err) = blocking_state.client_request_sender.send(client_request) {
-                let _ = blocking_sender.send(EventToServer::Error {
-                    message: format!("failed to enqueue client request: {err}"),
-                });
-            }
-        });
+            }),
+            grammar,
+            tools: request.tools.clone(),
+            user: Some(request.username.clone()),
+            priority: request.priority,
+            queue_slot,
+        };
+        if let Err(overloaded) = model.submit(client_request) {
+            return overloaded_response(&overloaded);
+        }
+
+        // a failed store drops the request guard on return, which cancels the generation
+        if let Err(err) = store_request_messages(&state, &request, model_db_id, chat_id) {
+            return database_error(&err);
+        }
     }
     // close sender used for validation
     drop(sender);
     
-    let streaming_buffer = Arc::new(Mutex::new(String::new()));
-    let streaming_request = request.clone();
+    let recorder = AnswerRecorder {
+        state: Arc::clone(&state),
+        username: request.username.clone(),
+        model_db_id,
+        // an invalid request gets no answer, so nothing is stored under this id
+        chat_id: chat_id.unwrap_or_default(),
+        text: String::new(),
+        tool_calls: Vec::new(),
+    };
+    let with_logprobs = request.params.logprobs.is_some();
+
+    if !request.stream {
+        // dropping the guard when the client goes away cancels the generation
+        let _guard = request_guard;
+        return collect_answer(receiver, recorder, request_id, with_logprobs, started_at).await;
+    }
 
     // STREAM RESPONSES TO CLIENT
-    // convert server events into SSE events (json payloads)
-    let sse_stream = UnboundedReceiverStream::new(receiver).map(move |event| -> Result<Event, Error> {
-        let mut buffer = streaming_buffer.lock().unwrap();
-        // store generated tokens in buffer to store full response in database
-        let payload = match event {
-            EventToServer::Token { token, index } => {
-                buffer.push_str(&token);
-                json!({ "token": token, "index": index }).to_string()
+    // events go through a buffer that outlives the connection, so a client can resume the stream
+    let buffer = Arc::new(StreamBuffer::new(request.username.clone()));
+    state.streams.lock().unwrap().insert(request_id, Arc::clone(&buffer));
+    let (_, live_events) = buffer.subscribe(None).expect("a new stream has dropped no events");
+    // first event tells the client which request id and chat id this generation belongs to
+    buffer.push(json!({ "request_id": request_id, "chat_id": chat_id }).to_string());
+    // the relay task keeps the request registered until generation ends, connected or not
+    task::spawn(relay_events(receiver, recorder, buffer, request_guard, with_logprobs));
+    sse_response(Vec::new(), live_events)
+}
+
+// add the user if they are new and return the request's chat id, a new chat if it has none
+fn open_chat(conn: &Connection, request: &GenerateRequest) -> rusqlite::Result<i32> {
+    add_user(conn, request.username.clone())?;
+    let user_id = get_user_id(conn, &request.username)?;
+    match request.chat_id {
+        Some(chat_id) => Ok(chat_id),
+        None => next_chat_id(conn, user_id),
+    }
+}
+
+// store only cur user message in the database, or the tool results answering the last turn's calls
+// (before the answer is read, so the answer is always stored after it)
+fn store_request_messages(
+    state: &AppState,
+    request: &GenerateRequest,
+    model_db_id: i32,
+    chat_id: i32,
+) -> rusqlite::Result<()> {
+    let conn = state.db_conn.lock().unwrap();
+    let tool_results = request.tool_results();
+    if tool_results.is_empty() {
+        add_message(
+            &conn,
+            request.username.clone(),
+            model_db_id,
+            chat_id,
+            Role::User,
+            &request.stored_user_message(),
+            false,
+        )?;
+    }
+    for result in tool_results {
+        add_tool_message(
+            &conn,
+            request.username.clone(),
+            model_db_id,
+            chat_id,
+            Role::Tool,
+            &result.content,
+            &json!({ "tool_call_id": result.tool_call_id }),
+        )?;
+    }
+    Ok(())
+}
+
+// 500 for a chat history database error
+fn database_error(err: &rusqlite::Error) -> Response {
+    let message = format!("chat history database error: {err}");
+    (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": message }))).into_response()
+}
+
+// convert server events into SSE payloads and buffer them, store the answer in the database, and
+// cancel the generation once no client has been connected for the resume grace period
+async fn relay_events(
+    mut receiver: EventReceiver,
+    mut recorder: AnswerRecorder,
+    buffer: Arc<StreamBuffer>,
+    request_guard: ActiveRequestGuard,
+    with_logprobs: bool,
+) {
+    let state = Arc::clone(&request_guard.state);
+    let request_id = request_guard.request_id;
+    let mut connection_check = tokio::time::interval(Duration::from_secs(1));
+    let mut disconnected_at: Option<Instant> = None;
+    loop {
+        tokio::select! {
+            event = receiver.next() => {
+                // channel closes once every answer is done
+                let Some(event) = event else { break };
+                let stored = recorder.record(&event);
+                buffer.push(sse_payload(event, with_logprobs));
+                if let Err(err) = stored {
+                    let message = format!("failed to store the answer in the chat history: {err}");
+                    buffer.push(json!({ "error": message }).to_string());
+                }
             }
-            EventToServer::Done { total_tokens } => {
-                add_message(
-                    &state.db_conn.lock().unwrap(), 
-                    streaming_request.username.clone(), 
-                    1,  // hardcoded to model 1 tiny llama ... for now
-                    chat_id,
-                    &buffer).unwrap();
-                buffer.clear();
-                json!({ "done": true, "total_tokens": total_tokens }).to_string()
+            _ = connection_check.tick() => {
+                if buffer.connected() {
+                    disconnected_at = None;
+                } else if disconnected_at.get_or_insert_with(Instant::now).elapsed() >= state.stream_resume_grace {
+                    // the engine answers with a cancelled event, the partial answer is stored as truncated
+                    request_guard.cancel();
+                }
             }
-            EventToServer::Error { message } => json!({ "error": message }).to_string(),
-        };
-        Ok(Event::default().data(payload))
-    });
+        }
+    }
+    buffer.finish();
+    drop(request_guard);
+
+    // a client that reconnects shortly after the end still gets the events it missed
+    tokio::time::sleep(state.stream_resume_grace).await;
+    state.streams.lock().unwrap().remove(&request_id);
+}
+
+// SSE response replaying the buffered `events` first, then streaming `live_events` as they come
+fn sse_response(
+    events: Vec<BufferedEvent>,
+    live_events: mpsc::Receiver<BufferedEvent>,
+) -> Response {
+    let sse_stream = iter(events)
+        .chain(ReceiverStream::new(live_events))
+        .map(|(id, payload)| -> Result<Event, Error> { Ok(Event::default().id(id.to_string()).data(payload)) });
 
     // Axum keeps the HTTP response open and pushes each SSE event so clients see streamed tokens
-    Sse::new(sse_stream).keep_alive(
-        KeepAlive::new()
-            .interval(Duration::from_secs(15))
-            .text("keep-alive"),
+    Sse::new(sse_stream)
+        .keep_alive(
+            KeepAlive::new()
+                .interval(Duration::from_secs(15))
+                .text("keep-alive"),
+        )
+        .into_response()
+}
+
+// axum handler for GET /generate/{request_id}/stream: resume a streamed generation after a
+// disconnect, replaying the events after the Last-Event-ID header (all of them without it); only
+// the user who sent the request can resume it
+pub async fn resume_generate(
+    State(state): State<Arc<AppState>>,
+    Path(request_id): Path<u64>,
+    Query(owner): Query<RequestOwner>,
+    headers: HeaderMap,
+) -> Response {
+    let last_event_id = match headers.get("last-event-id").map(|value| value.to_str().map(str::trim)) {
+        None => None,
+        Some(Ok(value)) => match value.parse::<u64>() {
+            Ok(id) => Some(id),
+            Err(_) => {
+                let message = format!("Last-Event-ID must be an event id, got `{value}`");
+                return (StatusCode::BAD_REQUEST, Json(json!({ "error": message }))).into_response();
+            }
+        },
+        Some(Err(_)) => {
+            let message = "Last-Event-ID must be an event id";
+            return (StatusCode::BAD_REQUEST, Json(json!({ "error": message }))).into_response();
+        }
+    };
+    // a stream of another user is reported as missing, so ids cannot be probed
+    let buffer = state.streams.lock().unwrap().get(&request_id).cloned();
+    let Some(buffer) = buffer.filter(|buffer| buffer.is_owned_by(&owner.username)) else {
+        let message = format!("no resumable stream for request id `{request_id}`");
+        return (StatusCode::NOT_FOUND, Json(json!({ "error": message }))).into_response();
+    };
+    let Some((events, live_events)) = buffer.subscribe(last_event_id) else {
+        let message = format!("the events of request id `{request_id}` after the Last-Event-ID are no longer buffered, fetch the stored answer instead");
+        return (StatusCode::GONE, Json(json!({ "error": message }))).into_response();
+    };
+    sse_response(events, live_events)
+}
+
+// json payload of the SSE event for a server event
+fn sse_payload(event: EventToServer, with_logprobs: bool) -> String {
+    match event {
+        EventToServer::Token { token, index, choice, logprobs } => {
+            let mut payload = json!({ "token": token, "index": index, "choice": choice });
+            if with_logprobs {
+                payload["logprobs"] = json!(logprobs);
+            }
+            payload.to_string()
+        }
+        EventToServer::ToolCall { call, choice } => json!({ "tool_call": call, "choice": choice }).to_string(),
+        EventToServer::Done { total_tokens, finish_reason, truncation, choice, cumulative_logprob, .. } => {
+            let mut payload = json!({
+                "done": true,
+                "choice": choice,
+                "total_tokens": total_tokens,
+                "finish_reason": finish_reason.as_str(),
+                "overflow_policy": truncation.policy.as_str(),
+                "dropped_tokens": truncation.dropped_tokens,
+            });
+            if let Some(cumulative_logprob) = cumulative_logprob {
+                payload["cumulative_logprob"] = json!(cumulative_logprob);
+            }
+            payload.to_string()
+        }
+        EventToServer::Cancelled { total_tokens, choice } => {
+            json!({ "cancelled": true, "choice": choice, "total_tokens": total_tokens, "finish_reason": "cancelled" }).to_string()
+        }
+        EventToServer::Queued { position } => json!({ "queued": true, "position": position }).to_string(),
+        EventToServer::ContextOverflow { prompt_tokens, max_tokens, context_length } => {
+            overflow_payload(prompt_tokens, max_tokens, context_length).to_string()
+        }
+        EventToServer::Error { message } => json!({ "error": message }).to_string(),
+    }
+}
+
+fn overflow_payload(prompt_tokens: usize, max_tokens: usize, context_length: usize) -> Value {
+    json!({
+        "error": format!(
+            "prompt is {prompt_tokens} tokens, which with max_tokens {max_tokens} does not fit into the {context_length} token context window"
+        ),
+        "code": "context_overflow",
+        "prompt_tokens": prompt_tokens,
+        "max_tokens": max_tokens,
+        "context_length": context_length,
+    })
+}
+
+// one answer of a non-streaming request, complete once it has a finish reason
+#[derive(Default)]
+struct CollectedAnswer {
+    text: String,
+    tool_calls: Vec<ToolCall>,
+    logprobs: Vec<TokenLogprob>,
+    total_tokens: usize,
+    finish_reason: Option<&'static str>,
+    usage: Option<Usage>,
+    truncation: Option<Truncation>,
+    cumulative_logprob: Option<f32>,
+}
+
+impl CollectedAnswer {
+    fn to_json(&self, with_logprobs: bool) -> Value {
+        let mut answer = json!({
+            "text": self.text,
+            "total_tokens": self.total_tokens,
+            "finish_reason": self.finish_reason,
+        });
+        if !self.tool_calls.is_empty() {
+            answer["tool_calls"] = json!(self.tool_calls);
+        }
+        if with_logprobs {
+            answer["logprobs"] = json!(self.logprobs);
+        }
+        if let Some(usage) = self.usage {
+            answer["usage"] = json!({
+                "prompt_tokens": usage.prompt_tokens,
+                "completion_tokens": usage.completion_tokens,
+            });
+        }
+        if let Some(truncation) = self.truncation {
+            answer["overflow_policy"] = json!(truncation.policy.as_str());
+            answer["dropped_tokens"] = json!(truncation.dropped_tokens);
+        }
+        if let Some(cumulative_logprob) = self.cumulative_logprob {
+            answer["cumulative_logprob"] = json!(cumulative_logprob);
+        }
+        answer
+    }
+}
+
+// stream: false, wait for the whole answer and send it as one JSON body:
+// 200 with the text (and every choice with n / best_of), 400 for a prompt that does not fit the
+// context window, 500 if generation failed
+async fn collect_answer(
+    mut receiver: EventReceiver,
+    mut recorder: AnswerRecorder,
+    request_id: u64,
+    with_logprobs: bool,
+    started_at: Instant,
+) -> Response {
+    let mut answers: Vec<CollectedAnswer> = Vec::new();
+    let mut first_token_at = None;
+    // the channel closes once every answer is done
+    while let Some(event) = receiver.next().await {
+        if let Err(err) = recorder.record(&event) {
+            return database_error(&err);
+        }
+        if let Some(choice) = event.choice() {
+            if answers.len() <= choice {
+                answers.resize_with(choice + 1, CollectedAnswer::default);
+            }
+        }
+        match event {
+            EventToServer::Token { token, choice, logprobs, .. } => {
+                first_token_at.get_or_insert_with(Instant::now);
+                answers[choice].text.push_str(&token);
+                answers[choice].logprobs.extend(logprobs);
+            }
+            EventToServer::ToolCall { call, choice } => {
+                first_token_at.get_or_insert_with(Instant::now);
+                answers[choice].tool_calls.push(call);
+            }
+            EventToServer::Done { total_tokens, finish_reason, truncation, usage, choice, cumulative_logprob } => {
+                let answer = &mut answers[choice];
+                answer.total_tokens = total_tokens;
+                answer.finish_reason = Some(finish_reason.as_str());
+                answer.usage = Some(usage);
+                answer.truncation = Some(truncation);
+                answer.cumulative_logprob = cumulative_logprob;
+            }
+            EventToServer::Cancelled { total_tokens, choice } => {
+                answers[choice].total_tokens = total_tokens;
+                answers[choice].finish_reason = Some("cancelled");
+            }
+            EventToServer::Queued { .. } => {}
+            EventToServer::ContextOverflow { prompt_tokens, max_tokens, context_length } => {
+                let payload = overflow_payload(prompt_tokens, max_tokens, context_length);
+                return (StatusCode::BAD_REQUEST, Json(payload)).into_response();
+            }
+            EventToServer::Error { message } => {
+                return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": message }))).into_response();
+            }
+        }
+    }
+
+    let Some(first) = answers.first().filter(|answer| answer.finish_reason.is_some()) else {
+        let message = "generation ended without a result";
+        return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": message }))).into_response();
+    };
+    let mut body = first.to_json(with_logprobs);
+    body["request_id"] = json!(request_id);
+    body["chat_id"] = json!(recorder.chat_id);
+    let elapsed_ms = |instant: Instant| instant.duration_since(started_at).as_secs_f64() * 1000.0;
+    body["timings"] = json!({
+        "time_to_first_token_ms": first_token_at.map(elapsed_ms),
+        "total_ms": elapsed_ms(Instant::now()),
+    });
+    if answers.len() > 1 {
+        let choices: Vec<Value> = answers.iter().map(|answer| answer.to_json(with_logprobs)).collect();
+        body["choices"] = json!(choices);
+    }
+    Json(body).into_response()
+}
+
+pub(crate) fn overloaded_response(overloaded: &Overloaded) -> Response {
+    let body = json!({ "error": overloaded.message, "code": overloaded.reason.code() });
+    overloaded_response_with(overloaded, body)
+}
+
+// 429 when the queue is full, 503 when the model is out of KV cache memory or its prep threads are
+// behind, all with Retry-After; the body is in the format of the API the request came through
+pub(crate) fn overloaded_response_with(overloaded: &Overloaded, body: Value) -> Response {
+    let status = match overloaded.reason {
+        OverloadReason::Queue => StatusCode::TOO_MANY_REQUESTS,
+        OverloadReason::KvCache | OverloadReason::Intake => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (
+        status,
+        [(header::RETRY_AFTER, overloaded.retry_after_secs.to_string())],
+        Json(body),
     )
+        .into_response()
+}
+
+// axum handler to stop an in-flight generation, the engine sends a final `cancelled` event on its SSE stream;
+// only the user who sent the request can cancel it, requests of other users are reported as missing
+pub async fn cancel_generate(
+    State(state): State<Arc<AppState>>,
+    Path(request_id): Path<u64>,
+    Query(owner): Query<RequestOwner>,
+) -> (StatusCode, Json<Value>) {
+    let active_requests = state.active_requests.lock().unwrap();
+    match active_requests.get(&request_id).filter(|request| request.is_owned_by(&owner.username)) {
+        Some(request) => {
+            request.cancel_token.cancel();
+            (
+                StatusCode::OK,
+                Json(json!({ "request_id": request_id, "cancelled": true })),
+            )
+        }
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": format!("no in-flight request with id `{request_id}`") })),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        chat_history::{add_model, create_tables, retrieve_chat},
+        registry::ModelRegistry,
+        types::GenerationParams,
+    };
+
+    #[tokio::test]
+    async fn stream_without_client_is_cancelled_and_dropped_after_the_grace_period() {
+        let conn = Connection::open_in_memory().unwrap();
+        create_tables(&conn).unwrap();
+        let model_db_id = add_model(&conn, "test-model").unwrap();
+        add_user(&conn, "alice".to_string()).unwrap();
+        let grace = Duration::from_millis(100);
+        let state = Arc::new(AppState::new(conn, ModelRegistry::empty(), grace));
+
+        let (request_id, cancel_token) = state.register_request(Some("alice"));
+        let buffer = Arc::new(StreamBuffer::new("alice".to_string()));
+        state.streams.lock().unwrap().insert(request_id, Arc::clone(&buffer));
+        let recorder = AnswerRecorder {
+            state: Arc::clone(&state),
+            username: "alice".to_string(),
+            model_db_id,
+            chat_id: 1,
+            text: String::new(),
+            tool_calls: Vec::new(),
+        };
+        let (sender, receiver) = event_channel(&GenerationParams::default());
+        let request_guard = ActiveRequestGuard {
+            state: Arc::clone(&state),
+            request_id,
+        };
+        let relay = task::spawn(relay_events(receiver, recorder, buffer, request_guard, false));
+
+        sender
+            .send(EventToServer::Token { token: "Hel".to_string(), index: 0, choice: 0, logprobs: Vec::new() })
+            .await
+            .unwrap();
+        tokio::time::sleep(grace / 2).await;
+        // no client has been connected since the start, but the grace period is not over yet
+        assert!(!cancel_token.is_cancelled());
+
+        let started = Instant::now();
+        while !cancel_token.is_cancelled() {
+            assert!(started.elapsed() < Duration::from_secs(5), "generation was never cancelled");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        // the engine answers the cancellation, the partial answer is stored as truncated
+        sender
+            .send(EventToServer::Cancelled { total_tokens: 1, choice: 0 })
+            .await
+            .unwrap();
+        drop(sender);
+        relay.await.unwrap();
+
+        assert!(state.active_requests.lock().unwrap().is_empty());
+        // the relay keeps the buffer for another grace period after the end, then drops it
+        assert!(state.streams.lock().unwrap().is_empty());
+        let conn = state.db_conn.lock().unwrap();
+        let user_id = get_user_id(&conn, "alice").unwrap();
+        let messages = retrieve_chat(&conn, user_id, 1).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].message, "Hel");
+        assert!(messages[0].truncated);
+    }
 }
@@ -1 +1,5 @@
 pub mod generate;
+pub mod models;
+pub mod openai;
+pub mod queue;
+pub mod tokens;
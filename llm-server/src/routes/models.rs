@@ -0,0 +1,27 @@
+use std::sync::Arc;
+
+use axum::{extract::State, Json};
+use serde_json::{json, Value};
+
+use crate::state::AppState;
+
+// axum handler that lists the loaded models, the first one is used when a request names no model
+pub async fn list_models(State(state): State<Arc<AppState>>) -> Json<Value> {
+    let default_model = state.models.get(None).map(|model| model.name.as_str());
+    let models: Vec<Value> = state
+        .models
+        .iter()
+        .map(|model| {
+            json!({
+                "name": model.name,
+                "model_id": model.model_id,
+                "default": Some(model.name.as_str()) == default_model,
+                "quantized": model.gguf_file.is_some(),
+                "gguf_file": model.gguf_file,
+                "context_length": model.engine.context_length(),
+                "chat_template": model.engine.chat_template().as_str(),
+            })
+        })
+        .collect();
+    Json(json!({ "models": models }))
+}
@@ -0,0 +1,1059 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::Infallible,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    Json,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::task;
+use tokio_stream::{once, StreamExt};
+
+use crate::{
+    constraint::Grammar,
+    engine::{event_channel, ClientRequest, EventReceiver, EventToServer, FinishReason, TokenLogprob, Usage},
+    routes::generate::{overloaded_response_with, ActiveRequestGuard},
+    scheduler::Overloaded,
+    state::AppState,
+    types::{ChatMessage, EmbedInput, GenerationParams, PromptInput, Priority, ResponseFormat, Tool, ToolCall},
+};
+
+/*
+ * OpenAI-compatible endpoints so off-the-shelf clients can talk to the server:
+ * - POST /v1/chat/completions (messages rendered with the model's chat template)
+ * - POST /v1/completions (raw prompt)
+ * - POST /v1/embeddings (mean pooled final hidden states of the model, unit length by default)
+ * - GET /v1/models
+ * Both completion endpoints stream `chat.completion.chunk` / `text_completion` SSE chunks ended by
+ * `data: [DONE]` when `stream` is true, or answer with one JSON body (including `usage`) otherwise.
+ * Streams only report `usage` with `stream_options.include_usage`, in a last chunk without choices.
+ * With `n` (and `best_of` on /v1/completions) every answer is a choice with its own `index`.
+ * Chat completions take `tools`, calls of them come back as `tool_calls` in the message (or delta).
+ * These requests are not stored in the chat history.
+*/
+
+#[derive(Debug, Deserialize)]
+pub struct ChatCompletionRequest {
+    #[serde(default)]
+    pub model: Option<String>,
+    pub messages: Vec<ChatMessage>,
+    #[serde(default)]
+    pub stream: bool,
+    #[serde(default)]
+    pub stream_options: StreamOptions,
+    // return the logprob of every sampled token, with top_logprobs alternatives each
+    #[serde(default)]
+    pub logprobs: bool,
+    #[serde(default)]
+    pub top_logprobs: Option<usize>,
+    // functions the model can call
+    #[serde(default)]
+    pub tools: Vec<Tool>,
+    #[serde(flatten)]
+    pub sampling: SamplingFields,
+    #[serde(flatten)]
+    pub scheduling: SchedulingFields,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CompletionRequest {
+    #[serde(default)]
+    pub model: Option<String>,
+    pub prompt: String,
+    #[serde(default)]
+    pub stream: bool,
+    #[serde(default)]
+    pub stream_options: StreamOptions,
+    // number of alternatives to return with the logprob of every sampled token
+    #[serde(default)]
+    pub logprobs: Option<usize>,
+    // generate this many answers and return the n with the highest cumulative logprob
+    #[serde(default)]
+    pub best_of: Option<usize>,
+    #[serde(flatten)]
+    pub sampling: SamplingFields,
+    #[serde(flatten)]
+    pub scheduling: SchedulingFields,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EmbeddingRequest {
+    #[serde(default)]
+    pub model: Option<String>,
+    pub input: EmbeddingInputField,
+    // scale every vector to unit length (OpenAI's always are), not part of the OpenAI API
+    #[serde(default = "default_normalize")]
+    pub normalize: bool,
+}
+
+fn default_normalize() -> bool {
+    true
+}
+
+// one text or a list of them, either can also be given as token ids
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum EmbeddingInputField {
+    One(String),
+    Many(Vec<String>),
+    Tokens(Vec<u32>),
+    ManyTokens(Vec<Vec<u32>>),
+}
+
+impl EmbeddingInputField {
+    fn into_inputs(self) -> Vec<EmbedInput> {
+        match self {
+            Self::One(text) => vec![EmbedInput::Text(text)],
+            Self::Many(texts) => texts.into_iter().map(EmbedInput::Text).collect(),
+            Self::Tokens(tokens) => vec![EmbedInput::Tokens(tokens)],
+            Self::ManyTokens(inputs) => inputs.into_iter().map(EmbedInput::Tokens).collect(),
+        }
+    }
+}
+
+// only read when streaming
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct StreamOptions {
+    // send a last chunk with the usage of the whole request
+    pub include_usage: bool,
+}
+
+// OpenAI sampling fields we support, anything not given keeps our own default
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct SamplingFields {
+    pub max_tokens: Option<usize>,
+    // newer name of max_tokens in the chat API
+    pub max_completion_tokens: Option<usize>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub seed: Option<u64>,
+    // one stop sequence or a list of them
+    pub stop: Option<StopField>,
+    // JSON mode or structured output following a JSON schema
+    pub response_format: Option<ResponseFormatField>,
+    // number of answers to generate
+    pub n: Option<usize>,
+}
+
+// who the request is for and how urgent it is, used to share the model fairly
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct SchedulingFields {
+    // OpenAI's end-user id, requests without one share one fair share bucket
+    pub user: Option<String>,
+    // not part of the OpenAI API
+    pub priority: Priority,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum StopField {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormatField {
+    Text,
+    JsonObject,
+    JsonSchema { json_schema: JsonSchemaField },
+}
+
+// the schema's name, description and strict flag are not needed, outputs always follow the schema
+#[derive(Debug, Deserialize)]
+pub struct JsonSchemaField {
+    pub schema: Value,
+}
+
+impl SamplingFields {
+    fn to_params(&self) -> GenerationParams {
+        let defaults = GenerationParams::default();
+        GenerationParams {
+            max_tokens: self
+                .max_completion_tokens
+                .or(self.max_tokens)
+                .unwrap_or(defaults.max_tokens),
+            temperature: self.temperature.unwrap_or(defaults.temperature),
+            top_p: self.top_p.or(defaults.top_p),
+            seed: self.seed.unwrap_or(defaults.seed),
+            n: self.n.unwrap_or(defaults.n),
+            stop: match &self.stop {
+                Some(StopField::One(stop)) => vec![stop.clone()],
+                Some(StopField::Many(stops)) => stops.clone(),
+                None => Vec::new(),
+            },
+            response_format: self.response_format.as_ref().map(|format| match format {
+                ResponseFormatField::Text => ResponseFormat::Text,
+                ResponseFormatField::JsonObject => ResponseFormat::JsonObject,
+                ResponseFormatField::JsonSchema { json_schema } => ResponseFormat::JsonSchema {
+                    schema: json_schema.schema.clone(),
+                },
+            }),
+            ..defaults
+        }
+    }
+}
+
+// the two response shapes share everything but names and where the text goes
+#[derive(Debug, Clone, Copy)]
+enum CompletionKind {
+    Chat,
+    Text,
+}
+
+impl CompletionKind {
+    fn id_prefix(&self) -> &'static str {
+        match self {
+            Self::Chat => "chatcmpl",
+            Self::Text => "cmpl",
+        }
+    }
+
+    fn object(&self, streaming: bool) -> &'static str {
+        match (self, streaming) {
+            (Self::Chat, true) => "chat.completion.chunk",
+            (Self::Chat, false) => "chat.completion",
+            (Self::Text, _) => "text_completion",
+        }
+    }
+
+    // one choice of a streamed chunk
+    fn chunk_choice(
+        &self,
+        index: usize,
+        text: Option<&str>,
+        logprobs: &[TokenLogprob],
+        finish_reason: Option<&str>,
+    ) -> Value {
+        match self {
+            Self::Chat => json!({
+                "index": index,
+                "delta": text.map_or(json!({}), |text| json!({ "content": text })),
+                "logprobs": self.logprobs(logprobs),
+                "finish_reason": finish_reason,
+            }),
+            Self::Text => json!({
+                "index": index,
+                "text": text.unwrap_or_default(),
+                "logprobs": self.logprobs(logprobs),
+                "finish_reason": finish_reason,
+            }),
+        }
+    }
+
+    // one chunk choice per tool call, the legacy completions API has no tools
+    fn tool_call_chunk_choice(&self, index: usize, call_index: usize, call: &ToolCall) -> Value {
+        match self {
+            Self::Chat => json!({
+                "index": index,
+                "delta": { "tool_calls": [openai_tool_call(call_index, call)] },
+                "logprobs": null,
+                "finish_reason": null,
+            }),
+            Self::Text => self.chunk_choice(index, None, &[], None),
+        }
+    }
+
+    // one choice of a non-streaming response
+    fn full_choice(&self, index: usize, answer: &Answer, finish_reason: &str) -> Value {
+        let (text, logprobs) = (&answer.text, &answer.logprobs);
+        match self {
+            Self::Chat if !answer.tool_calls.is_empty() => {
+                let tool_calls: Vec<Value> = answer
+                    .tool_calls
+                    .iter()
+                    .enumerate()
+                    .map(|(call_index, call)| openai_tool_call(call_index, call))
+                    .collect();
+                json!({
+                    "index": index,
+                    "message": {
+                        "role": "assistant",
+                        "content": (!text.is_empty()).then_some(text),
+                        "tool_calls": tool_calls,
+                    },
+                    "logprobs": self.logprobs(logprobs),
+                    "finish_reason": finish_reason,
+                })
+            }
+            Self::Chat => json!({
+                "index": index,
+                "message": { "role": "assistant", "content": text },
+                "logprobs": self.logprobs(logprobs),
+                "finish_reason": finish_reason,
+            }),
+            Self::Text => json!({
+                "index": index,
+                "text": text,
+                "logprobs": self.logprobs(logprobs),
+                "finish_reason": finish_reason,
+            }),
+        }
+    }
+
+    // the chat API lists an object per token, the legacy completions API parallel arrays
+    fn logprobs(&self, logprobs: &[TokenLogprob]) -> Value {
+        if logprobs.is_empty() {
+            return Value::Null;
+        }
+        match self {
+            Self::Chat => {
+                let content: Vec<Value> = logprobs
+                    .iter()
+                    .map(|logprob| {
+                        let top_logprobs: Vec<Value> = logprob
+                            .top_logprobs
+                            .iter()
+                            .map(|top| {
+                                json!({
+                                    "token": top.token,
+                                    "logprob": top.logprob,
+                                    "bytes": top.token.as_bytes(),
+                                })
+                            })
+                            .collect();
+                        json!({
+                            "token": logprob.token,
+                            "logprob": logprob.logprob,
+                            "bytes": logprob.token.as_bytes(),
+                            "top_logprobs": top_logprobs,
+                        })
+                    })
+                    .collect();
+                json!({ "content": content })
+            }
+            Self::Text => {
+                let top_logprobs: Vec<serde_json::Map<String, Value>> = logprobs
+                    .iter()
+                    .map(|logprob| {
+                        logprob
+                            .top_logprobs
+                            .iter()
+                            .map(|top| (top.token.clone(), json!(top.logprob)))
+                            .collect()
+                    })
+                    .collect();
+                json!({
+                    "tokens": logprobs.iter().map(|logprob| &logprob.token).collect::<Vec<_>>(),
+                    "token_logprobs": logprobs.iter().map(|logprob| logprob.logprob).collect::<Vec<_>>(),
+                    "top_logprobs": top_logprobs,
+                })
+            }
+        }
+    }
+}
+
+// axum handler for POST /v1/chat/completions
+pub async fn chat_completions(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<ChatCompletionRequest>,
+) -> Response {
+    if request.messages.is_empty() {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "messages must not be empty",
+            "invalid_request_error",
+            None,
+        );
+    }
+    let input = PromptInput::Messages(request.messages);
+    let mut params = request.sampling.to_params();
+    if request.logprobs {
+        params.logprobs = Some(request.top_logprobs.unwrap_or(0));
+    }
+    complete(
+        state,
+        CompletionKind::Chat,
+        request.model,
+        CompletionInput { prompt: input, tools: request.tools },
+        params,
+        &request.scheduling,
+        request.stream.then_some(request.stream_options),
+    )
+    .await
+}
+
+// axum handler for POST /v1/completions
+pub async fn completions(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<CompletionRequest>,
+) -> Response {
+    if request.prompt.trim().is_empty() {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "prompt must not be empty",
+            "invalid_request_error",
+            None,
+        );
+    }
+    let input = PromptInput::Text(request.prompt);
+    let params = GenerationParams {
+        logprobs: request.logprobs,
+        best_of: request.best_of,
+        ..request.sampling.to_params()
+    };
+    complete(
+        state,
+        CompletionKind::Text,
+        request.model,
+        CompletionInput { prompt: input, tools: Vec::new() },
+        params,
+        &request.scheduling,
+        request.stream.then_some(request.stream_options),
+    )
+    .await
+}
+
+// axum handler for GET /v1/models
+pub async fn list_models(State(state): State<Arc<AppState>>) -> Json<Value> {
+    let data: Vec<Value> = state
+        .models
+        .iter()
+        .map(|model| {
+            json!({
+                "id": model.name,
+                "object": "model",
+                "created": model.created,
+                "owned_by": "llm-server",
+            })
+        })
+        .collect();
+    Json(json!({ "object": "list", "data": data }))
+}
+
+// what the model is asked: the prompt and the tools it may call
+struct CompletionInput {
+    prompt: PromptInput,
+    tools: Vec<Tool>,
+}
+
+// validate the request, queue it on the model's engine and answer in the OpenAI format
+// the answer is streamed if stream options are given, and sent as one JSON body otherwise
+async fn complete(
+    state: Arc<AppState>,
+    kind: CompletionKind,
+    model_name: Option<String>,
+    input: CompletionInput,
+    params: GenerationParams,
+    scheduling: &SchedulingFields,
+    stream: Option<StreamOptions>,
+) -> Response {
+    let Some(model) = state.models.get(model_name.as_deref()) else {
+        let message = format!(
+            "model `{}` does not exist, loaded models: {}",
+            model_name.unwrap_or_default(),
+            state.models.names().join(", ")
+        );
+        return error_response(
+            StatusCode::NOT_FOUND,
+            &message,
+            "invalid_request_error",
+            Some("model_not_found"),
+        );
+    };
+    let grammar = match params
+        .validate()
+        .and_then(|()| {
+            params.validate_for_model(model.engine.vocab_size(), model.engine.context_length(), model.engine.max_choices())
+        })
+        .and_then(|()| Grammar::from_params(&params))
+    {
+        Ok(grammar) => grammar.map(Arc::new),
+        Err(message) => {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                &message,
+                "invalid_request_error",
+                None,
+            );
+        }
+    };
+
+    let queue_slot = match model.engine.admit() {
+        Ok(queue_slot) => queue_slot,
+        Err(overloaded) => return overloaded_response(&overloaded),
+    };
+
+    let (request_id, cancel_token) = state.register_request(scheduling.user.as_deref());
+    let request_guard = ActiveRequestGuard {
+        state: Arc::clone(&state),
+        request_id,
+    };
+    let (sender, receiver) = event_channel(&params);
+    let choices = params.n;
+    let client_request = ClientRequest {
+        input: input.prompt,
+        params,
+        sender,
+        cancel_token,
+        chat_key: None,
+        grammar,
+        tools: input.tools,
+        user: scheduling.user.clone(),
+        priority: scheduling.priority,
+        queue_slot: Some(queue_slot),
+    };
+    if let Err(overloaded) = model.submit(client_request) {
+        return overloaded_response(&overloaded);
+    }
+
+    let header = ResponseHeader {
+        id: format!("{}-{request_id}", kind.id_prefix()),
+        created: unix_time(),
+        model: model.name.clone(),
+    };
+    if let Some(stream_options) = stream {
+        stream_response(kind, header, choices, receiver, request_guard, stream_options.include_usage).into_response()
+    } else {
+        // dropping the guard when the client goes away cancels the generation
+        let _guard = request_guard;
+        collect_response(kind, header, receiver).await
+    }
+}
+
+// axum handler for POST /v1/embeddings
+pub async fn embeddings(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<EmbeddingRequest>,
+) -> Response {
+    let Some(model) = state.models.get(request.model.as_deref()) else {
+        let message = format!(
+            "model `{}` does not exist, loaded models: {}",
+            request.model.unwrap_or_default(),
+            state.models.names().join(", ")
+        );
+        return error_response(
+            StatusCode::NOT_FOUND,
+            &message,
+            "invalid_request_error",
+            Some("model_not_found"),
+        );
+    };
+    let inputs = request.input.into_inputs();
+    if inputs.is_empty() {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "input must not be empty",
+            "invalid_request_error",
+            None,
+        );
+    }
+    let inputs = match model.engine.embedding_tokens(inputs) {
+        Ok(inputs) => inputs,
+        Err(message) => {
+            return error_response(StatusCode::BAD_REQUEST, &message, "invalid_request_error", None);
+        }
+    };
+    let queue_slot = match model.engine.admit() {
+        Ok(queue_slot) => queue_slot,
+        Err(overloaded) => return overloaded_response(&overloaded),
+    };
+
+    let prompt_tokens: usize = inputs.iter().map(Vec::len).sum();
+    let engine = Arc::clone(&model.engine);
+    let normalize = request.normalize;
+    // the engine blocks until a worker has run the inputs through the model
+    let vectors = task::spawn_blocking(move || engine.embed(inputs, normalize, queue_slot))
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|vectors| vectors);
+    let vectors = match vectors {
+        Ok(vectors) => vectors,
+        Err(err) => {
+            return error_response(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string(), "server_error", None);
+        }
+    };
+    let data: Vec<Value> = vectors
+        .into_iter()
+        .enumerate()
+        .map(|(index, embedding)| json!({ "object": "embedding", "index": index, "embedding": embedding }))
+        .collect();
+    Json(json!({
+        "object": "list",
+        "data": data,
+        "model": model.name,
+        "usage": { "prompt_tokens": prompt_tokens, "total_tokens": prompt_tokens },
+    }))
+    .into_response()
+}
+
+// fields every response and chunk repeats
+struct ResponseHeader {
+    id: String,
+    created: u64,
+    model: String,
+}
+
+impl ResponseHeader {
+    fn body(&self, object: &str, choices: Vec<Value>) -> Value {
+        json!({
+            "id": self.id,
+            "object": object,
+            "created": self.created,
+            "model": self.model,
+            "choices": choices,
+        })
+    }
+}
+
+fn stream_response(
+    kind: CompletionKind,
+    header: ResponseHeader,
+    choices: usize,
+    receiver: EventReceiver,
+    request_guard: ActiveRequestGuard,
+    include_usage: bool,
+) -> Sse<impl tokio_stream::Stream<Item = Result<Event, Infallible>>> {
+    let mut writer = ChunkWriter::new(kind, header, include_usage);
+    let first_chunk = writer.first_chunk(choices);
+    // the end of the events is marked by a None, to send the usage chunk after every answer is done
+    let chunks = receiver.map(Some).chain(once(None)).filter_map(move |event| {
+        // moving the guard into the stream keeps the request registered while the client is connected
+        let _ = &request_guard;
+        let chunk = match event {
+            // OpenAI has no such chunk, an SSE comment keeps clients that do not expect it working
+            Some(EventToServer::Queued { position }) => {
+                return Some(Ok(Event::default().comment(format!("queued position {position}"))));
+            }
+            Some(event) => writer.chunk(event)?,
+            None => writer.usage_chunk()?,
+        };
+        Some(Ok(Event::default().data(chunk.to_string())))
+    });
+
+    let stream =
+        tokio_stream::iter(first_chunk.map(|chunk| Ok(Event::default().data(chunk.to_string()))))
+            .chain(chunks)
+            .chain(once(Ok(Event::default().data("[DONE]"))));
+    Sse::new(stream).keep_alive(
+        KeepAlive::new()
+            .interval(Duration::from_secs(15))
+            .text("keep-alive"),
+    )
+}
+
+// turns the events of a streamed request into OpenAI chunks
+// with stream_options.include_usage every chunk has a `usage` of null, and once every answer is done
+// a last chunk without choices carries the usage of the whole request
+struct ChunkWriter {
+    kind: CompletionKind,
+    header: ResponseHeader,
+    include_usage: bool,
+    // tool calls sent so far per choice, each call has its own index in the delta
+    tool_calls: HashMap<usize, usize>,
+    // the choices share the prompt, their completion tokens add up
+    usage: Option<Usage>,
+}
+
+impl ChunkWriter {
+    fn new(kind: CompletionKind, header: ResponseHeader, include_usage: bool) -> Self {
+        Self {
+            kind,
+            header,
+            include_usage,
+            tool_calls: HashMap::new(),
+            usage: None,
+        }
+    }
+
+    fn body(&self, choices: Vec<Value>) -> Value {
+        let mut chunk = self.header.body(self.kind.object(true), choices);
+        if self.include_usage {
+            chunk["usage"] = Value::Null;
+        }
+        chunk
+    }
+
+    // chat streams announce the assistant role of every choice before any content
+    fn first_chunk(&self, choices: usize) -> Option<Value> {
+        match self.kind {
+            CompletionKind::Chat => Some(self.body(
+                (0..choices)
+                    .map(|index| json!({ "index": index, "delta": { "role": "assistant", "content": "" }, "logprobs": null, "finish_reason": null }))
+                    .collect(),
+            )),
+            CompletionKind::Text => None,
+        }
+    }
+
+    // chunk of one event, None for queue positions, which are not chunks
+    fn chunk(&mut self, event: EventToServer) -> Option<Value> {
+        let kind = self.kind;
+        let chunk = match event {
+            EventToServer::ToolCall { call, choice } => {
+                let call_index = self.tool_calls.entry(choice).or_default();
+                *call_index += 1;
+                let call_index = *call_index - 1;
+                self.body(vec![kind.tool_call_chunk_choice(choice, call_index, &call)])
+            }
+            EventToServer::Token {
+                token, choice, logprobs, ..
+            } => self.body(vec![kind.chunk_choice(choice, Some(&token), &logprobs, None)]),
+            EventToServer::Done {
+                finish_reason,
+                usage,
+                choice,
+                ..
+            } => {
+                let total = self.usage.get_or_insert(Usage {
+                    prompt_tokens: usage.prompt_tokens,
+                    completion_tokens: 0,
+                });
+                total.completion_tokens += usage.completion_tokens;
+                self.body(vec![kind.chunk_choice(choice, None, &[], Some(openai_finish_reason(finish_reason)))])
+            }
+            EventToServer::Cancelled { choice, .. } => {
+                self.body(vec![kind.chunk_choice(choice, None, &[], Some("cancelled"))])
+            }
+            EventToServer::Queued { .. } => return None,
+            EventToServer::ContextOverflow {
+                prompt_tokens,
+                max_tokens,
+                context_length,
+            } => error_body(
+                &overflow_message(prompt_tokens, max_tokens, context_length),
+                "invalid_request_error",
+                Some("context_length_exceeded"),
+            ),
+            EventToServer::Error { message } => error_body(&message, "server_error", None),
+        };
+        Some(chunk)
+    }
+
+    // last chunk, only sent with include_usage and once an answer is done
+    fn usage_chunk(&self) -> Option<Value> {
+        let usage = self.usage.filter(|_| self.include_usage)?;
+        let mut chunk = self.header.body(self.kind.object(true), Vec::new());
+        chunk["usage"] = usage_json(&usage);
+        Some(chunk)
+    }
+}
+
+// wait for every answer and send them as one JSON body
+async fn collect_response(
+    kind: CompletionKind,
+    header: ResponseHeader,
+    mut receiver: EventReceiver,
+) -> Response {
+    // every choice's answer, with its finish reason once done
+    let mut answers: BTreeMap<usize, (Answer, Option<&'static str>)> = BTreeMap::new();
+    let mut total_usage: Option<Usage> = None;
+    // the channel closes once every answer is done
+    while let Some(event) = receiver.next().await {
+        match event {
+            EventToServer::Token {
+                token, choice, logprobs, ..
+            } => {
+                let (answer, _) = answers.entry(choice).or_default();
+                answer.text.push_str(&token);
+                answer.logprobs.extend(logprobs);
+            }
+            EventToServer::ToolCall { call, choice } => {
+                answers.entry(choice).or_default().0.tool_calls.push(call);
+            }
+            EventToServer::Queued { .. } => {}
+            EventToServer::Done {
+                finish_reason,
+                usage,
+                choice,
+                ..
+            } => {
+                answers.entry(choice).or_default().1 = Some(openai_finish_reason(finish_reason));
+                // the choices share the prompt, their completion tokens add up
+                let total = total_usage.get_or_insert(Usage {
+                    prompt_tokens: usage.prompt_tokens,
+                    completion_tokens: 0,
+                });
+                total.completion_tokens += usage.completion_tokens;
+            }
+            EventToServer::Cancelled { .. } => {
+                return error_response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "request was cancelled",
+                    "server_error",
+                    None,
+                );
+            }
+            EventToServer::ContextOverflow {
+                prompt_tokens,
+                max_tokens,
+                context_length,
+            } => {
+                let message = overflow_message(prompt_tokens, max_tokens, context_length);
+                return error_response(
+                    StatusCode::BAD_REQUEST,
+                    &message,
+                    "invalid_request_error",
+                    Some("context_length_exceeded"),
+                );
+            }
+            EventToServer::Error { message } => {
+                return error_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    &message,
+                    "server_error",
+                    None,
+                );
+            }
+        }
+    }
+    if let Some(usage) = total_usage {
+        let choices = answers
+            .into_iter()
+            .filter_map(|(index, (answer, finish_reason))| {
+                finish_reason.map(|finish_reason| kind.full_choice(index, &answer, finish_reason))
+            })
+            .collect();
+        let mut body = header.body(kind.object(false), choices);
+        body["usage"] = usage_json(&usage);
+        return Json(body).into_response();
+    }
+    error_response(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "generation ended without a result",
+        "server_error",
+        None,
+    )
+}
+
+// text, tool calls and logprobs collected for one choice of a non-streaming response
+#[derive(Default)]
+struct Answer {
+    text: String,
+    tool_calls: Vec<ToolCall>,
+    logprobs: Vec<TokenLogprob>,
+}
+
+// OpenAI sends the arguments of a call as a JSON string
+fn openai_tool_call(index: usize, call: &ToolCall) -> Value {
+    let arguments = match &call.arguments {
+        Value::String(arguments) => arguments.clone(),
+        arguments => arguments.to_string(),
+    };
+    json!({
+        "index": index,
+        "id": call.id,
+        "type": "function",
+        "function": { "name": call.name, "arguments": arguments },
+    })
+}
+
+// OpenAI does not tell end of sequence tokens and stop sequences apart
+fn openai_finish_reason(finish_reason: FinishReason) -> &'static str {
+    match finish_reason {
+        FinishReason::Eos | FinishReason::Stop => "stop",
+        other => other.as_str(),
+    }
+}
+
+fn usage_json(usage: &Usage) -> Value {
+    json!({
+        "prompt_tokens": usage.prompt_tokens,
+        "completion_tokens": usage.completion_tokens,
+        "total_tokens": usage.prompt_tokens + usage.completion_tokens,
+    })
+}
+
+fn overflow_message(prompt_tokens: usize, max_tokens: usize, context_length: usize) -> String {
+    format!(
+        "prompt is {prompt_tokens} tokens, which with max_tokens {max_tokens} does not fit into the {context_length} token context window"
+    )
+}
+
+// error object in the shape OpenAI clients expect
+fn error_body(message: &str, error_type: &str, code: Option<&str>) -> Value {
+    json!({
+        "error": {
+            "message": message,
+            "type": error_type,
+            "param": null,
+            "code": code,
+        }
+    })
+}
+
+// same status and Retry-After as /generate, with an OpenAI error body
+fn overloaded_response(overloaded: &Overloaded) -> Response {
+    let body = error_body(&overloaded.message, "server_error", Some(overloaded.reason.code()));
+    overloaded_response_with(overloaded, body)
+}
+
+fn error_response(
+    status: StatusCode,
+    message: &str,
+    error_type: &str,
+    code: Option<&str>,
+) -> Response {
+    (status, Json(error_body(message, error_type, code))).into_response()
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{engine::Truncation, types::OverflowPolicy};
+
+    fn chunk_writer(kind: CompletionKind, include_usage: bool) -> ChunkWriter {
+        let header = ResponseHeader {
+            id: "chatcmpl-1".to_string(),
+            created: 1700000000,
+            model: "tiny".to_string(),
+        };
+        ChunkWriter::new(kind, header, include_usage)
+    }
+
+    fn token(text: &str, choice: usize) -> EventToServer {
+        EventToServer::Token { token: text.to_string(), index: 0, choice, logprobs: Vec::new() }
+    }
+
+    fn done(choice: usize, completion_tokens: usize) -> EventToServer {
+        EventToServer::Done {
+            total_tokens: completion_tokens,
+            finish_reason: FinishReason::Eos,
+            truncation: Truncation { policy: OverflowPolicy::Reject, dropped_tokens: 0 },
+            usage: Usage { prompt_tokens: 5, completion_tokens },
+            choice,
+            cumulative_logprob: None,
+        }
+    }
+
+    fn header_fields(object: &str) -> Value {
+        json!({ "id": "chatcmpl-1", "object": object, "created": 1700000000, "model": "tiny" })
+    }
+
+    // the header fields plus `fields`
+    fn chunk(object: &str, fields: Value) -> Value {
+        let mut chunk = header_fields(object);
+        chunk.as_object_mut().unwrap().extend(fields.as_object().unwrap().clone());
+        chunk
+    }
+
+    #[test]
+    fn chat_chunks_have_no_usage_by_default() {
+        let mut writer = chunk_writer(CompletionKind::Chat, false);
+        assert_eq!(
+            writer.first_chunk(1).unwrap(),
+            chunk(
+                "chat.completion.chunk",
+                json!({ "choices": [{ "index": 0, "delta": { "role": "assistant", "content": "" }, "logprobs": null, "finish_reason": null }] }),
+            )
+        );
+        assert_eq!(
+            writer.chunk(token("Hi", 0)).unwrap(),
+            chunk(
+                "chat.completion.chunk",
+                json!({ "choices": [{ "index": 0, "delta": { "content": "Hi" }, "logprobs": null, "finish_reason": null }] }),
+            )
+        );
+        assert_eq!(
+            writer.chunk(done(0, 1)).unwrap(),
+            chunk(
+                "chat.completion.chunk",
+                json!({ "choices": [{ "index": 0, "delta": {}, "logprobs": null, "finish_reason": "stop" }] }),
+            )
+        );
+        assert_eq!(writer.chunk(EventToServer::Queued { position: 1 }), None);
+        assert_eq!(writer.usage_chunk(), None);
+    }
+
+    #[test]
+    fn chat_stream_with_include_usage_ends_with_the_usage_of_every_choice() {
+        let mut writer = chunk_writer(CompletionKind::Chat, true);
+        assert_eq!(writer.first_chunk(2).unwrap()["usage"], Value::Null);
+        assert_eq!(
+            writer.chunk(token("Hi", 1)).unwrap(),
+            chunk(
+                "chat.completion.chunk",
+                json!({
+                    "choices": [{ "index": 1, "delta": { "content": "Hi" }, "logprobs": null, "finish_reason": null }],
+                    "usage": null,
+                }),
+            )
+        );
+        // every choice's last chunk keeps a usage of null
+        assert_eq!(writer.chunk(done(1, 3)).unwrap()["usage"], Value::Null);
+        assert_eq!(writer.chunk(done(0, 4)).unwrap()["usage"], Value::Null);
+        assert_eq!(
+            writer.usage_chunk().unwrap(),
+            chunk(
+                "chat.completion.chunk",
+                json!({
+                    "choices": [],
+                    "usage": { "prompt_tokens": 5, "completion_tokens": 7, "total_tokens": 12 },
+                }),
+            )
+        );
+    }
+
+    #[test]
+    fn completion_chunks_carry_the_text_and_usage_only_when_asked() {
+        let mut writer = chunk_writer(CompletionKind::Text, true);
+        assert_eq!(writer.first_chunk(1), None);
+        assert_eq!(
+            writer.chunk(token("Hi", 0)).unwrap(),
+            chunk(
+                "text_completion",
+                json!({
+                    "choices": [{ "index": 0, "text": "Hi", "logprobs": null, "finish_reason": null }],
+                    "usage": null,
+                }),
+            )
+        );
+        assert_eq!(
+            writer.chunk(done(0, 2)).unwrap(),
+            chunk(
+                "text_completion",
+                json!({
+                    "choices": [{ "index": 0, "text": "", "logprobs": null, "finish_reason": "stop" }],
+                    "usage": null,
+                }),
+            )
+        );
+        assert_eq!(
+            writer.usage_chunk().unwrap(),
+            chunk(
+                "text_completion",
+                json!({
+                    "choices": [],
+                    "usage": { "prompt_tokens": 5, "completion_tokens": 2, "total_tokens": 7 },
+                }),
+            )
+        );
+
+        let mut writer = chunk_writer(CompletionKind::Text, false);
+        assert!(writer.chunk(done(0, 2)).unwrap().get("usage").is_none());
+        assert_eq!(writer.usage_chunk(), None);
+    }
+
+    #[test]
+    fn stream_options_are_optional() {
+        let request: CompletionRequest = serde_json::from_value(json!({ "prompt": "Hi", "stream": true })).unwrap();
+        assert!(!request.stream_options.include_usage);
+        let request: ChatCompletionRequest = serde_json::from_value(json!({
+            "messages": [{ "role": "user", "content": "Hi" }],
+            "stream": true,
+            "stream_options": { "include_usage": true },
+        }))
+        .unwrap();
+        assert!(request.stream_options.include_usage);
+    }
+}
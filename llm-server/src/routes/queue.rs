@@ -0,0 +1,20 @@
+use std::sync::Arc;
+
+use axum::{extract::State, Json};
+use serde_json::{json, Value};
+
+use crate::state::AppState;
+
+// axum handler that shows how loaded every model is: waiting requests, running sessions and KV cache memory
+pub async fn queue_status(State(state): State<Arc<AppState>>) -> Json<Value> {
+    let models: Vec<Value> = state
+        .models
+        .iter()
+        .map(|model| {
+            let mut stats = json!(model.engine.queue_stats());
+            stats["name"] = json!(model.name);
+            stats
+        })
+        .collect();
+    Json(json!({ "models": models }))
+}
@@ -0,0 +1,122 @@
+use std::sync::Arc;
+
+use axum::{extract::State, http::StatusCode, Json};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    registry::LoadedModel,
+    state::AppState,
+    types::{ChatMessage, PromptInput, Tool},
+};
+
+/*
+ * Tokenizer endpoints, so clients can budget prompts before sending them:
+ * - POST /tokenize turns text into token ids
+ * - POST /detokenize turns token ids back into text
+ * - POST /count_tokens counts the prompt tokens of a raw prompt, or of messages (and tools)
+ *   rendered with the model's chat template, exactly as /generate would see them
+ * Each one uses the tokenizer of the requested model (the default model when none is given).
+*/
+
+#[derive(Debug, Deserialize)]
+pub struct TokenizeRequest {
+    #[serde(default)]
+    pub model: Option<String>,
+    pub text: String,
+    // add the model's BOS token like a prompt gets it
+    #[serde(default = "default_add_special_tokens")]
+    pub add_special_tokens: bool,
+}
+
+fn default_add_special_tokens() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DetokenizeRequest {
+    #[serde(default)]
+    pub model: Option<String>,
+    pub tokens: Vec<u32>,
+    // leave out BOS / EOS and other special tokens
+    #[serde(default)]
+    pub skip_special_tokens: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CountTokensRequest {
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub prompt: String,
+    #[serde(default)]
+    pub messages: Option<Vec<ChatMessage>>,
+    #[serde(default)]
+    pub tools: Vec<Tool>,
+}
+
+type ErrorResponse = (StatusCode, Json<Value>);
+
+// axum handler for POST /tokenize
+pub async fn tokenize(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<TokenizeRequest>,
+) -> Result<Json<Value>, ErrorResponse> {
+    let model = find_model(&state, request.model.as_deref())?;
+    let tokens = model
+        .engine
+        .tokenize(&request.text, request.add_special_tokens)
+        .map_err(|err| error(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    Ok(Json(json!({ "model": model.name, "tokens": tokens, "count": tokens.len() })))
+}
+
+// axum handler for POST /detokenize
+pub async fn detokenize(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<DetokenizeRequest>,
+) -> Result<Json<Value>, ErrorResponse> {
+    let model = find_model(&state, request.model.as_deref())?;
+    let text = model
+        .engine
+        .detokenize(&request.tokens, request.skip_special_tokens)
+        .map_err(|err| error(StatusCode::BAD_REQUEST, err.to_string()))?;
+    Ok(Json(json!({ "model": model.name, "text": text })))
+}
+
+// axum handler for POST /count_tokens, also tells how much of the context window is left for the answer
+pub async fn count_tokens(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<CountTokensRequest>,
+) -> Result<Json<Value>, ErrorResponse> {
+    let model = find_model(&state, request.model.as_deref())?;
+    let input = PromptInput::from_request(&request.prompt, request.messages.as_deref(), &request.tools)
+        .map_err(|message| error(StatusCode::BAD_REQUEST, message))?;
+    let count = model
+        .engine
+        .count_prompt_tokens(&input, &request.tools)
+        .map_err(|err| error(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    let context_length = model.engine.context_length();
+    Ok(Json(json!({
+        "model": model.name,
+        "count": count,
+        "context_length": context_length,
+        "remaining": context_length.saturating_sub(count),
+    })))
+}
+
+fn find_model<'a>(state: &'a AppState, name: Option<&str>) -> Result<&'a LoadedModel, ErrorResponse> {
+    state.models.get(name).ok_or_else(|| {
+        error(
+            StatusCode::NOT_FOUND,
+            format!(
+                "requested model `{}` is unavailable, loaded models: {}",
+                name.unwrap_or_default(),
+                state.models.names().join(", ")
+            ),
+        )
+    })
+}
+
+fn error(status: StatusCode, message: String) -> ErrorResponse {
+    (status, Json(json!({ "error": message })))
+}
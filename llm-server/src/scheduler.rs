@@ -0,0 +1,1158 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+};
+
+use candle_core::Device;
+use serde::Serialize;
+
+use crate::config::ServerConfig;
+use crate::engine::{run_decode_batch, ClientRequestSession, DraftStats, EmbedJob};
+use crate::kv_cache::{BlockBudget, PreemptionMode};
+use crate::model::Llama;
+use crate::prefix_cache::PrefixCache;
+use crate::types::{Priority, MAX_CHOICES};
+
+/*
+ * Scheduler hands out prefill and decode work to a pool of worker threads.
+ * Every worker can run either phase, so no model sits idle while the other has work queued:
+ * - new client request sessions wait in the prefill queue until a worker processes their prompt,
+ *   prompts longer than prefill_chunk_size are prefilled one chunk at a time, with a decode batch
+ *   taken between chunks so running answers do not stall behind a long prompt
+ * - prefilled sessions wait in the decode queue, a worker takes a batch of them, decodes up to
+ *   decode_quantum tokens for each in batched forward passes and puts the unfinished ones back;
+ *   the slice ends after the first token once a prefill is waiting for a worker
+ * Prefill is preferred while no other worker is prefilling so new prompts get a fast first token,
+ * but a second worker only joins in on prefill when there is no decode work waiting. A new prompt
+ * that fits into one chunk goes before the next chunk of a long prompt of the same priority.
+ * Embedding requests are run by a worker in place of a prefill, ahead of the prefill queue.
+ *
+ * Both queues are shared fairly between users rather than first come first served:
+ * - interactive sessions always go before batch sessions
+ * - within a priority class, the user who got the fewest tokens so far goes first, so a user
+ *   with five generations gets the same share of a full decode batch as a user with one
+ * - a user can have at most max_sessions_per_user sessions prefilling or decoding at once,
+ *   the rest wait in the prefill queue
+ * Sessions waiting for prefill are told their position in the queue whenever it changes.
+ *
+ * Admission control keeps load bounded: a request takes a queue slot before it reaches the engine
+ * and holds it until its prefill starts, requests beyond max_queued_prefills are turned away, as
+ * are all requests while the KV cache memory is used up. A prefill only starts while fewer than
+ * max_decode_sessions sessions are running and the KV cache blocks for its prompt are free.
+ * The answers an n / best_of request forks off after its prefill count against max_decode_sessions,
+ * max_sessions_per_user and the free blocks from the moment the request starts, so the request only
+ * starts once there is room for all of them, and requests that could never get that room are
+ * rejected when they are validated (see max_choices).
+ *
+ * Decoding sessions take one more KV cache block every BLOCK_SIZE tokens. When none is left,
+ * cached chat prefixes are evicted first, then the decoding session with the weakest claim
+ * (batch before interactive, then the user who got the most tokens) is preempted: its cache is
+ * swapped out to host memory or dropped to be recomputed (see PreemptionMode). Preempted
+ * sessions come back, in the order they were preempted, before any new prefill of the same or
+ * lower priority starts. An interactive session that cannot start for lack of room or blocks
+ * preempts the batch sessions that have generated the most tokens, so interactive requests keep
+ * a low time to first token under a long running batch load.
+*/
+pub struct Scheduler {
+    state: Mutex<SchedulerState>,
+    cond: Condvar,
+    num_workers: usize,
+    max_decode_batch_size: usize,
+    // limits below are 0 for no limit
+    max_sessions_per_user: usize,
+    max_queued_prefills: usize,
+    max_decode_sessions: usize,
+    // tokens decoded per session before a decode batch goes back to the queue
+    decode_quantum: usize,
+    // max prompt tokens per prefill pass, 0 to prefill whole prompts at once
+    prefill_chunk_size: usize,
+    // KV cache memory budget of the model, in blocks
+    blocks: Arc<BlockBudget>,
+    // host memory budget swapped out caches take their blocks from, None to always recompute
+    swap_blocks: Option<Arc<BlockBudget>>,
+    // cached chat prefixes hold blocks too and are evicted before any session is preempted
+    prefix_cache: Arc<PrefixCache>,
+    // device of the model, swapped out caches are moved back to it
+    device: Device,
+    retry_after_secs: u64,
+    // requests admitted that have not started prefill yet
+    queued: Arc<AtomicUsize>,
+}
+
+// held by an admitted request until its prefill starts (or it fails before that)
+#[derive(Clone)]
+pub struct QueueSlot {
+    // the count goes down once the last clone of the slot is dropped
+    _counter: Arc<QueueSlotCounter>,
+}
+
+struct QueueSlotCounter(Arc<AtomicUsize>);
+
+impl Drop for QueueSlotCounter {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// request turned away at admission
+pub struct Overloaded {
+    pub reason: OverloadReason,
+    pub message: String,
+    pub retry_after_secs: u64,
+}
+
+// the limit a turned away request ran into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverloadReason {
+    // too many requests wait for prefill (429)
+    Queue,
+    // the KV cache memory budget is used up (503)
+    KvCache,
+    // the model's prep threads are too far behind to take the request (503)
+    Intake,
+}
+
+impl OverloadReason {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Queue => "queue_full",
+            Self::KvCache => "kv_cache_full",
+            Self::Intake => "intake_full",
+        }
+    }
+}
+
+// current load of one model, served on /queue
+#[derive(Debug, Serialize)]
+pub struct QueueStats {
+    // admitted requests that have not started prefill yet
+    pub queued: usize,
+    pub max_queued_prefills: usize,
+    pub prefills_running: usize,
+    // sessions part way through a chunked prefill, including the ones running
+    pub prefilling_sessions: usize,
+    pub decode_sessions: usize,
+    pub max_decode_sessions: usize,
+    pub kv_cache_bytes: u64,
+    // 0 without a memory budget
+    pub kv_cache_budget_bytes: u64,
+    pub kv_cache_blocks_used: usize,
+    pub kv_cache_blocks_total: Option<usize>,
+    // sessions swapped out or waiting to be recomputed
+    pub preempted_sessions: usize,
+    // sessions preempted since startup
+    pub preemptions: u64,
+    // tokens a draft model proposed and the main model accepted since startup (speculative decoding)
+    pub draft_tokens_proposed: u64,
+    pub draft_tokens_accepted: u64,
+    // share of proposed tokens that were accepted, None until the draft model proposed any
+    pub draft_acceptance_rate: Option<f64>,
+    // embedding requests waiting for a worker
+    pub embeddings_queued: usize,
+    pub embeddings_running: usize,
+}
+
+// requests without a user share one fair share bucket and are not limited per user
+type UserKey = Option<String>;
+
+struct SchedulerState {
+    prefill_queue: VecDeque<ClientRequestSession>,
+    // running sessions with part of their prompt still to prefill
+    prefill_chunks: VecDeque<ClientRequestSession>,
+    decode_queue: VecDeque<ClientRequestSession>,
+    embed_queue: VecDeque<EmbedJob>,
+    // number of workers currently running a prefill
+    prefills_running: usize,
+    // number of workers currently running an embedding job, which takes the place of a prefill
+    embeds_running: usize,
+    // a prefill chunk ran since the last decode batch was taken
+    decode_turn: bool,
+    // number of workers waiting for work
+    idle_workers: usize,
+    // sessions per user that are prefilling or decoding, plus the answers they will fork off
+    active_sessions: HashMap<UserKey, usize>,
+    // answers still to be forked off by sessions that started, counted in active_sessions ahead of time
+    pending_forks: usize,
+    // tokens generated for each user with sessions in the scheduler, the lowest goes first
+    usage: HashMap<UserKey, u64>,
+    // sessions that lost their KV cache blocks, oldest first
+    preempted: VecDeque<Preempted>,
+    preemptions: u64,
+    // speculative decoding since startup
+    draft_stats: DraftStats,
+}
+
+struct Preempted {
+    session: ClientRequestSession,
+    // the cache is in host memory, otherwise it was dropped and the session has to be prefilled again
+    swapped: bool,
+}
+
+// unit of work a worker takes from the scheduler
+enum Work {
+    Prefill(Box<ClientRequestSession>),
+    Embed(EmbedJob),
+    Decode(Vec<ClientRequestSession>),
+}
+
+impl Scheduler {
+    pub fn new(
+        config: &ServerConfig,
+        blocks: Arc<BlockBudget>,
+        prefix_cache: Arc<PrefixCache>,
+        kv_bytes_per_token: u64,
+        device: Device,
+    ) -> Self {
+        let swap_blocks = (config.preemption == PreemptionMode::Swap)
+            .then(|| Arc::new(BlockBudget::new(config.swap_space_mb << 20, kv_bytes_per_token)));
+        Self {
+            state: Mutex::new(SchedulerState {
+                prefill_queue: VecDeque::new(),
+                prefill_chunks: VecDeque::new(),
+                decode_queue: VecDeque::new(),
+                embed_queue: VecDeque::new(),
+                prefills_running: 0,
+                embeds_running: 0,
+                decode_turn: false,
+                idle_workers: 0,
+                active_sessions: HashMap::new(),
+                pending_forks: 0,
+                usage: HashMap::new(),
+                preempted: VecDeque::new(),
+                preemptions: 0,
+                draft_stats: DraftStats::default(),
+            }),
+            cond: Condvar::new(),
+            num_workers: config.num_workers,
+            max_decode_batch_size: config.max_decode_batch_size,
+            max_sessions_per_user: config.max_sessions_per_user,
+            max_queued_prefills: config.max_queued_prefills,
+            max_decode_sessions: config.max_decode_sessions,
+            decode_quantum: config.decode_quantum,
+            prefill_chunk_size: config.prefill_chunk_size,
+            blocks,
+            swap_blocks,
+            prefix_cache,
+            device,
+            retry_after_secs: config.retry_after_secs,
+            queued: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    // take a queue slot for a new request, or say why the model cannot take it now
+    pub fn admit(&self) -> Result<QueueSlot, Overloaded> {
+        // memory is used up once every block is in use and there are no cached prefixes left to evict
+        let out_of_blocks = self.blocks.free_blocks() == Some(0) && self.prefix_cache.num_blocks() == 0;
+        if out_of_blocks {
+            let preempted = self.state.lock().unwrap().preempted.len();
+            return Err(Overloaded {
+                reason: OverloadReason::KvCache,
+                message: format!(
+                    "KV cache memory is used up ({} of {} blocks in use, {preempted} sessions preempted), retry later",
+                    self.blocks.used_blocks(),
+                    self.blocks.total_blocks().unwrap_or_default()
+                ),
+                retry_after_secs: self.retry_after_secs,
+            });
+        }
+        let queued = self.queued.fetch_add(1, Ordering::SeqCst);
+        let slot = QueueSlot {
+            _counter: Arc::new(QueueSlotCounter(Arc::clone(&self.queued))),
+        };
+        if self.max_queued_prefills > 0 && queued >= self.max_queued_prefills {
+            return Err(Overloaded {
+                reason: OverloadReason::Queue,
+                message: format!(
+                    "too many requests waiting ({queued} of {}), retry later",
+                    self.max_queued_prefills
+                ),
+                retry_after_secs: self.retry_after_secs,
+            });
+        }
+        Ok(slot)
+    }
+
+    pub fn retry_after_secs(&self) -> u64 {
+        self.retry_after_secs
+    }
+
+    // most answers one request can ask for: all of them have to run at once, so a request asking for
+    // more than max_decode_sessions or max_sessions_per_user could never start
+    pub fn max_choices(&self) -> usize {
+        [self.max_decode_sessions, self.max_sessions_per_user]
+            .into_iter()
+            .filter(|&limit| limit > 0)
+            .fold(MAX_CHOICES, usize::min)
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        let state = self.state.lock().unwrap();
+        let active: usize = state.active_sessions.values().sum();
+        let block_bytes = self.blocks.block_bytes();
+        QueueStats {
+            queued: self.queued.load(Ordering::SeqCst),
+            max_queued_prefills: self.max_queued_prefills,
+            prefills_running: state.prefills_running,
+            prefilling_sessions: state.prefills_running + state.prefill_chunks.len(),
+            decode_sessions: active
+                - state.pending_forks
+                - state.prefills_running
+                - state.prefill_chunks.len()
+                - state.preempted.len(),
+            max_decode_sessions: self.max_decode_sessions,
+            kv_cache_bytes: self.blocks.used_blocks() as u64 * block_bytes,
+            kv_cache_budget_bytes: self.blocks.total_blocks().unwrap_or_default() as u64 * block_bytes,
+            kv_cache_blocks_used: self.blocks.used_blocks(),
+            kv_cache_blocks_total: self.blocks.total_blocks(),
+            preempted_sessions: state.preempted.len(),
+            preemptions: state.preemptions,
+            draft_tokens_proposed: state.draft_stats.proposed,
+            draft_tokens_accepted: state.draft_stats.accepted,
+            draft_acceptance_rate: (state.draft_stats.proposed > 0)
+                .then(|| state.draft_stats.accepted as f64 / state.draft_stats.proposed as f64),
+            embeddings_queued: state.embed_queue.len(),
+            embeddings_running: state.embeds_running,
+        }
+    }
+
+    // spawn the worker threads, each one gets its own handle to the model (and its draft model)
+    pub fn start(self: &Arc<Self>, llama: Llama, draft_llama: Option<Llama>) {
+        for worker_id in 0..self.num_workers {
+            let scheduler = Arc::clone(self);
+            // cloning the model only clones tensor handles, so every worker shares the same weights
+            let llama = llama.clone();
+            let draft_llama = draft_llama.clone();
+            thread::Builder::new()
+                .name(format!("inference-worker-{worker_id}"))
+                .spawn(move || scheduler.run_worker(&llama, draft_llama.as_ref()))
+                .expect("failed to spawn inference worker thread");
+        }
+    }
+
+    // queue a new client request session for prefill
+    pub fn submit(&self, session: ClientRequestSession) {
+        let mut state = self.state.lock().unwrap();
+        // a user joining starts level with the least served user, not ahead of everyone
+        let start_usage = state.usage.values().min().copied().unwrap_or(0);
+        state.usage.entry(user_key(&session)).or_insert(start_usage);
+        state.prefill_queue.push_back(session);
+        // an idle worker takes the session right away, otherwise tell the client it has to wait
+        if state.idle_workers == 0 {
+            self.send_queue_positions(&mut state);
+        }
+        self.cond.notify_one();
+    }
+
+    // queue an embedding request for the next worker that could prefill
+    pub fn submit_embedding(&self, job: EmbedJob) {
+        self.state.lock().unwrap().embed_queue.push_back(job);
+        self.cond.notify_one();
+    }
+
+    fn run_worker(&self, llama: &Llama, draft_llama: Option<&Llama>) {
+        loop {
+            match self.next_work() {
+                Work::Embed(job) => {
+                    job.run(llama, self.prefill_chunk_size);
+                    let mut state = self.state.lock().unwrap();
+                    state.embeds_running -= 1;
+                    state.decode_turn = true;
+                    self.cond.notify_all();
+                }
+                Work::Prefill(mut session) => {
+                    let pending_forks = session.pending_forks();
+                    let keep_going = match session.run_prefill(llama, self.prefill_chunk_size) {
+                        Ok(keep_going) => keep_going,
+                        Err(err) => {
+                            session.send_error(err.to_string());
+                            false
+                        }
+                    };
+                    // the other answers of an n / best_of request go straight to decoding, in the room
+                    // kept for them when the request started; answers that ended on their first token
+                    // give theirs back
+                    let forks = session.take_forks();
+                    let forked = pending_forks - session.pending_forks();
+                    let mut state = self.state.lock().unwrap();
+                    state.prefills_running -= 1;
+                    state.decode_turn = true;
+                    state.pending_forks -= forked;
+                    for _ in forks.len()..forked {
+                        state.finish_session(&user_key(&session));
+                    }
+                    state.decode_queue.extend(forks);
+                    if !keep_going {
+                        state.drop_pending_forks(&mut session);
+                        state.finish_session(&user_key(&session));
+                    } else if session.is_prefilled() {
+                        state.decode_queue.push_back(*session);
+                    } else {
+                        state.prefill_chunks.push_back(*session);
+                    }
+                    self.cond.notify_all();
+                }
+                Work::Decode(mut sessions) => {
+                    let users: Vec<UserKey> = sessions.iter().map(user_key).collect();
+                    // decode up to decode_quantum tokens per session before the batch goes back to the
+                    // queue, the slice ends early once a prefill waits for a worker
+                    let mut decoded: Vec<UserKey> = Vec::new();
+                    let mut draft_stats = DraftStats::default();
+                    for step in 0..self.decode_quantum {
+                        if sessions.is_empty() || (step > 0 && self.prefill_waiting()) {
+                            break;
+                        }
+                        decoded.extend(sessions.iter().map(user_key));
+                        let stats = run_decode_batch(llama, draft_llama, &mut sessions);
+                        draft_stats.proposed += stats.proposed;
+                        draft_stats.accepted += stats.accepted;
+                    }
+
+                    let mut state = self.state.lock().unwrap();
+                    state.draft_stats.proposed += draft_stats.proposed;
+                    state.draft_stats.accepted += draft_stats.accepted;
+                    for user in decoded {
+                        *state.usage.entry(user).or_default() += 1;
+                    }
+                    let mut remaining: Vec<UserKey> = sessions.iter().map(user_key).collect();
+                    for user in users {
+                        match remaining.iter().position(|other| *other == user) {
+                            Some(i) => {
+                                remaining.swap_remove(i);
+                            }
+                            None => state.finish_session(&user),
+                        }
+                    }
+                    state.decode_queue.extend(sessions);
+                    self.cond.notify_all();
+                }
+            }
+        }
+    }
+
+    // block until there is prefill or decode work for this worker
+    fn next_work(&self) -> Work {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let has_decode = !state.decode_queue.is_empty();
+            // with decode work waiting only one worker prefills, and after a prefill chunk the decoding
+            // sessions get their slice before the next chunk runs
+            let can_prefill =
+                !has_decode || (state.prefills_running + state.embeds_running == 0 && !state.decode_turn);
+            if can_prefill {
+                if let Some(mut job) = state.embed_queue.pop_front() {
+                    job.leave_queue();
+                    state.embeds_running += 1;
+                    return Work::Embed(job);
+                }
+                if let Some(session) = self.next_prefill(&mut state) {
+                    state.prefills_running += 1;
+                    return Work::Prefill(Box::new(session));
+                }
+            }
+            if let Some(session) = self.resume_preempted(&mut state, can_prefill) {
+                state.prefills_running += 1;
+                return Work::Prefill(Box::new(session));
+            }
+
+            if !state.decode_queue.is_empty() {
+                state.decode_turn = false;
+                // split waiting sessions across workers so idle workers can decode in parallel
+                let share = state.decode_queue.len().div_ceil(self.num_workers);
+                let batch_size = share.clamp(1, self.max_decode_batch_size);
+                return Work::Decode(self.take_decode_batch(&mut state, batch_size));
+            }
+
+            state.idle_workers += 1;
+            state = self.cond.wait(state).unwrap();
+            state.idle_workers -= 1;
+        }
+    }
+
+    // the next prompt chunk to run: sessions in the middle of their prompt take turns chunk by chunk,
+    // a new session goes before them if it has a higher priority, or the same and a prompt that fits
+    // into one chunk, so a short request is not stuck behind a long prompt
+    fn next_prefill(&self, state: &mut SchedulerState) -> Option<ClientRequestSession> {
+        let new_session = self.next_new_session(state);
+        let start_new = match (new_session, state.prefill_chunks.front()) {
+            (Some(i), Some(chunked)) => {
+                let session = &state.prefill_queue[i];
+                session.priority() < chunked.priority()
+                    || (session.priority() == chunked.priority()
+                        && session.prefill_remaining() <= self.prefill_chunk_size)
+            }
+            (new_session, _) => new_session.is_some(),
+        };
+        if let (true, Some(i)) = (start_new, new_session) {
+            return Some(self.start_session(state, i));
+        }
+        let session = state.prefill_chunks.pop_front()?;
+        // the next chunk needs blocks too, which can cost this or another session its place
+        self.grow(state, session)
+    }
+
+    // index of the new session to prefill next, if it can start now
+    // preempted sessions come back before new ones, unless the new one has a higher priority, and an
+    // interactive session waiting for room takes it from the batch session that has run the longest
+    fn next_new_session(&self, state: &mut SchedulerState) -> Option<usize> {
+        loop {
+            let i = *self.prefill_order(state).first()?;
+            let session = &state.prefill_queue[i];
+            let outranks_preempted = state
+                .preempted
+                .iter()
+                .all(|preempted| session.priority() < preempted.session.priority());
+            if !outranks_preempted || !self.user_can_start(state, session) {
+                return None;
+            }
+            if self.fits(state, session) {
+                return Some(i);
+            }
+            if session.priority() != Priority::Interactive {
+                return None;
+            }
+            let victim = (0..state.decode_queue.len())
+                .filter(|&i| state.decode_queue[i].priority() > Priority::Interactive)
+                .max_by_key(|&i| (state.decode_queue[i].tokens_generated(), i))?;
+            let victim = state.decode_queue.remove(victim).unwrap();
+            self.preempt(state, victim);
+        }
+    }
+
+    // take a new session out of the prefill queue, it becomes active with the blocks for its first chunk
+    // and room for the answers it forks off after its prefill
+    fn start_session(&self, state: &mut SchedulerState, i: usize) -> ClientRequestSession {
+        let mut session = state.prefill_queue.remove(i).unwrap();
+        session.leave_queue();
+        // fits made sure the blocks are free or held by cached prefixes
+        let cache_len = self.next_cache_len(&session);
+        if !session.is_cancelled() && self.make_room(session.blocks_needed(cache_len)) {
+            session.reserve_blocks(cache_len);
+        }
+        let user = user_key(&session);
+        *state.active_sessions.entry(user.clone()).or_default() += 1 + session.pending_forks();
+        state.pending_forks += session.pending_forks();
+        *state.usage.entry(user).or_default() += 1;
+        self.send_queue_positions(state);
+        session
+    }
+
+    // a prefill is waiting for a worker, so decoding workers end their slice early
+    fn prefill_waiting(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.idle_workers == 0
+            && (!state.prefill_chunks.is_empty()
+                || !state.embed_queue.is_empty()
+                || state
+                    .prefill_queue
+                    .iter()
+                    .any(|session| self.user_can_start(&state, session) && self.fits(&state, session)))
+    }
+
+    // indices of the prefill queue in the order they will be prefilled, sessions of users at their
+    // session limit come last as they cannot start yet
+    fn prefill_order(&self, state: &SchedulerState) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..state.prefill_queue.len()).collect();
+        order.sort_by_key(|&i| {
+            let session = &state.prefill_queue[i];
+            (
+                !self.user_can_start(state, session),
+                session.priority(),
+                state.usage.get(&user_key(session)).copied().unwrap_or(0),
+                i,
+            )
+        });
+        order
+    }
+
+    // the session's user stays within max_sessions_per_user with the session and its forks
+    fn user_can_start(&self, state: &SchedulerState, session: &ClientRequestSession) -> bool {
+        // cancelled sessions finish without running the model, they never wait for a slot
+        if session.is_cancelled() || self.max_sessions_per_user == 0 || session.user().is_none() {
+            return true;
+        }
+        let active = state.active_sessions.get(&user_key(session)).copied().unwrap_or(0);
+        active + session.pending_forks() < self.max_sessions_per_user
+    }
+
+    // there is room for the session and its forks, and the blocks for its first chunk and the forks'
+    // copies of the prompt are free
+    fn fits(&self, state: &SchedulerState, session: &ClientRequestSession) -> bool {
+        if session.is_cancelled() {
+            return true;
+        }
+        // preempted sessions do not count, they come back once there is room again
+        let active: usize = state.active_sessions.values().sum();
+        let running = active - state.preempted.len() + session.pending_forks();
+        if self.max_decode_sessions > 0 && running >= self.max_decode_sessions {
+            return false;
+        }
+        // the blocks have to be free, or held by cached prefixes that can be evicted
+        let blocks_needed = session.blocks_needed(self.next_cache_len(session)) + session.fork_blocks_needed();
+        match self.blocks.free_blocks() {
+            Some(free) => free + self.prefix_cache.num_blocks() >= blocks_needed,
+            None => true,
+        }
+    }
+
+    // tell every session waiting for prefill its 1-based position in the queue
+    fn send_queue_positions(&self, state: &mut SchedulerState) {
+        for (position, i) in self.prefill_order(state).into_iter().enumerate() {
+            state.prefill_queue[i].send_queued(position + 1);
+        }
+    }
+
+    // tokens in the session's cache after its next prefill chunk or decode slice
+    fn next_cache_len(&self, session: &ClientRequestSession) -> usize {
+        session.next_cache_len(self.prefill_chunk_size, self.decode_quantum)
+    }
+
+    // fair decode batch: repeatedly take the oldest session of the highest priority user who got the
+    // fewest tokens, counting the sessions already picked for this batch
+    fn take_decode_batch(&self, state: &mut SchedulerState, batch_size: usize) -> Vec<ClientRequestSession> {
+        let mut picked: HashMap<UserKey, u64> = HashMap::new();
+        let mut batch = Vec::with_capacity(batch_size);
+        while batch.len() < batch_size && !state.decode_queue.is_empty() {
+            let i = (0..state.decode_queue.len())
+                .min_by_key(|&i| {
+                    let session = &state.decode_queue[i];
+                    let user = user_key(session);
+                    let usage = state.usage.get(&user).copied().unwrap_or(0)
+                        + picked.get(&user).copied().unwrap_or(0);
+                    (session.priority(), usage, i)
+                })
+                .unwrap();
+            let session = state.decode_queue.remove(i).unwrap();
+            // the next tokens may need new blocks, which can cost this or another session its place
+            if let Some(session) = self.grow(state, session) {
+                *picked.entry(user_key(&session)).or_default() += 1;
+                batch.push(session);
+            }
+        }
+        batch
+    }
+
+    // get a running session the blocks for its next decode slice or prefill chunk: evict cached
+    // prefixes first, then preempt the decoding session with the weakest claim, which can be this one
+    // returns the session if it can run
+    fn grow(&self, state: &mut SchedulerState, mut session: ClientRequestSession) -> Option<ClientRequestSession> {
+        loop {
+            // cancelled sessions finish without a forward pass
+            if session.is_cancelled() || session.reserve_blocks(self.next_cache_len(&session)) {
+                return Some(session);
+            }
+            if self.prefix_cache.evict_oldest() {
+                continue;
+            }
+            // batch sessions lose their blocks before interactive ones, then the user with the most tokens
+            let claim = |session: &ClientRequestSession| {
+                let usage = state.usage.get(&user_key(session)).copied().unwrap_or(0);
+                (session.priority(), usage)
+            };
+            let own_claim = claim(&session);
+            let victim = (0..state.decode_queue.len())
+                .filter(|&i| state.decode_queue[i].held_blocks() > 0)
+                .max_by_key(|&i| (claim(&state.decode_queue[i]), i))
+                .filter(|&i| claim(&state.decode_queue[i]) >= own_claim);
+            match victim {
+                Some(i) => {
+                    let victim = state.decode_queue.remove(i).unwrap();
+                    self.preempt(state, victim);
+                }
+                // every block in use is this session's, the budget cannot hold a longer answer
+                None if session.held_blocks() == self.blocks.used_blocks() => {
+                    session.finish_out_of_memory();
+                    state.drop_pending_forks(&mut session);
+                    state.finish_session(&user_key(&session));
+                    return None;
+                }
+                None => {
+                    self.preempt(state, session);
+                    return None;
+                }
+            }
+        }
+    }
+
+    // take a session's blocks away: swap its cache out to host memory if preemption is swap and
+    // there is swap space left, otherwise drop the cache to be recomputed
+    fn preempt(&self, state: &mut SchedulerState, mut session: ClientRequestSession) {
+        state.preemptions += 1;
+        let swapped = match &self.swap_blocks {
+            Some(swap_blocks) => session.swap_out(swap_blocks),
+            None => Ok(false),
+        };
+        match swapped {
+            Ok(swapped) => {
+                if !swapped {
+                    session.drop_cache();
+                }
+                state.preempted.push_back(Preempted { session, swapped });
+            }
+            Err(err) => {
+                session.send_error(err.to_string());
+                state.drop_pending_forks(&mut session);
+                state.finish_session(&user_key(&session));
+            }
+        }
+    }
+
+    // bring preempted sessions back in the order they were preempted once their blocks are free and
+    // there is room for them: swapped sessions are swapped in and carry on, the first one to recompute
+    // is returned for prefill if `can_prefill`
+    fn resume_preempted(&self, state: &mut SchedulerState, can_prefill: bool) -> Option<ClientRequestSession> {
+        while let Some(preempted) = state.preempted.front() {
+            // cancelled sessions finish on their next decode batch, without a forward pass
+            if preempted.session.is_cancelled() {
+                let mut session = state.preempted.pop_front().unwrap().session;
+                state.drop_pending_forks(&mut session);
+                state.decode_queue.push_back(session);
+                continue;
+            }
+            let active: usize = state.active_sessions.values().sum();
+            if self.max_decode_sessions > 0 && active - state.preempted.len() >= self.max_decode_sessions {
+                return None;
+            }
+            let blocks_needed = if preempted.swapped {
+                preempted.session.held_blocks()
+            } else {
+                preempted.session.blocks_needed(self.next_cache_len(&preempted.session))
+            };
+            // an answer that outgrew the whole memory budget cannot come back
+            if self.blocks.total_blocks().is_some_and(|total| blocks_needed > total) {
+                let mut session = state.preempted.pop_front().unwrap().session;
+                session.finish_out_of_memory();
+                state.drop_pending_forks(&mut session);
+                state.finish_session(&user_key(&session));
+                continue;
+            }
+            // recomputing is a prefill, which waits like any other while decode work is queued
+            if (!preempted.swapped && !can_prefill) || !self.make_room(blocks_needed) {
+                return None;
+            }
+            let Preempted { mut session, swapped } = state.preempted.pop_front().unwrap();
+            if !swapped {
+                session.reserve_blocks(self.next_cache_len(&session));
+                return Some(session);
+            }
+            match session.swap_in(&self.blocks, &self.device) {
+                // a session preempted in the middle of its prompt goes on with the next chunk
+                Ok(_) if !session.is_prefilled() => state.prefill_chunks.push_back(session),
+                Ok(_) => state.decode_queue.push_back(session),
+                Err(err) => {
+                    session.send_error(err.to_string());
+                    state.drop_pending_forks(&mut session);
+                    state.finish_session(&user_key(&session));
+                }
+            }
+        }
+        None
+    }
+
+    // evict cached prefixes until `blocks` blocks are free, false if that is not enough
+    fn make_room(&self, blocks: usize) -> bool {
+        loop {
+            match self.blocks.free_blocks() {
+                Some(free) if free < blocks => {
+                    if !self.prefix_cache.evict_oldest() {
+                        return false;
+                    }
+                }
+                _ => return true,
+            }
+        }
+    }
+}
+
+impl SchedulerState {
+    // a session ends before forking off its other answers, give back the room kept for them
+    fn drop_pending_forks(&mut self, session: &mut ClientRequestSession) {
+        let dropped = session.drop_pending_forks();
+        self.pending_forks -= dropped;
+        for _ in 0..dropped {
+            self.finish_session(&user_key(session));
+        }
+    }
+
+    // a session of this user left the scheduler, users without sessions are forgotten
+    fn finish_session(&mut self, user: &UserKey) {
+        if let Some(active) = self.active_sessions.get_mut(user) {
+            *active -= 1;
+            if *active > 0 {
+                return;
+            }
+            self.active_sessions.remove(user);
+        }
+        let queued = self
+            .prefill_queue
+            .iter()
+            .any(|session| user_key(session) == *user);
+        if !queued {
+            self.usage.remove(user);
+        }
+    }
+}
+
+fn user_key(session: &ClientRequestSession) -> UserKey {
+    session.user().map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use candle_transformers::models::llama::Config;
+
+    use super::*;
+    use crate::engine::EventToServer;
+    use crate::kv_cache::{KvCache, BLOCK_SIZE};
+    use crate::prefix_cache::{CachedPrefix, ChatKey};
+
+    fn scheduler(config: ServerConfig) -> Scheduler {
+        scheduler_with_blocks(config, 0)
+    }
+
+    // scheduler whose model has a KV cache budget of `total_blocks` blocks (0 for no limit), one
+    // byte per token so the swap pool is unlimited as well
+    fn scheduler_with_blocks(config: ServerConfig, total_blocks: u64) -> Scheduler {
+        Scheduler::new(
+            &config,
+            Arc::new(BlockBudget::new(total_blocks * BLOCK_SIZE as u64, 1)),
+            Arc::new(PrefixCache::new(4)),
+            1,
+            Device::Cpu,
+        )
+    }
+
+    // queue a session for prefill, the returned receiver has to outlive the test's scheduling
+    fn submit(
+        scheduler: &Scheduler,
+        user: &str,
+        priority: Priority,
+        prompt_tokens: usize,
+    ) -> mpsc::Receiver<EventToServer> {
+        let (session, receiver) = ClientRequestSession::for_test(Some(user), priority, prompt_tokens, &scheduler.blocks);
+        scheduler.submit(session);
+        receiver
+    }
+
+    // queue a session for prefill that forks off `forks` more answers once its prompt is in
+    fn submit_forking(
+        scheduler: &Scheduler,
+        user: &str,
+        prompt_tokens: usize,
+        forks: usize,
+    ) -> mpsc::Receiver<EventToServer> {
+        let (mut session, receiver) =
+            ClientRequestSession::for_test(Some(user), Priority::Interactive, prompt_tokens, &scheduler.blocks);
+        session.forks_for_test(forks);
+        scheduler.submit(session);
+        receiver
+    }
+
+    // running session holding the blocks for its prompt, queued for decoding
+    fn decoding(
+        scheduler: &Scheduler,
+        user: &str,
+        priority: Priority,
+        prompt_tokens: usize,
+    ) -> mpsc::Receiver<EventToServer> {
+        let (mut session, receiver) =
+            ClientRequestSession::for_test(Some(user), priority, prompt_tokens, &scheduler.blocks);
+        session.prefilled_for_test();
+        let mut state = scheduler.state.lock().unwrap();
+        *state.active_sessions.entry(user_key(&session)).or_default() += 1;
+        state.usage.entry(user_key(&session)).or_default();
+        state.decode_queue.push_back(session);
+        receiver
+    }
+
+    // cache a chat prefix holding `blocks` blocks of the scheduler's budget
+    fn cache_prefix(scheduler: &Scheduler, chat_id: i32, blocks: usize) {
+        let mut cache = KvCache::new(&Config::config_7b_v2(false), Arc::clone(&scheduler.blocks));
+        assert!(cache.blocks.grow_to(blocks));
+        let key = ChatKey {
+            username: "alice".to_string(),
+            chat_id,
+        };
+        scheduler.prefix_cache.insert(key, CachedPrefix { tokens: Vec::new(), cache });
+    }
+
+    // users of the sessions in a decode batch
+    fn users(batch: &[ClientRequestSession]) -> Vec<&str> {
+        batch.iter().map(|session| session.user().unwrap()).collect()
+    }
+
+    // user and prompt length of the next session to prefill
+    fn next_prefill(scheduler: &Scheduler) -> Option<(String, usize)> {
+        let mut state = scheduler.state.lock().unwrap();
+        let session = scheduler.next_prefill(&mut state)?;
+        Some((session.user().unwrap().to_string(), session.prefill_remaining()))
+    }
+
+    #[test]
+    fn stats_count_running_embedding_apart_from_sessions() {
+        let scheduler = scheduler(ServerConfig::default());
+        let (job, _result) = EmbedJob::for_test();
+        scheduler.submit_embedding(job);
+        assert!(matches!(scheduler.next_work(), Work::Embed(_)));
+        let stats = scheduler.stats();
+        assert_eq!(stats.embeddings_queued, 0);
+        assert_eq!(stats.embeddings_running, 1);
+        assert_eq!(stats.prefills_running, 0);
+        assert_eq!(stats.prefilling_sessions, 0);
+        assert_eq!(stats.decode_sessions, 0);
+    }
+
+    #[test]
+    fn interactive_goes_before_batch() {
+        let scheduler = scheduler(ServerConfig::default());
+        let _batch = submit(&scheduler, "batch", Priority::Batch, 8);
+        let _interactive = submit(&scheduler, "interactive", Priority::Interactive, 8);
+        assert_eq!(next_prefill(&scheduler), Some(("interactive".to_string(), 8)));
+        assert_eq!(next_prefill(&scheduler), Some(("batch".to_string(), 8)));
+        assert_eq!(next_prefill(&scheduler), None);
+    }
+
+    #[test]
+    fn least_served_user_goes_first() {
+        let scheduler = scheduler(ServerConfig::default());
+        let _first = submit(&scheduler, "alice", Priority::Interactive, 8);
+        let _second = submit(&scheduler, "alice", Priority::Interactive, 8);
+        let _other = submit(&scheduler, "bob", Priority::Interactive, 8);
+        assert_eq!(next_prefill(&scheduler), Some(("alice".to_string(), 8)));
+        // alice's second request came first, but bob got no tokens yet
+        assert_eq!(next_prefill(&scheduler), Some(("bob".to_string(), 8)));
+        assert_eq!(next_prefill(&scheduler), Some(("alice".to_string(), 8)));
+    }
+
+    #[test]
+    fn user_at_session_limit_waits() {
+        let scheduler = scheduler(ServerConfig {
+            max_sessions_per_user: 1,
+            ..ServerConfig::default()
+        });
+        let _first = submit(&scheduler, "alice", Priority::Interactive, 8);
+        assert_eq!(next_prefill(&scheduler), Some(("alice".to_string(), 8)));
+        let _second = submit(&scheduler, "alice", Priority::Interactive, 8);
+        let _other = submit(&scheduler, "bob", Priority::Interactive, 8);
+        assert_eq!(next_prefill(&scheduler), Some(("bob".to_string(), 8)));
+        assert_eq!(next_prefill(&scheduler), None);
+    }
+
+    #[test]
+    fn forks_count_against_the_user_session_limit() {
+        let scheduler = scheduler(ServerConfig {
+            max_sessions_per_user: 3,
+            ..ServerConfig::default()
+        });
+        assert_eq!(scheduler.max_choices(), 3);
+        let _forking = submit_forking(&scheduler, "alice", 8, 2);
+        assert_eq!(next_prefill(&scheduler), Some(("alice".to_string(), 8)));
+        // the two answers still to be forked off take alice's other sessions
+        let _second = submit(&scheduler, "alice", Priority::Interactive, 8);
+        let _other = submit(&scheduler, "bob", Priority::Interactive, 8);
+        assert_eq!(next_prefill(&scheduler), Some(("bob".to_string(), 8)));
+        assert_eq!(next_prefill(&scheduler), None);
+    }
+
+    #[test]
+    fn forks_count_against_max_decode_sessions() {
+        let scheduler = scheduler(ServerConfig {
+            max_decode_sessions: 2,
+            ..ServerConfig::default()
+        });
+        assert_eq!(scheduler.max_choices(), 2);
+        let _forking = submit_forking(&scheduler, "alice", 8, 1);
+        assert_eq!(next_prefill(&scheduler), Some(("alice".to_string(), 8)));
+        // the request and its fork are the two sessions allowed
+        let _other = submit(&scheduler, "bob", Priority::Interactive, 8);
+        assert_eq!(next_prefill(&scheduler), None);
+    }
+
+    #[test]
+    fn forks_need_blocks_for_their_copy_of_the_prompt() {
+        // the prompt takes a block, each fork a block for the prompt and its first token
+        let scheduler = scheduler_with_blocks(ServerConfig::default(), 2);
+        let _forking = submit_forking(&scheduler, "alice", BLOCK_SIZE - 1, 2);
+        assert_eq!(next_prefill(&scheduler), None);
+        let scheduler = scheduler_with_blocks(ServerConfig::default(), 3);
+        let _forking = submit_forking(&scheduler, "alice", BLOCK_SIZE - 1, 2);
+        assert_eq!(next_prefill(&scheduler), Some(("alice".to_string(), BLOCK_SIZE - 1)));
+    }
+
+    #[test]
+    fn finishing_before_forking_gives_back_the_forks_sessions() {
+        let scheduler = scheduler(ServerConfig {
+            max_sessions_per_user: 3,
+            ..ServerConfig::default()
+        });
+        let _forking = submit_forking(&scheduler, "alice", 8, 2);
+        let mut state = scheduler.state.lock().unwrap();
+        let mut session = scheduler.next_prefill(&mut state).unwrap();
+        assert_eq!(state.active_sessions[&user_key(&session)], 3);
+        assert_eq!(state.pending_forks, 2);
+        state.drop_pending_forks(&mut session);
+        state.finish_session(&user_key(&session));
+        assert_eq!(state.active_sessions.get(&user_key(&session)), None);
+        assert_eq!(state.pending_forks, 0);
+    }
+
+    #[test]
+    fn short_prompt_goes_before_next_chunk_of_same_priority() {
+        let scheduler = scheduler(ServerConfig {
+            prefill_chunk_size: 16,
+            ..ServerConfig::default()
+        });
+        let (chunked, _chunked) =
+            ClientRequestSession::for_test(Some("alice"), Priority::Interactive, 100, &scheduler.blocks);
+        scheduler.state.lock().unwrap().prefill_chunks.push_back(chunked);
+        let _short = submit(&scheduler, "bob", Priority::Interactive, 8);
+        let _long = submit(&scheduler, "carol", Priority::Interactive, 40);
+        assert_eq!(next_prefill(&scheduler), Some(("bob".to_string(), 8)));
+        // a new prompt longer than a chunk waits for the running prompt's next chunk
+        assert_eq!(next_prefill(&scheduler), Some(("alice".to_string(), 100)));
+        assert_eq!(next_prefill(&scheduler), Some(("carol".to_string(), 40)));
+    }
+
+    #[test]
+    fn new_interactive_prompt_goes_before_batch_chunk() {
+        let scheduler = scheduler(ServerConfig {
+            prefill_chunk_size: 16,
+            ..ServerConfig::default()
+        });
+        let (chunked, _chunked) =
+            ClientRequestSession::for_test(Some("alice"), Priority::Batch, 100, &scheduler.blocks);
+        scheduler.state.lock().unwrap().prefill_chunks.push_back(chunked);
+        let _long = submit(&scheduler, "bob", Priority::Interactive, 40);
+        assert_eq!(next_prefill(&scheduler), Some(("bob".to_string(), 40)));
+        assert_eq!(next_prefill(&scheduler), Some(("alice".to_string(), 100)));
+    }
+
+    #[test]
+    fn make_room_evicts_cached_prefixes_until_enough_blocks_are_free() {
+        let scheduler = scheduler_with_blocks(ServerConfig::default(), 4);
+        cache_prefix(&scheduler, 1, 2);
+        cache_prefix(&scheduler, 2, 1);
+        assert_eq!(scheduler.blocks.free_blocks(), Some(1));
+
+        assert!(scheduler.make_room(1));
+        assert_eq!(scheduler.prefix_cache.num_blocks(), 3);
+        // the least recently used chat goes first, which is enough here
+        assert!(scheduler.make_room(3));
+        assert_eq!(scheduler.prefix_cache.num_blocks(), 1);
+        assert!(scheduler.make_room(4));
+        assert_eq!(scheduler.blocks.free_blocks(), Some(4));
+        assert!(!scheduler.make_room(5));
+    }
+
+    #[test]
+    fn blocks_go_back_to_the_budget_when_a_session_ends() {
+        let scheduler = scheduler_with_blocks(ServerConfig::default(), 4);
+        let _first = decoding(&scheduler, "alice", Priority::Interactive, BLOCK_SIZE * 2);
+        let _second = decoding(&scheduler, "bob", Priority::Interactive, BLOCK_SIZE);
+        assert_eq!(scheduler.blocks.used_blocks(), 3);
+        let session = scheduler.state.lock().unwrap().decode_queue.pop_front().unwrap();
+        assert_eq!(session.held_blocks(), 2);
+        drop(session);
+        assert_eq!(scheduler.blocks.used_blocks(), 1);
+    }
+
+    #[test]
+    fn decoding_without_free_blocks_preempts_batch_session_to_recompute() {
+        // one token per slice: a prompt of a full block needs a second block for its first token
+        let scheduler = scheduler_with_blocks(ServerConfig::default(), 3);
+        let _batch = decoding(&scheduler, "batch", Priority::Batch, BLOCK_SIZE);
+        let _interactive = decoding(&scheduler, "interactive", Priority::Interactive, BLOCK_SIZE * 2);
+        assert_eq!(scheduler.blocks.free_blocks(), Some(0));
+
+        let mut state = scheduler.state.lock().unwrap();
+        let batch = scheduler.take_decode_batch(&mut state, 2);
+        assert_eq!(users(&batch), vec!["interactive"]);
+        assert_eq!(batch[0].held_blocks(), 3);
+        assert_eq!(state.preemptions, 1);
+        let preempted = state.preempted.front().unwrap();
+        assert!(!preempted.swapped);
+        assert!(!preempted.session.is_prefilled());
+        assert_eq!(preempted.session.held_blocks(), 0);
+
+        // once the interactive answer is done, the batch session prefills prompt and answer again
+        drop(batch);
+        let session = scheduler.resume_preempted(&mut state, true).unwrap();
+        assert_eq!(session.user(), Some("batch"));
+        assert_eq!(session.prefill_remaining(), BLOCK_SIZE + 1);
+        assert_eq!(session.held_blocks(), 2);
+        assert!(state.preempted.is_empty());
+    }
+
+    #[test]
+    fn swap_preemption_moves_the_blocks_to_the_swap_pool_and_back() {
+        let scheduler = scheduler_with_blocks(
+            ServerConfig {
+                preemption: PreemptionMode::Swap,
+                ..ServerConfig::default()
+            },
+            3,
+        );
+        let _batch = decoding(&scheduler, "batch", Priority::Batch, BLOCK_SIZE);
+        let _interactive = decoding(&scheduler, "interactive", Priority::Interactive, BLOCK_SIZE * 2);
+
+        let mut state = scheduler.state.lock().unwrap();
+        let batch = scheduler.take_decode_batch(&mut state, 2);
+        assert_eq!(users(&batch), vec!["interactive"]);
+        let preempted = state.preempted.front().unwrap();
+        assert!(preempted.swapped);
+        assert!(preempted.session.is_prefilled());
+        // the swapped out cache holds its block in the swap pool, not in the model's budget
+        assert_eq!(preempted.session.held_blocks(), 1);
+        assert_eq!(scheduler.swap_blocks.as_ref().unwrap().used_blocks(), 1);
+        assert_eq!(scheduler.blocks.used_blocks(), 3);
+
+        // no room to swap back in while the interactive session holds every block
+        assert!(scheduler.resume_preempted(&mut state, true).is_none());
+        assert_eq!(state.preempted.len(), 1);
+        drop(batch);
+        // swapped in sessions go on decoding without a prefill
+        assert!(scheduler.resume_preempted(&mut state, false).is_none());
+        assert!(state.preempted.is_empty());
+        assert_eq!(users(state.decode_queue.make_contiguous()), vec!["batch"]);
+        assert_eq!(scheduler.blocks.used_blocks(), 1);
+        assert_eq!(scheduler.swap_blocks.as_ref().unwrap().used_blocks(), 0);
+    }
+
+    #[test]
+    fn cached_prefixes_are_evicted_before_any_session_is_preempted() {
+        let scheduler = scheduler_with_blocks(ServerConfig::default(), 3);
+        let _batch = decoding(&scheduler, "batch", Priority::Batch, BLOCK_SIZE);
+        let _interactive = decoding(&scheduler, "interactive", Priority::Interactive, BLOCK_SIZE);
+        cache_prefix(&scheduler, 1, 1);
+
+        let mut state = scheduler.state.lock().unwrap();
+        let batch = scheduler.take_decode_batch(&mut state, 1);
+        assert_eq!(users(&batch), vec!["interactive"]);
+        assert_eq!(scheduler.prefix_cache.num_blocks(), 0);
+        assert_eq!(state.preemptions, 0);
+    }
+
+    #[test]
+    fn answer_that_outgrows_the_whole_budget_finishes() {
+        let scheduler = scheduler_with_blocks(ServerConfig::default(), 1);
+        let mut receiver = decoding(&scheduler, "alice", Priority::Interactive, BLOCK_SIZE);
+
+        let mut state = scheduler.state.lock().unwrap();
+        assert!(scheduler.take_decode_batch(&mut state, 1).is_empty());
+        assert_eq!(state.preemptions, 0);
+        assert!(state.active_sessions.is_empty());
+        let mut events = Vec::new();
+        while let Ok(event) = receiver.try_recv() {
+            events.push(event);
+        }
+        assert!(
+            matches!(events.last(), Some(EventToServer::Done { .. })),
+            "expected the answer to end, got {events:?}"
+        );
+    }
+}
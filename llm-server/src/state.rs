@@ -1,23 +1,67 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
-use std::sync::mpsc::Sender;
+use std::time::Duration;
 
-use crate::engine::ClientRequest;
+use crate::engine::CancellationToken;
+use crate::registry::ModelRegistry;
+use crate::stream_buffer::StreamBuffer;
 
 pub struct AppState {
     pub db_conn: Arc<Mutex<rusqlite::Connection>>,
-    // channel sender to send client /generate requests into the engine worker thread
-    pub client_request_sender: Sender<ClientRequest>,
+    // loaded models, /generate requests are sent into the engine thread of the requested one
+    pub models: ModelRegistry,
+    // in-flight /generate requests by request id, so they can be cancelled
+    pub active_requests: Mutex<HashMap<u64, ActiveRequest>>,
+    // events of streamed requests by request id, so clients can resume them after a disconnect
+    pub streams: Mutex<HashMap<u64, Arc<StreamBuffer>>>,
+    // how long a disconnected stream is kept running and buffered
+    pub stream_resume_grace: Duration,
+    next_request_id: AtomicU64,
+}
+
+pub struct ActiveRequest {
+    // username of the request, the only one allowed to cancel it (none for OpenAI requests
+    // without a `user`, those cannot be cancelled by id)
+    pub owner: Option<String>,
+    pub cancel_token: CancellationToken,
+}
+
+impl ActiveRequest {
+    pub fn is_owned_by(&self, username: &str) -> bool {
+        self.owner.as_deref() == Some(username)
+    }
 }
 
 impl AppState {
     pub fn new(
         db_conn: rusqlite::Connection,
-        client_request_sender: Sender<ClientRequest>,
+        models: ModelRegistry,
+        stream_resume_grace: Duration,
     ) -> Self {
         Self {
             db_conn: Arc::new(Mutex::new(db_conn)),
-            client_request_sender,
+            models,
+            active_requests: Mutex::new(HashMap::new()),
+            streams: Mutex::new(HashMap::new()),
+            stream_resume_grace,
+            next_request_id: AtomicU64::new(1),
         }
     }
-}
 
+    // register a new in-flight request and return its id and cancellation token
+    pub fn register_request(&self, owner: Option<&str>) -> (u64, CancellationToken) {
+        let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        let cancel_token = CancellationToken::default();
+        let request = ActiveRequest {
+            owner: owner.map(str::to_string),
+            cancel_token: cancel_token.clone(),
+        };
+        self.active_requests.lock().unwrap().insert(request_id, request);
+        (request_id, cancel_token)
+    }
+
+    pub fn finish_request(&self, request_id: u64) {
+        self.active_requests.lock().unwrap().remove(&request_id);
+    }
+}
@@ -0,0 +1,136 @@
+use tokenizers::Tokenizer;
+
+/*
+ * When a generation ends before max_tokens:
+ * - on one of the model's end of sequence tokens (finish_reason "eos")
+ * - on a stop token, e.g. a chat template turn marker that is a single token (finish_reason "stop")
+ * - when a stop sequence shows up in the decoded text (finish_reason "stop")
+ * Stop tokens and stop sequences are never sent to the client. Decoded text that could still turn
+ * into a stop sequence (e.g. "<|us" while waiting to see if "<|user|>" follows) is held back until
+ * the next tokens decide it.
+*/
+#[derive(Debug, Clone, Default)]
+pub struct StopConditions {
+    pub eos_token_ids: Vec<u32>,
+    pub stop_token_ids: Vec<u32>,
+    pub stop_sequences: Vec<String>,
+}
+
+// outcome of adding decoded text to the held back text
+#[derive(Debug, PartialEq)]
+pub enum StopScan {
+    // no stop sequence yet, this text can be sent to the client
+    Continue(String),
+    // a stop sequence was found, this is the text before it
+    Stop(String),
+}
+
+impl StopConditions {
+    // model defaults: end of sequence tokens plus the chat template's turn markers,
+    // markers the tokenizer knows as a single token become stop tokens, the others stop sequences
+    pub fn new(tokenizer: &Tokenizer, eos_token_ids: Vec<u32>, turn_markers: &[&str]) -> Self {
+        let mut stop = Self {
+            eos_token_ids,
+            ..Self::default()
+        };
+        for marker in turn_markers {
+            match tokenizer.token_to_id(marker) {
+                Some(id) if !stop.eos_token_ids.contains(&id) => stop.stop_token_ids.push(id),
+                Some(_) => {}
+                None => stop.stop_sequences.push(marker.to_string()),
+            }
+        }
+        stop
+    }
+
+    // model defaults plus what the client asked for
+    pub fn with_request(&self, stop_sequences: &[String], stop_token_ids: &[u32]) -> Self {
+        let mut stop = self.clone();
+        stop.stop_sequences.extend(stop_sequences.iter().cloned());
+        stop.stop_token_ids.extend_from_slice(stop_token_ids);
+        stop
+    }
+
+    // add newly decoded text to the held back text and take out whatever is decided
+    pub fn scan(&self, pending: &mut String, text: &str) -> StopScan {
+        pending.push_str(text);
+
+        // the earliest complete stop sequence ends the answer
+        if let Some(position) = self
+            .stop_sequences
+            .iter()
+            .filter_map(|stop| pending.find(stop.as_str()))
+            .min()
+        {
+            pending.truncate(position);
+            return StopScan::Stop(std::mem::take(pending));
+        }
+
+        // keep back the longest tail that is the start of a stop sequence
+        let held = self
+            .stop_sequences
+            .iter()
+            .map(|stop| partial_match_len(pending, stop))
+            .max()
+            .unwrap_or(0);
+        let ready: String = pending.drain(..pending.len() - held).collect();
+        StopScan::Continue(ready)
+    }
+}
+
+// length of the longest suffix of text that is a proper prefix of stop
+pub fn partial_match_len(text: &str, stop: &str) -> usize {
+    (1..stop.len())
+        .rev()
+        .filter(|&len| stop.is_char_boundary(len))
+        .find(|&len| text.ends_with(&stop[..len]))
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stop(sequences: &[&str]) -> StopConditions {
+        StopConditions {
+            stop_sequences: sequences.iter().map(|stop| stop.to_string()).collect(),
+            ..StopConditions::default()
+        }
+    }
+
+    #[test]
+    fn partial_match_is_flushed_once_ruled_out() {
+        let stop = stop(&["<|user|>"]);
+        let mut pending = String::new();
+        assert_eq!(stop.scan(&mut pending, "Hello <|us"), StopScan::Continue("Hello ".to_string()));
+        assert_eq!(pending, "<|us");
+        assert_eq!(stop.scan(&mut pending, "e"), StopScan::Continue(String::new()));
+        assert_eq!(stop.scan(&mut pending, "d to it"), StopScan::Continue("<|used to it".to_string()));
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn partial_match_completed_by_later_text_stops() {
+        let stop = stop(&["<|user|>"]);
+        let mut pending = String::new();
+        assert_eq!(stop.scan(&mut pending, "Hi <|us"), StopScan::Continue("Hi ".to_string()));
+        assert_eq!(stop.scan(&mut pending, "er|> more"), StopScan::Stop(String::new()));
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn longest_partial_match_is_held_back() {
+        let stop = stop(&["END", "\n\nQ:"]);
+        let mut pending = String::new();
+        assert_eq!(stop.scan(&mut pending, "answer\n\n"), StopScan::Continue("answer".to_string()));
+        assert_eq!(pending, "\n\n");
+        assert_eq!(stop.scan(&mut pending, "Q"), StopScan::Continue(String::new()));
+        assert_eq!(stop.scan(&mut pending, ": next"), StopScan::Stop(String::new()));
+    }
+
+    #[test]
+    fn partial_match_respects_char_boundaries() {
+        assert_eq!(partial_match_len("café", "é!"), "é".len());
+        assert_eq!(partial_match_len("caf", "é!"), 0);
+    }
+}
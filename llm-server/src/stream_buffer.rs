@@ -0,0 +1,154 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use tokio::sync::mpsc;
+
+// events a client's connection can fall behind by, a client further behind is dropped and can
+// resume from the last event it got
+const SUBSCRIBER_CAPACITY: usize = 256;
+
+// events kept per stream, older ones are dropped; one event is a token (a few hundred bytes, up to a
+// few KB with 20 top logprobs), so a buffer stays below about 10 MB
+const MAX_BUFFERED_EVENTS: usize = 4096;
+
+/*
+ * Events of one streamed /generate request, kept so a client whose connection dropped can pick
+ * the answer up again. Every SSE event gets the index of its payload here as its id; a client
+ * reconnecting through GET /generate/{request_id}/stream?username=... with the Last-Event-ID
+ * header gets every event after that id replayed, followed by the live ones. Request ids are
+ * sequential, so only the user who sent the request can resume it. Only the last
+ * MAX_BUFFERED_EVENTS events are kept, a client that missed older ones cannot resume and has to
+ * fetch the stored answer instead. The buffer outlives the generation by the resume grace period,
+ * and a generation whose clients are all gone for longer than that is cancelled.
+*/
+pub struct StreamBuffer {
+    // username of the request, the only one allowed to resume the stream
+    owner: String,
+    events: Mutex<BufferedEvents>,
+}
+
+// event id and json payload
+pub type BufferedEvent = (u64, String);
+
+#[derive(Default)]
+struct BufferedEvents {
+    // json payloads of the last MAX_BUFFERED_EVENTS events, an event's id is its index counting
+    // the dropped ones
+    payloads: VecDeque<String>,
+    // events dropped from the front of `payloads`
+    dropped: u64,
+    // connected clients, each one gets every new event
+    subscribers: Vec<mpsc::Sender<BufferedEvent>>,
+    // generation ended, no events follow
+    finished: bool,
+}
+
+impl StreamBuffer {
+    pub fn new(owner: String) -> Self {
+        Self {
+            owner,
+            events: Mutex::default(),
+        }
+    }
+
+    pub fn is_owned_by(&self, username: &str) -> bool {
+        self.owner == username
+    }
+
+    // store the event and send it to every connected client that keeps up
+    pub fn push(&self, payload: String) {
+        let mut events = self.events.lock().unwrap();
+        let id = events.dropped + events.payloads.len() as u64;
+        events
+            .subscribers
+            .retain(|subscriber| subscriber.try_send((id, payload.clone())).is_ok());
+        if events.payloads.len() == MAX_BUFFERED_EVENTS {
+            events.payloads.pop_front();
+            events.dropped += 1;
+        }
+        events.payloads.push_back(payload);
+    }
+
+    // no more events, closes the stream of every connected client
+    pub fn finish(&self) {
+        let mut events = self.events.lock().unwrap();
+        events.finished = true;
+        events.subscribers.clear();
+    }
+
+    // events after `last_event_id` (all of them without one) and a receiver for those still to come,
+    // the receiver is closed right away once the generation has ended; none if some of the events
+    // after `last_event_id` were already dropped
+    pub fn subscribe(&self, last_event_id: Option<u64>) -> Option<(Vec<BufferedEvent>, mpsc::Receiver<BufferedEvent>)> {
+        let mut events = self.events.lock().unwrap();
+        let first = last_event_id.map_or(0, |id| id + 1);
+        if first < events.dropped {
+            return None;
+        }
+        let missed = events
+            .payloads
+            .iter()
+            .zip(events.dropped..)
+            .skip((first - events.dropped) as usize)
+            .map(|(payload, id)| (id, payload.clone()))
+            .collect();
+        let (sender, receiver) = mpsc::channel(SUBSCRIBER_CAPACITY);
+        if !events.finished {
+            events.subscribers.push(sender);
+        }
+        Some((missed, receiver))
+    }
+
+    // whether any client is still listening
+    pub fn connected(&self) -> bool {
+        let mut events = self.events.lock().unwrap();
+        events.subscribers.retain(|subscriber| !subscriber.is_closed());
+        !events.subscribers.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(events: &[BufferedEvent]) -> Vec<u64> {
+        events.iter().map(|(id, _)| *id).collect()
+    }
+
+    #[test]
+    fn resume_replays_the_events_after_last_event_id_then_goes_live() {
+        let buffer = StreamBuffer::new("alice".to_string());
+        for token in ["a", "b", "c"] {
+            buffer.push(token.to_string());
+        }
+        let (missed, mut live) = buffer.subscribe(Some(0)).unwrap();
+        assert_eq!(missed, vec![(1, "b".to_string()), (2, "c".to_string())]);
+
+        buffer.push("d".to_string());
+        assert_eq!(live.try_recv().unwrap(), (3, "d".to_string()));
+        buffer.finish();
+        assert!(live.try_recv().is_err());
+        assert!(!buffer.connected());
+
+        // a client that got everything before the end only learns that the stream is over
+        let (missed, mut live) = buffer.subscribe(Some(3)).unwrap();
+        assert!(missed.is_empty());
+        assert!(live.blocking_recv().is_none());
+    }
+
+    #[test]
+    fn dropped_events_cannot_be_resumed() {
+        let buffer = StreamBuffer::new("alice".to_string());
+        for token in 0..MAX_BUFFERED_EVENTS + 2 {
+            buffer.push(token.to_string());
+        }
+        assert!(buffer.subscribe(None).is_none());
+        // event 2 is the oldest one kept
+        assert!(buffer.subscribe(Some(0)).is_none());
+        let (missed, _) = buffer.subscribe(Some(1)).unwrap();
+        assert_eq!(missed.len(), MAX_BUFFERED_EVENTS);
+        assert_eq!(missed[0], (2, "2".to_string()));
+        let (missed, _) = buffer.subscribe(Some(MAX_BUFFERED_EVENTS as u64)).unwrap();
+        assert_eq!(ids(&missed), vec![MAX_BUFFERED_EVENTS as u64 + 1]);
+    }
+}
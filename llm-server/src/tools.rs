@@ -0,0 +1,233 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::stop::partial_match_len;
+use crate::types::{ChatMessage, Role, Tool, ToolCall};
+
+/*
+ * Tool calling in the format of Hermes / Qwen function calling models:
+ * - the tools of a request are listed in the system prompt, with instructions to answer a call
+ *   as <tool_call>{"name": ..., "arguments": {...}}</tool_call>
+ * - the decoded answer is scanned for those blocks, each one becomes a tool_call event instead of
+ *   text, and the answer finishes with finish_reason "tool_calls"
+ * - the client runs the tools and sends the results back as role "tool" messages, which are
+ *   rendered as <tool_response> blocks in a user turn
+*/
+pub const TOOL_CALL_START: &str = "<tool_call>";
+pub const TOOL_CALL_END: &str = "</tool_call>";
+
+const TOOLS_INSTRUCTION: &str = "You may call one or more of the following functions to answer the user.";
+const TOOL_CALL_INSTRUCTION: &str = "For each function call, answer with a JSON object with the \
+function name and arguments within <tool_call></tool_call> tags:\n<tool_call>\n\
+{\"name\": <function-name>, \"arguments\": <args-json-object>}\n</tool_call>";
+
+static NEXT_CALL_ID: AtomicU64 = AtomicU64::new(0);
+
+// the messages as the chat template sees them: the tool descriptions added to the system prompt,
+// tool calls written out the way the model is asked to write them and tool results as user turns
+pub fn template_messages(messages: &[ChatMessage], tools: &[Tool]) -> Vec<ChatMessage> {
+    let mut rendered: Vec<ChatMessage> = messages
+        .iter()
+        .map(|message| match message.role {
+            Role::Tool => ChatMessage::new(
+                Role::User,
+                format!("<tool_response>\n{}\n</tool_response>", message.content),
+            ),
+            _ if !message.tool_calls.is_empty() => {
+                let mut content = message.content.clone();
+                for call in &message.tool_calls {
+                    let call = json!({ "name": call.name, "arguments": call.arguments });
+                    content.push_str(&format!("\n{TOOL_CALL_START}\n{call}\n{TOOL_CALL_END}"));
+                }
+                ChatMessage::new(message.role, content.trim_start().to_string())
+            }
+            _ => ChatMessage::new(message.role, message.content.clone()),
+        })
+        .collect();
+    if tools.is_empty() {
+        return rendered;
+    }
+
+    let descriptions: String = tools
+        .iter()
+        .map(|tool| {
+            let function = json!({
+                "type": "function",
+                "function": {
+                    "name": tool.name,
+                    "description": tool.description,
+                    "parameters": tool.parameters,
+                },
+            });
+            format!("{function}\n")
+        })
+        .collect();
+    let tools_prompt = format!("{TOOLS_INSTRUCTION}\n<tools>\n{descriptions}</tools>\n{TOOL_CALL_INSTRUCTION}");
+    match rendered.first_mut() {
+        Some(system) if system.role == Role::System => {
+            system.content = format!("{}\n\n{tools_prompt}", system.content);
+        }
+        _ => rendered.insert(0, ChatMessage::new(Role::System, tools_prompt)),
+    }
+    rendered
+}
+
+// piece of a scanned answer
+pub enum ToolScan {
+    // text outside of tool calls, sent to the client as is
+    Text(String),
+    Call(ToolCall),
+}
+
+// finds tool call blocks in the decoded answer, text that could be the start of one is held back
+#[derive(Debug, Clone, Default)]
+pub struct ToolCallParser {
+    // held back text, or the body of the open tool call
+    pending: String,
+    in_call: bool,
+    calls: usize,
+}
+
+impl ToolCallParser {
+    // add newly decoded text and take out whatever is decided
+    pub fn scan(&mut self, text: &str) -> Vec<ToolScan> {
+        self.pending.push_str(text);
+        let mut scanned = Vec::new();
+        loop {
+            if self.in_call {
+                let Some(end) = self.pending.find(TOOL_CALL_END) else {
+                    break;
+                };
+                let body: String = self.pending.drain(..end + TOOL_CALL_END.len()).collect();
+                self.in_call = false;
+                scanned.push(self.parse_call(&body[..end], true));
+            } else if let Some(start) = self.pending.find(TOOL_CALL_START) {
+                if start > 0 {
+                    scanned.push(ToolScan::Text(self.pending.drain(..start).collect()));
+                }
+                self.pending.drain(..TOOL_CALL_START.len());
+                self.in_call = true;
+            } else {
+                let held = partial_match_len(&self.pending, TOOL_CALL_START);
+                let ready: String = self.pending.drain(..self.pending.len() - held).collect();
+                if !ready.is_empty() {
+                    scanned.push(ToolScan::Text(ready));
+                }
+                break;
+            }
+        }
+        scanned
+    }
+
+    // the answer ended: a call whose end tag is missing still counts if its JSON is complete
+    pub fn finish(&mut self) -> Vec<ToolScan> {
+        let pending = std::mem::take(&mut self.pending);
+        if std::mem::take(&mut self.in_call) {
+            vec![self.parse_call(&pending, false)]
+        } else if pending.is_empty() {
+            Vec::new()
+        } else {
+            vec![ToolScan::Text(pending)]
+        }
+    }
+
+    // number of tool calls found so far
+    pub fn calls(&self) -> usize {
+        self.calls
+    }
+
+    // a block that is not a call the client could run is passed on as the text the model wrote
+    fn parse_call(&mut self, body: &str, closed: bool) -> ToolScan {
+        #[derive(Deserialize)]
+        struct CallBody {
+            name: String,
+            #[serde(default)]
+            arguments: Value,
+        }
+        match serde_json::from_str::<CallBody>(body.trim()) {
+            Ok(call) => {
+                self.calls += 1;
+                ToolScan::Call(ToolCall {
+                    id: next_call_id(),
+                    name: call.name,
+                    arguments: call.arguments,
+                })
+            }
+            Err(_) if closed => ToolScan::Text(format!("{TOOL_CALL_START}{body}{TOOL_CALL_END}")),
+            Err(_) => ToolScan::Text(format!("{TOOL_CALL_START}{body}")),
+        }
+    }
+}
+
+// ids only have to be unique within a chat, the time keeps ids of different server runs apart
+fn next_call_id() -> String {
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    format!("call_{started:x}{:04}", NEXT_CALL_ID.fetch_add(1, Ordering::Relaxed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // text of scanned pieces, a call is written as `call:<name>`
+    fn pieces(scanned: Vec<ToolScan>) -> Vec<String> {
+        scanned
+            .into_iter()
+            .map(|piece| match piece {
+                ToolScan::Text(text) => text,
+                ToolScan::Call(call) => format!("call:{}", call.name),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn malformed_call_is_passed_on_as_text() {
+        let mut parser = ToolCallParser::default();
+        let scanned = parser.scan("Sure <tool_call>{\"name\": oops}</tool_call> done");
+        assert_eq!(
+            pieces(scanned),
+            ["Sure ", "<tool_call>{\"name\": oops}</tool_call>", " done"]
+        );
+        assert!(parser.finish().is_empty());
+        assert_eq!(parser.calls(), 0);
+    }
+
+    #[test]
+    fn call_without_a_name_is_passed_on_as_text() {
+        let mut parser = ToolCallParser::default();
+        let scanned = parser.scan("<tool_call>{\"arguments\": {}}</tool_call>");
+        assert_eq!(pieces(scanned), ["<tool_call>{\"arguments\": {}}</tool_call>"]);
+        assert_eq!(parser.calls(), 0);
+    }
+
+    #[test]
+    fn unclosed_incomplete_call_is_passed_on_as_text() {
+        let mut parser = ToolCallParser::default();
+        assert!(parser.scan("<tool_call>{\"name\": \"get_").is_empty());
+        assert_eq!(pieces(parser.finish()), ["<tool_call>{\"name\": \"get_"]);
+        assert_eq!(parser.calls(), 0);
+    }
+
+    #[test]
+    fn call_split_across_scans_is_parsed() {
+        let mut parser = ToolCallParser::default();
+        assert_eq!(pieces(parser.scan("Let me check.<tool_")), ["Let me check."]);
+        assert!(parser.scan("call>{\"name\": \"weather\", ").is_empty());
+        let scanned = parser.scan("\"arguments\": {\"city\": \"Oslo\"}}</tool_call>");
+        assert_eq!(pieces(scanned), ["call:weather"]);
+        assert_eq!(parser.calls(), 1);
+    }
+
+    #[test]
+    fn unclosed_complete_call_counts_at_finish() {
+        let mut parser = ToolCallParser::default();
+        assert!(parser.scan("<tool_call>{\"name\": \"weather\"}").is_empty());
+        assert_eq!(pieces(parser.finish()), ["call:weather"]);
+        assert_eq!(parser.calls(), 1);
+    }
+}
@@ -1,12 +1,29 @@
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
 
 const DEFAULT_MAX_TOKENS: usize = 256;
+const DEFAULT_TEMPERATURE: f64 = 0.8;
+const DEFAULT_TOP_P: f64 = 0.95;
+const DEFAULT_SEED: u64 = 13;
+const DEFAULT_REPEAT_PENALTY: f32 = 1.1;
+const DEFAULT_REPEAT_LAST_N: usize = 64;
+// most alternatives a client can ask for per token
+pub const MAX_LOGPROBS: usize = 20;
+// most answers (n or best_of) a client can ask for per request
+pub const MAX_CHOICES: usize = 16;
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct GenerateRequest {
-    // text prompt to feed into the model
+    #[serde(default)]
+    // text prompt to feed into the model as is (already in the model's chat format)
     pub prompt: String,
     #[serde(default)]
+    // structured chat history, rendered with the model's chat template on the server (instead of prompt)
+    pub messages: Option<Vec<ChatMessage>>,
+    #[serde(default)]
+    // tools the model can call, described to it through the chat template (needs messages)
+    pub tools: Vec<Tool>,
+    #[serde(default)]
     // current user message without system prompt/history
     pub user_message: String,
     #[serde(default)]
@@ -19,8 +36,265 @@ pub struct GenerateRequest {
     #[serde(default)]
     pub username: String,
 
+    #[serde(default)]
+    // interactive requests are scheduled before batch requests
+    pub priority: Priority,
+
     #[serde(default)]
     pub chat_id: Option<i32>,
+
+    #[serde(default = "default_stream")]
+    // false answers with one JSON body once generation is done instead of streaming SSE events
+    pub stream: bool,
+}
+
+fn default_stream() -> bool {
+    true
+}
+
+// scheduling class of a request, interactive requests always go first
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    #[default]
+    Interactive,
+    Batch,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+    // result of a tool call, sent back by the client
+    Tool,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::System => "system",
+            Role::User => "user",
+            Role::Assistant => "assistant",
+            Role::Tool => "tool",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChatMessage {
+    pub role: Role,
+    // null for assistant messages that only call tools
+    #[serde(default, deserialize_with = "null_as_empty")]
+    pub content: String,
+    // tools an assistant message called
+    #[serde(default)]
+    pub tool_calls: Vec<ToolCall>,
+    // call a tool message answers
+    #[serde(default)]
+    pub tool_call_id: Option<String>,
+}
+
+impl ChatMessage {
+    pub fn new(role: Role, content: String) -> Self {
+        Self {
+            role,
+            content,
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+        }
+    }
+}
+
+fn null_as_empty<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    Ok(Option::<String>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+// function the model can call, given as {name, description, parameters} or in the OpenAI shape
+// {"type": "function", "function": {name, description, parameters}}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "ToolField")]
+pub struct Tool {
+    pub name: String,
+    pub description: String,
+    // JSON schema of the arguments
+    pub parameters: Value,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ToolField {
+    OpenAi { function: ToolFunction },
+    Flat(ToolFunction),
+}
+
+#[derive(Deserialize)]
+struct ToolFunction {
+    name: String,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    parameters: Value,
+}
+
+impl From<ToolField> for Tool {
+    fn from(field: ToolField) -> Self {
+        let (ToolField::OpenAi { function } | ToolField::Flat(function)) = field;
+        Self {
+            name: function.name,
+            description: function.description,
+            parameters: function.parameters,
+        }
+    }
+}
+
+// call of a tool by the model, given back in later turns as {id, name, arguments} or in the OpenAI
+// shape {"id", "type": "function", "function": {name, arguments}} with the arguments as a JSON string
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "ToolCallField")]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub arguments: Value,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ToolCallField {
+    OpenAi { id: String, function: ToolCallFunction },
+    Flat { id: String, name: String, #[serde(default)] arguments: Value },
+}
+
+#[derive(Deserialize)]
+struct ToolCallFunction {
+    name: String,
+    arguments: String,
+}
+
+impl From<ToolCallField> for ToolCall {
+    fn from(field: ToolCallField) -> Self {
+        match field {
+            ToolCallField::OpenAi { id, function } => Self {
+                id,
+                name: function.name,
+                // arguments that are not valid JSON are kept as the string the model wrote
+                arguments: serde_json::from_str(&function.arguments)
+                    .unwrap_or(Value::String(function.arguments)),
+            },
+            ToolCallField::Flat { id, name, arguments } => Self { id, name, arguments },
+        }
+    }
+}
+
+// one text to embed, given as text or as token ids
+#[derive(Debug, Clone)]
+pub enum EmbedInput {
+    Text(String),
+    Tokens(Vec<u32>),
+}
+
+// what the engine turns into prompt tokens
+#[derive(Debug, Clone)]
+pub enum PromptInput {
+    // raw prompt text, used as is
+    Text(String),
+    // chat messages, rendered with the model's chat template
+    Messages(Vec<ChatMessage>),
+}
+
+impl PromptInput {
+    // exactly one of prompt / messages has to be given
+    pub fn from_request(prompt: &str, messages: Option<&[ChatMessage]>, tools: &[Tool]) -> Result<Self, String> {
+        match messages {
+            Some(_) if !prompt.is_empty() => {
+                Err("only one of prompt and messages can be given".to_string())
+            }
+            Some([]) => Err("messages must not be empty".to_string()),
+            Some(messages) => Ok(Self::Messages(messages.to_vec())),
+            // a raw prompt has no chat template to describe the tools with
+            None if !tools.is_empty() => {
+                Err("tools can only be used with messages".to_string())
+            }
+            None if prompt.trim().is_empty() => {
+                Err("prompt must not be empty".to_string())
+            }
+            None => Ok(Self::Text(prompt.to_string())),
+        }
+    }
+}
+
+impl GenerateRequest {
+    pub fn prompt_input(&self) -> Result<PromptInput, String> {
+        PromptInput::from_request(&self.prompt, self.messages.as_deref(), &self.tools)
+    }
+
+    // tool results the client sends back after the model's last answer, stored in place of a user message
+    pub fn tool_results(&self) -> &[ChatMessage] {
+        let messages = self.messages.as_deref().unwrap_or_default();
+        let start = messages
+            .iter()
+            .rposition(|message| message.role != Role::Tool)
+            .map_or(0, |index| index + 1);
+        &messages[start..]
+    }
+
+    // message to store in the chat history: the explicit user_message, or the last user message
+    pub fn stored_user_message(&self) -> String {
+        if !self.user_message.is_empty() {
+            return self.user_message.clone();
+        }
+        self.messages
+            .iter()
+            .flatten()
+            .rev()
+            .find(|message| message.role == Role::User)
+            .map(|message| message.content.clone())
+            .unwrap_or_default()
+    }
+}
+
+// what to do when the prompt plus max_tokens does not fit into the model's context window
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    // fail the request with a context_overflow error
+    Reject,
+    // drop the oldest chat turns (system prompt is kept) until the prompt fits
+    DropOldest,
+    // replace the oldest chat turns with a model-written summary of them
+    Summarize,
+}
+
+impl OverflowPolicy {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "reject" => Some(Self::Reject),
+            "drop_oldest" => Some(Self::DropOldest),
+            "summarize" => Some(Self::Summarize),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Reject => "reject",
+            Self::DropOldest => "drop_oldest",
+            Self::Summarize => "summarize",
+        }
+    }
+}
+
+// shape the answer has to take, enforced while sampling
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    // free text, same as not setting a format
+    Text,
+    // any JSON object
+    JsonObject,
+    // JSON matching the given JSON schema
+    JsonSchema { schema: Value },
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -28,12 +302,151 @@ pub struct GenerateRequest {
 pub struct GenerationParams {
     // max tokens to be generated
     pub max_tokens: usize,
+    // softmax temperature, 0 falls back to greedy decoding
+    pub temperature: f64,
+    // nucleus sampling: only sample from the smallest set of tokens whose probability adds up to top_p
+    pub top_p: Option<f64>,
+    // only sample from the k most likely tokens
+    pub top_k: Option<usize>,
+    // drop tokens whose probability is below min_p * (probability of the most likely token)
+    pub min_p: Option<f64>,
+    // always pick the most likely token (ignores temperature, top_p, top_k and min_p)
+    pub greedy: bool,
+    // rng seed for sampling, same seed + same prompt + same params gives the same answer
+    pub seed: u64,
+    // penalty applied to recently seen tokens, 1.0 disables it
+    pub repeat_penalty: f32,
+    // how many of the most recent tokens the repeat penalty looks at
+    pub repeat_last_n: usize,
+    // context overflow handling for this request, the server's default policy is used if not set
+    pub overflow_policy: Option<OverflowPolicy>,
+    // generation stops when the answer contains one of these strings, which are not sent back
+    pub stop: Vec<String>,
+    // generation stops when one of these token ids is sampled (on top of the model's own end of turn tokens)
+    pub stop_token_ids: Vec<u32>,
+    // attach the logprob of every sampled token plus this many most likely alternatives to the stream
+    pub logprobs: Option<usize>,
+    // constrain the answer to JSON (optionally following a schema)
+    pub response_format: Option<ResponseFormat>,
+    // constrain the answer to match this regex as a whole
+    pub regex: Option<String>,
+    // number of answers to send back, all of them share the prompt's prefill
+    pub n: usize,
+    // generate this many answers and send back the n with the highest cumulative logprob
+    pub best_of: Option<usize>,
 }
 
 impl Default for GenerationParams {
     fn default() -> Self {
         Self {
             max_tokens: DEFAULT_MAX_TOKENS,
+            temperature: DEFAULT_TEMPERATURE,
+            top_p: Some(DEFAULT_TOP_P),
+            top_k: None,
+            min_p: None,
+            greedy: false,
+            seed: DEFAULT_SEED,
+            repeat_penalty: DEFAULT_REPEAT_PENALTY,
+            repeat_last_n: DEFAULT_REPEAT_LAST_N,
+            overflow_policy: None,
+            stop: Vec::new(),
+            stop_token_ids: Vec::new(),
+            logprobs: None,
+            response_format: None,
+            regex: None,
+            n: 1,
+            best_of: None,
+        }
+    }
+}
+
+impl GenerationParams {
+    // check params before the request reaches the engine, returns a message for the client on error
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_tokens == 0 {
+            return Err("params.max_tokens must be greater than 0".to_string());
+        }
+        if !self.temperature.is_finite() || self.temperature < 0.0 {
+            return Err(format!(
+                "params.temperature must be a non-negative number, got {}",
+                self.temperature
+            ));
+        }
+        if let Some(top_p) = self.top_p {
+            if !(top_p > 0.0 && top_p <= 1.0) {
+                return Err(format!("params.top_p must be in (0, 1], got {top_p}"));
+            }
+        }
+        if self.top_k == Some(0) {
+            return Err("params.top_k must be at least 1".to_string());
+        }
+        if let Some(min_p) = self.min_p {
+            if !(0.0..=1.0).contains(&min_p) {
+                return Err(format!("params.min_p must be in [0, 1], got {min_p}"));
+            }
+        }
+        if !self.repeat_penalty.is_finite() || self.repeat_penalty <= 0.0 {
+            return Err(format!(
+                "params.repeat_penalty must be a positive number, got {}",
+                self.repeat_penalty
+            ));
+        }
+        if self.logprobs.is_some_and(|logprobs| logprobs > MAX_LOGPROBS) {
+            return Err(format!("params.logprobs must be at most {MAX_LOGPROBS}"));
+        }
+        if self.n == 0 || self.n > MAX_CHOICES {
+            return Err(format!("params.n must be in [1, {MAX_CHOICES}], got {}", self.n));
+        }
+        if let Some(best_of) = self.best_of {
+            if best_of < self.n || best_of > MAX_CHOICES {
+                return Err(format!(
+                    "params.best_of must be in [n, {MAX_CHOICES}], got {best_of} with n {}",
+                    self.n
+                ));
+            }
         }
+        if self.stop.iter().any(|stop| stop.is_empty()) {
+            return Err("params.stop must not contain empty strings".to_string());
+        }
+        Ok(())
+    }
+
+    // check the params that depend on the model serving the request, after validate
+    // max_choices is how many answers of one request the server can run at once
+    pub fn validate_for_model(&self, vocab_size: usize, context_length: usize, max_choices: usize) -> Result<(), String> {
+        if self.repeat_last_n > context_length {
+            return Err(format!(
+                "params.repeat_last_n must be at most the model's context window of {context_length} tokens, got {}",
+                self.repeat_last_n
+            ));
+        }
+        if let Some(id) = self.stop_token_ids.iter().find(|&&id| id as usize >= vocab_size) {
+            return Err(format!(
+                "params.stop_token_ids holds token id {id}, which is not in the vocabulary of {vocab_size} tokens"
+            ));
+        }
+        if self.choices() > max_choices {
+            let field = if self.best_of.is_some() { "params.best_of" } else { "params.n" };
+            return Err(format!(
+                "{field} must be at most {max_choices}, the number of answers the server runs at once for one request, got {}",
+                self.choices()
+            ));
+        }
+        Ok(())
+    }
+
+    // greedy decoding either requested directly or implied by a zero temperature
+    pub fn is_greedy(&self) -> bool {
+        self.greedy || self.temperature == 0.0
+    }
+
+    // answers generated for the request, best_of of them if set, otherwise n
+    pub fn choices(&self) -> usize {
+        self.best_of.unwrap_or(self.n)
+    }
+
+    // more answers are generated than sent back, the best ones are picked by cumulative logprob
+    pub fn ranks_choices(&self) -> bool {
+        self.choices() > self.n
     }
 }
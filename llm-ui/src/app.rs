@@ -1,12 +1,13 @@
 use tokio::sync::mpsc;
 use reqwest::Client;
 use serde::Deserialize;
-use serde_json::json;
+use serde_json::{json, Value};
 use futures::StreamExt;
 use anyhow::Result;
 use ratatui::style::{Color, Style, Modifier};
 use ratatui::widgets::ListState;
-use std::time::Instant;
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex};
 
 pub enum InputMode {
     Normal,
@@ -59,12 +60,18 @@ struct ServerResponses {
     token: Option<String>,
     #[serde(default)]
     done: Option<bool>,
+    #[serde(default)]
+    cancelled: Option<bool>,
+    #[serde(default)]
+    request_id: Option<u64>,
 }
 
 #[derive(Debug, Deserialize)]
 struct HistoryResponse {
     chat_id: i32,
     latest_msg: String,
+    role: String,
+    tool_data: Option<Value>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -72,9 +79,18 @@ pub struct NextChatIdResponse {
     pub chat_id: i32,
 }
 
+// one stored message of a chat, tool_data holds the calls of an answer that called tools or the
+// id of the call a tool result answers
+#[derive(Debug, Deserialize)]
+pub struct StoredMessage {
+    pub role: String,
+    pub message: String,
+    pub tool_data: Option<Value>,
+}
+
 #[derive(Debug, Deserialize)]
 pub enum FetchResponses {
-    Success {messages: Vec<(i32, String, String)>},
+    Success {messages: Vec<StoredMessage>},
     Error {message: String},
 }
 
@@ -131,6 +147,8 @@ pub struct App {
     // Tracking for current streamed response
     pub token_count: u64,
     pub stream_start: Option<Instant>,
+    // server request id of the response being streamed, used to cancel it
+    pub active_request_id: Arc<Mutex<Option<u64>>>,
 
     pub buttons: Vec<Button>,
     pub selected_button: usize,
@@ -156,6 +174,7 @@ impl App {
         chat_id: None,
         token_count: 0,
         stream_start: None,
+        active_request_id: Arc::new(Mutex::new(None)),
         buttons: vec![
                 Button::new("New Chat"),
                 Button::new("Resume Chat from History"),
@@ -245,39 +264,28 @@ impl App {
         self.character_index = 0;
     }
 
-    fn build_chat_history_prompt(&self) -> String {
-        let mut prompt = String::new();
-
-        // following chat template for TinyLlama-1.1B-Chat-v1.0 model from HuggingFace
-        prompt.push_str("<|system|>\n");
-        prompt.push_str("You are a helpful assistant. You are able to remember context and details about the user through their going through past messages.");
-        prompt.push_str("</s>\n");
+    // chat history as structured messages, the server renders them with the model's chat template
+    fn build_chat_messages(&self) -> Vec<serde_json::Value> {
+        let mut messages = vec![json!({
+            "role": "system",
+            "content": "You are a helpful assistant. You are able to remember context and details about the user through their going through past messages.",
+        })];
 
         // build context from chat history
         let pair_count = self.messages.len().min(self.llm_messages.len());
         for i in 0..pair_count {
-            prompt.push_str("<|user|>\n");
-            prompt.push_str(&self.messages[i]);
-            prompt.push_str("</s>\n");
-
-            prompt.push_str("<|assistant|>\n");
-            prompt.push_str(&self.llm_messages[i]);
-            prompt.push_str("</s>\n");
+            messages.push(json!({ "role": "user", "content": self.messages[i] }));
+            messages.push(json!({ "role": "assistant", "content": self.llm_messages[i] }));
         }
 
         // last client message
         if self.messages.len() > self.llm_messages.len() {
             if let Some(last_user) = self.messages.last() {
-                prompt.push_str("<|user|>\n");
-                prompt.push_str(last_user);
-                prompt.push_str("</s>\n");
+                messages.push(json!({ "role": "user", "content": last_user }));
             }
         }
 
-        // Add assistant header to indicate model should generate the next reply.
-        prompt.push_str("<|assistant|>\n");
-
-        prompt
+        messages
     }
 
     pub fn submit_message(&mut self, tx: mpsc::Sender<String>) {
@@ -285,7 +293,7 @@ impl App {
         // latest raw user message (without system / history)
         let user_message = self.input.clone();
         self.messages.push(user_message.clone());
-        let prompt = self.build_chat_history_prompt();
+        let chat_messages = self.build_chat_messages();
         // reset tok/s for new response
         self.token_count = 0;
         self.stream_start = None;
@@ -293,8 +301,20 @@ impl App {
         self.reset_cursor();
         let username = self.username.clone();
         let chat_id = self.chat_id;
+        let active_request_id = Arc::clone(&self.active_request_id);
         tokio::spawn(async move {
-            let _ = run_llm(tx, prompt, username, chat_id, user_message).await;
+            let _ = run_llm(tx, chat_messages, username, chat_id, user_message, active_request_id).await;
+        });
+    }
+
+    // ask the server to stop generating the current response, the partial answer is kept
+    pub fn cancel_generation(&mut self) {
+        let Some(request_id) = *self.active_request_id.lock().unwrap() else {
+            return;
+        };
+        let username = self.username.clone();
+        tokio::spawn(async move {
+            let _ = cancel_llm(request_id, username).await;
         });
     }
 
@@ -346,46 +366,108 @@ pub async fn get_next_chat_id_for_user(username: String) -> Result<i32> {
     Ok(body.chat_id)
 }
 
-async fn run_llm(tx: mpsc::Sender<String>, prompt: String, username:String, chat_id: Option<i32>, user_message: String) -> Result<()>{
-    // send HTTP POST request with prompt to llm-server
+// times the response stream is picked up again after the connection dropped
+const STREAM_RESUME_ATTEMPTS: usize = 5;
+
+async fn run_llm(tx: mpsc::Sender<String>, chat_messages: Vec<serde_json::Value>, username:String, chat_id: Option<i32>, user_message: String, active_request_id: Arc<Mutex<Option<u64>>>) -> Result<()>{
+    // send HTTP POST request with the chat messages to llm-server
     // tx.send(prompt.to_string()).await.ok();
     let addr = "127.0.0.1:4000";
     let prompt_post_url = format!("http://{addr}/generate");
     let client = Client::new();
     let response = client
         .post(&prompt_post_url)
-        .json(&json!({ "prompt": prompt , "user_message": user_message, "username": username, "chat_id": chat_id}))
+        .json(&json!({ "messages": chat_messages, "user_message": user_message, "username": username, "chat_id": chat_id}))
         .send()
-        .await?;
-
-    // read server response stream
-    let mut stream = response.bytes_stream();
-    while let Some(chunk) = stream.next().await {
-        let chunk = chunk?;
-        let line = std::str::from_utf8(&chunk).unwrap_or("").trim();
-        
-        // process only responses that start with data and have content
-        if line.is_empty() || !line.starts_with("data:") {
-            continue;
+        .await;
+
+    // id of the last event received, so a dropped stream can be resumed after it
+    let mut last_event_id: Option<u64> = None;
+    let mut finished = match response {
+        Ok(response) => read_llm_stream(response, &tx, &active_request_id, &mut last_event_id).await,
+        Err(_) => false,
+    };
+
+    // the server keeps generating for a while after a disconnect, pick up the missed tokens
+    let mut attempts = 0;
+    while !finished && attempts < STREAM_RESUME_ATTEMPTS {
+        let request_id = *active_request_id.lock().unwrap();
+        let Some(request_id) = request_id else {
+            break;
+        };
+        attempts += 1;
+        tokio::time::sleep(Duration::from_secs(attempts as u64)).await;
+        let mut request = client
+            .get(format!("http://{addr}/generate/{request_id}/stream"))
+            .query(&[("username", &username)]);
+        if let Some(id) = last_event_id {
+            request = request.header("Last-Event-ID", id.to_string());
         }
-        let payload = line.trim_start_matches("data:").trim();
-        if payload.is_empty() {
-            continue;
+        match request.send().await {
+            // the stream expired on the server, nothing left to resume
+            Ok(response) if !response.status().is_success() => break,
+            Ok(response) => {
+                finished = read_llm_stream(response, &tx, &active_request_id, &mut last_event_id).await;
+            }
+            Err(_) => {}
         }
+    }
+    *active_request_id.lock().unwrap() = None;
+    tx.send("Thread work complete!".to_string()).await.ok();
+    Ok(())
+}
 
-        // deserialize server response and send token to UI
-        if let Ok(message) = serde_json::from_str::<ServerResponses>(payload) {
-            if let Some(token) = message.token {
-                tx.send(token).await.ok();
+// forward streamed tokens to the UI, returns false if the connection dropped before the end
+async fn read_llm_stream(response: reqwest::Response, tx: &mpsc::Sender<String>, active_request_id: &Arc<Mutex<Option<u64>>>, last_event_id: &mut Option<u64>) -> bool {
+    // read server response stream, events are separated by a blank line and can span chunks
+    let mut stream = response.bytes_stream();
+    let mut pending = String::new();
+    while let Some(chunk) = stream.next().await {
+        let Ok(chunk) = chunk else {
+            return false;
+        };
+        pending.push_str(&String::from_utf8_lossy(&chunk));
+
+        while let Some(end) = pending.find("\n\n") {
+            let event: String = pending.drain(..end + 2).collect();
+            let mut payload = "";
+            for line in event.lines() {
+                if let Some(id) = line.strip_prefix("id:") {
+                    *last_event_id = id.trim().parse().ok();
+                } else if let Some(data) = line.strip_prefix("data:") {
+                    payload = data.trim();
+                }
+            }
+            // process only responses that have content
+            if payload.is_empty() {
+                continue;
             }
-            // response finished when done token received
-            if message.done.unwrap_or(false) {
-                tx.send("Thread work complete!".to_string()).await.ok();
-                return Ok(());
+
+            // deserialize server response and send token to UI
+            if let Ok(message) = serde_json::from_str::<ServerResponses>(payload) {
+                // first event carries the request id needed to cancel (or resume) the response
+                if let Some(request_id) = message.request_id {
+                    *active_request_id.lock().unwrap() = Some(request_id);
+                }
+                if let Some(token) = message.token {
+                    tx.send(token).await.ok();
+                }
+                // response finished when done (or cancelled) token received
+                if message.done.unwrap_or(false) || message.cancelled.unwrap_or(false) {
+                    return true;
+                }
             }
         }
     }
-    tx.send("Thread work complete!".to_string()).await.ok();
+    // the server closed the stream (e.g. after an error event), there is nothing left to resume
+    true
+}
+
+async fn cancel_llm(request_id: u64, username: String) -> Result<()> {
+    let addr = "127.0.0.1:4000";
+    let cancel_url = format!("http://{addr}/generate/{request_id}/cancel");
+    let client = Client::new();
+    client.post(&cancel_url).query(&[("username", &username)]).send().await?;
     Ok(())
 }
 
@@ -403,8 +485,9 @@ async fn run_history(tx: mpsc::Sender<String>, username:String) -> Result<()> {
     let fetched_history = response.json::<Vec<HistoryResponse>>().await?;
 
     for chat in fetched_history {
+        let latest_msg = describe_message(&chat.role, &chat.latest_msg, chat.tool_data.as_ref());
         let formatted = format!("Chat ID: {} | Latest Message: {}\n",
-                                 chat.chat_id, chat.latest_msg);
+                                 chat.chat_id, latest_msg);
         tx.send(formatted).await.ok();
     }
     tx.send("Thread work complete!".to_string()).await.ok();
@@ -429,8 +512,9 @@ async fn run_chat(tx: mpsc::Sender<String>, input:String, username:String) -> Re
 
     match messages {
         FetchResponses::Success {messages} => {
-            for (msg_id, msg, _timestamp) in messages {
-                tx.send(format!("[{}]: {}\n", msg_id, msg)).await.ok();
+            for (user, answer) in chat_turns(messages) {
+                tx.send(format!("[user]: {}\n", user)).await.ok();
+                tx.send(format!("[assistant]: {}\n", answer)).await.ok();
             }
         },
         FetchResponses::Error {message} => {
@@ -440,4 +524,44 @@ async fn run_chat(tx: mpsc::Sender<String>, input:String, username:String) -> Re
     
     tx.send("Thread work complete!".to_string()).await.ok();
     Ok(())
-}
\ No newline at end of file
+}
+// pairs every user message with everything the model did in reply: tool calls and tool results
+// are written into the answer, a user message that got no answer is paired with an empty one
+fn chat_turns(messages: Vec<StoredMessage>) -> Vec<(String, String)> {
+    let mut turns: Vec<(String, String)> = Vec::new();
+    for stored in messages {
+        if stored.role == "user" {
+            turns.push((stored.message, String::new()));
+            continue;
+        }
+        if turns.is_empty() {
+            turns.push((String::new(), String::new()));
+        }
+        let text = describe_message(&stored.role, &stored.message, stored.tool_data.as_ref());
+        if let Some((_, answer)) = turns.last_mut() {
+            if !answer.is_empty() && !text.is_empty() {
+                answer.push('\n');
+            }
+            answer.push_str(&text);
+        }
+    }
+    turns
+}
+
+// text of a stored message as shown in the chat, with the tools an answer called
+fn describe_message(role: &str, message: &str, tool_data: Option<&Value>) -> String {
+    if role == "tool" {
+        return format!("[tool result] {}", message);
+    }
+    let calls = tool_data
+        .and_then(|data| data["tool_calls"].as_array())
+        .into_iter()
+        .flatten()
+        .map(|call| format!("[tool call] {}({})", call["name"].as_str().unwrap_or_default(), call["arguments"]));
+    let mut lines: Vec<String> = Vec::new();
+    if !message.is_empty() {
+        lines.push(message.to_string());
+    }
+    lines.extend(calls);
+    lines.join("\n")
+}
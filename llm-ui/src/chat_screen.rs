@@ -75,7 +75,9 @@ pub fn render_chat(frame: &mut Frame, app: &App) {
         InputMode::Processing => (
             {
                 let mut spans = Vec::new();
-                spans.push("Processing".into());
+                spans.push("Processing (press ".into());
+                spans.push("ESC".bold());
+                spans.push(" to stop)".into());
 
                 if let Some(start_time) = app.stream_start {
                     if app.token_count > 0 {
@@ -84,6 +84,7 @@ pub async fn key_handler(app: &mut App, tx: mpsc::Sender<String>, rx: &mut mpsc:
                         },
                         InputMode::Editing => {},
                         InputMode::Processing => match key.code {
+                            KeyCode::Esc => app.cancel_generation(),
                             KeyCode::Up => scroll_offset = scroll_offset.saturating_sub(1),
                             KeyCode::Down => scroll_offset = scroll_offset.saturating_add(1),
                             KeyCode::PageUp => scroll_offset = scroll_offset.saturating_sub(5),
@@ -250,12 +251,12 @@ fn process_incoming_message(app: &mut App, rx: &mut mpsc::Receiver<String>) {
                     None => break,
                 };
 
-                let msg_id = &msg[start_index + 1 .. end_index];
-                let id: u32 = msg_id.trim().parse().unwrap_or(0);
+                let role = &msg[start_index + 1 .. end_index];
 
                 let clean = msg[end_index + 2 ..].to_string();
 
-                if id % 2 == 1 {
+                // the fetched chat comes as user / assistant pairs, tool rows already folded into the answers
+                if role == "user" {
                     app.messages.push(clean);
                 } else {
                     app.llm_messages.push(clean);